@@ -0,0 +1,25 @@
+#!/usr/bin/env bash
+# Seeds a local replica with ROWS patients, each with a medication, a patient record and an
+# appointment, and prints the instructions used by the indexed queries. Start the replica
+# first with `dfx start --background`; needs candid-extractor like did.sh.
+set -e
+
+ROWS=${ROWS:-100000}
+BATCH=${BATCH:-2000}
+canister=icp_rust_boilerplate_backend
+wasm="target/wasm32-unknown-unknown/release/$canister.wasm"
+
+cargo build --target wasm32-unknown-unknown --release --package "$canister" --features bench
+candid-extractor "$wasm" > target/bench.did
+
+dfx canister create "$canister"
+dfx canister install "$canister" --mode reinstall --yes --wasm "$wasm"
+
+seeded=0
+while [ "$seeded" -lt "$ROWS" ]; do
+    dfx canister call --candid target/bench.did "$canister" bench_seed "($BATCH : nat32)" > /dev/null
+    seeded=$((seeded + BATCH))
+    echo "Seeded $seeded rows"
+done
+
+dfx canister call --candid target/bench.did "$canister" bench_queries
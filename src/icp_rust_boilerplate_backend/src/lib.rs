@@ -1,770 +1,13428 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+use chrono::{DateTime, Datelike, Days, NaiveDate};
+use ic_cdk::api::caller;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{
+    btreemap, BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow, cell::RefCell, iter::Peekable, marker::PhantomData, ops::Bound, time::Duration,
+};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
-type IdCell = Cell<u64, Memory>;
+type VersionCell = Cell<u32, Memory>;
+
+// Bump when stable memory needs migrating, and add the migration step to run_migration_batch
+// Version 0 is the original layout: a shared id counter in memory 0 and bounded maps of
+// doctors, patients, appointments, patient records and medications in memories 1 to 5
+const SCHEMA_VERSION: u32 = 1;
+
+// Rows migrated per message; post_upgrade runs the first batch and a timer runs the rest
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+// Entities kept in bounded maps are limited to this many encoded bytes
+const BOUNDED_ENTITY_SIZE: u32 = 1024;
+
+// Raw bytes of an entity stored in a bounded map, used when migrating it elsewhere
+type BoundedEntityBytes = Blob<{ BOUNDED_ENTITY_SIZE as usize }>;
+
+// Values in a ChunkedMap are split into chunks of this many bytes
+const CHUNK_SIZE: usize = 1024;
+type Chunk = Blob<CHUNK_SIZE>;
+
+// Field length limits in bytes, checked before anything is stored
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_LABEL_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+const MAX_DIAGNOSIS_LENGTH: usize = 8 * 1024;
+const MAX_TREATMENT_LENGTH: usize = 32 * 1024;
+const MAX_RECORD_MEDICATIONS: usize = 100;
+const MAX_RECORD_PROBLEMS: usize = 20;
+const MAX_IDENTIFIER_LENGTH: usize = 64;
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_LANGUAGE_TAG_LENGTH: usize = 35;
+const MAX_CONTACT_PHONES: usize = 5;
+const MAX_PHONE_LENGTH: usize = 32;
+const MIN_BIRTH_YEAR: i32 = 1900;
+const MAX_CODED_DIAGNOSES: usize = 20;
+const MAX_SEARCH_RESULTS: usize = 100;
+// Duplicate blocks with more patients than this are too common to tell anything, such as a
+// clinic phone number; a page of duplicates stops after about this many comparisons
+const MAX_DUPLICATE_BLOCK_SIZE: usize = 25;
+const MAX_DUPLICATE_COMPARISONS: usize = 5000;
+const MAX_OBSERVATION_RESULTS: usize = 1000;
+const MAX_SERIES_POINTS: u32 = 500;
+// Keeps a lab order within the bounded entity size
+const MAX_LAB_TESTS: usize = 8;
+const MAX_TEST_NAME_LENGTH: usize = 64;
+const MAX_VACCINE_NAME_LENGTH: usize = 64;
+const MAX_SCHEDULE_DOSES: usize = 200;
+const MAX_NOTE_SECTION_LENGTH: usize = 8 * 1024;
+const MAX_MIME_TYPE_LENGTH: usize = 100;
+const MAX_DOCTOR_SPECIALITIES: usize = 5;
+const MAX_QUALIFICATIONS: usize = 10;
+const MAX_DOCTOR_LANGUAGES: usize = 10;
+const MAX_BIO_LENGTH: usize = 4 * 1024;
+const MAX_AGE_AT_ONSET: u32 = 120;
+const MAX_CARE_GOALS: usize = 20;
+const MAX_CARE_INTERVENTIONS: usize = 20;
+const MAX_TIMELINE_SUMMARY_LENGTH: usize = 120;
+
+// Appointment durations are in minutes
+const MAX_APPOINTMENT_DURATION: u32 = 24 * 60;
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * NANOS_PER_MINUTE;
+
+// Documents are uploaded and downloaded in chunks of this many bytes; every chunk
+// but the last is full
+const DOCUMENT_CHUNK_SIZE: usize = 256 * 1024;
+const MAX_DOCUMENT_SIZE: u64 = 10 * 1024 * 1024;
+const ALLOWED_DOCUMENT_TYPES: [&str; 6] = [
+    "application/pdf",
+    "image/jpeg",
+    "image/png",
+    "image/tiff",
+    "application/dicom",
+    "text/plain",
+];
+
+// Download links are valid for 15 minutes
+const DOWNLOAD_LINK_TTL: u64 = 15 * 60 * 1_000_000_000;
+const DOWNLOAD_TOKEN_LENGTH: usize = 32;
+type DownloadToken = Blob<DOWNLOAD_TOKEN_LENGTH>;
+
+// ICD-10 codes are at most 7 characters plus the dot, e.g. S72.001A
+const MAX_ICD10_CODE_LENGTH: usize = 8;
+type Icd10CodeKey = Blob<MAX_ICD10_CODE_LENGTH>;
+
+// Description words are indexed by their first 24 bytes
+const MAX_KEYWORD_LENGTH: usize = 24;
+type Icd10Keyword = Blob<MAX_KEYWORD_LENGTH>;
+
+// Drug names in the interaction knowledge base, normalized to lower-case words
+const MAX_DRUG_NAME_LENGTH: usize = 64;
+type DrugKey = Blob<MAX_DRUG_NAME_LENGTH>;
+
+// Observations are indexed by ((patient, observation type code), (observed_at, id))
+type ObservationKey = ((PatientId, u8), (u64, ObservationId));
+
+// Duplicate blocks are keyed by the SHA-256 of the blocking key, such as "phone:5551234"
+type DuplicateBlockKey = Blob<32>;
+
+// Timeline events are indexed by (patient, (time, (event code, entity id)))
+type TimelineKey = (PatientId, (u64, (u8, u64)));
+
+// Room bookings are indexed by (room, (start time, appointment id))
+type RoomBookingKey = (RoomId, (u64, AppointmentId));
+
+// Normalized vaccine names
+type VaccineKey = Blob<MAX_VACCINE_NAME_LENGTH>;
+
+// Normalized doctor specialities
+type SpecialityKey = Blob<MAX_LABEL_LENGTH>;
+
+// Doctors' languages are indexed by their lower-cased primary subtag, e.g. "pt" for "pt-BR"
+const MAX_LANGUAGE_SUBTAG_LENGTH: usize = 8;
+type LanguageKey = Blob<MAX_LANGUAGE_SUBTAG_LENGTH>;
+
+// Ids carry the entity kind tag in their top byte; ids without a tag predate per-entity sequences
+const ID_TAG_SHIFT: u32 = 56;
+
+// Declares id newtypes over u64, stored as their u64. In the Candid interface every id is a
+// plain nat64, so the type only holds inside the canister; callers are checked at runtime by
+// the entity tag (see ensure_id_kind), and untagged ids from before the tags accept any kind.
+macro_rules! typed_id {
+    ($($name:ident),+ $(,)?) => {
+        $(
+            #[derive(
+                candid::CandidType,
+                Clone,
+                Copy,
+                Serialize,
+                Deserialize,
+                Default,
+                PartialEq,
+                Eq,
+                PartialOrd,
+                Ord,
+                Debug,
+            )]
+            struct $name(u64);
+
+            impl Storable for $name {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    self.0.to_bytes()
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    Self(u64::from_bytes(bytes))
+                }
+            }
+
+            impl BoundedStorable for $name {
+                const MAX_SIZE: u32 = u64::MAX_SIZE;
+                const IS_FIXED_SIZE: bool = u64::IS_FIXED_SIZE;
+            }
+        )+
+    };
+}
+
+typed_id!(
+    DoctorId,
+    PatientId,
+    AppointmentId,
+    PatientRecordId,
+    MedicationId,
+    EmergencyContactId,
+    InsuranceCoverageId,
+    AllergyId,
+    ObservationId,
+    LabOrderId,
+    LabResultId,
+    ImmunizationId,
+    ClinicalNoteId,
+    DocumentId,
+    FacilityId,
+    DepartmentId,
+    RoomId,
+    ProblemId,
+    FamilyHistoryId,
+    CarePlanId,
+    CareTaskId,
+);
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Doctor {
-    id: u64,
+    id: DoctorId,
     name: String,
-    speciality: String,
+    // Names from the speciality catalogue; the first is the primary speciality
+    specialities: Vec<String>,
+    license_number: Option<String>,
+    qualifications: Vec<String>,
+    // Language tags such as "en" or "pt-BR"
+    languages: Vec<String>,
+    consultation_fee: Option<ConsultationFee>,
+    bio: Option<String>,
+    created_at: u64,
+}
+
+// An amount in the minor unit of an ISO 4217 currency, e.g. 5000 USD is $50.00
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct ConsultationFee {
+    amount: u64,
+    currency: String,
+}
+
+// An entry of the speciality catalogue managed by controllers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Speciality {
+    name: String,
+    description: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Facility {
+    id: FacilityId,
+    name: String,
+    address: Option<Address>,
+    phone: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Department {
+    id: DepartmentId,
+    facility_id: FacilityId,
+    name: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Room {
+    id: RoomId,
+    department_id: DepartmentId,
+    name: String,
+    kind: RoomKind,
     created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum RoomKind {
+    #[default]
+    Consultation,
+    Procedure,
+    Operating,
+    Imaging,
+    Ward,
+    Other,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Patient {
-    id: u64,
+    id: PatientId,
     name: String,
+    preferred_name: Option<String>,
+    // ISO 8601 calendar date, "YYYY-MM-DD"
+    date_of_birth: String,
+    // Set when the date of birth was estimated from a recorded age
+    date_of_birth_estimated: bool,
+    // Computed from date_of_birth whenever the patient is returned
     age: u32,
-    gender: String,
+    sex: Sex,
+    gender_identity: Option<GenderIdentity>,
+    phone: Option<String>,
+    email: Option<String>,
+    address: Option<Address>,
+    // BCP 47 language tag, e.g. "en" or "pt-BR"
+    preferred_language: Option<String>,
+    medical_record_number: Option<String>,
+    national_id: Option<String>,
     created_at: u64,
 }
 
+// Sex recorded for clinical purposes
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum Sex {
+    Female,
+    Male,
+    Intersex,
+    #[default]
+    Unknown,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum GenderIdentity {
+    Woman,
+    Man,
+    NonBinary,
+    Other(String),
+    Undisclosed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct Address {
+    line1: String,
+    line2: Option<String>,
+    city: String,
+    region: Option<String>,
+    postal_code: Option<String>,
+    country: String,
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Appointment {
-    id: u64,
-    patient_id: u64,
-    doctor_id: u64,
+    id: AppointmentId,
+    patient_id: PatientId,
+    doctor_id: DoctorId,
     date_time: u64,
+    // Minutes
     duration: u32,
     description: String,
+    // Where the appointment takes place; None for older appointments
+    room_id: Option<RoomId>,
+    // Coverage found for the appointment date when it was booked; None for older appointments
+    eligibility: Option<Eligibility>,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct PatientRecord {
-    id: u64,
-    patient_id: u64,
-    doctor_id: u64,
+    id: PatientRecordId,
+    patient_id: PatientId,
+    doctor_id: DoctorId,
     diagnosis: String,
+    // ICD-10 codes, e.g. J45.909, alongside the free-text diagnosis
+    coded_diagnoses: Vec<String>,
     treatment: String,
-    medications: Vec<String>,
+    medications: Vec<MedicationId>,
     created_at: u64,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Medication {
-    id: u64,
+    id: MedicationId,
     name: String,
-    dosage: String,
-    frequency: String,
-    patient_id: u64,
+    // None only for migrated medications whose free text could not be parsed
+    dose: Option<Dose>,
+    route: Route,
+    frequency: Option<Frequency>,
+    // YYYY-MM-DD
+    start_date: Option<String>,
+    end_date: Option<String>,
+    patient_id: PatientId,
+    // The prescribing doctor; None for medications recorded before prescribers were tracked
+    doctor_id: Option<DoctorId>,
+    indication: Option<String>,
+    // The original free text of medications recorded before dosing was structured
+    legacy_dosage: Option<String>,
+    legacy_frequency: Option<String>,
+    // Set when the medication was prescribed despite matching active allergies
+    allergy_override: Option<AllergyOverride>,
     created_at: u64,
+    updated_at: Option<u64>,
 }
 
-impl Storable for Doctor {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+struct AllergyOverride {
+    allergy_ids: Vec<AllergyId>,
+    reason: String,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct Dose {
+    quantity: f64,
+    unit: DoseUnit,
 }
 
-impl BoundedStorable for Doctor {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum DoseUnit {
+    #[default]
+    Mg,
+    G,
+    Mcg,
+    Ml,
+    Unit,
+    Tablet,
+    Capsule,
+    Drop,
+    Puff,
+    Patch,
 }
 
-impl Storable for Patient {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum Route {
+    Oral,
+    Sublingual,
+    Intravenous,
+    Intramuscular,
+    Subcutaneous,
+    Topical,
+    Transdermal,
+    Inhaled,
+    Nasal,
+    Ophthalmic,
+    Otic,
+    Rectal,
+    Vaginal,
+    // Only for migrated medications
+    #[default]
+    Unknown,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Parsed from the codes QD, BID, TID, QID, PRN and qNh
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum Frequency {
+    OnceDaily,
+    TwiceDaily,
+    ThreeTimesDaily,
+    FourTimesDaily,
+    EveryHours(u32),
+    AsNeeded,
 }
 
-impl BoundedStorable for Patient {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EmergencyContact {
+    id: EmergencyContactId,
+    patient_id: PatientId,
+    name: String,
+    relationship: Relationship,
+    phones: Vec<String>,
+    // 1 is called first
+    priority: u32,
+    next_of_kin: bool,
+    // Whether clinical information may be shared with this contact
+    consent_to_share: bool,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-impl Storable for Appointment {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum Relationship {
+    Spouse,
+    Partner,
+    Parent,
+    Child,
+    Sibling,
+    Guardian,
+    Friend,
+    Other(String),
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl Default for Relationship {
+    fn default() -> Self {
+        Relationship::Other(String::new())
     }
 }
 
-impl BoundedStorable for Appointment {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct InsuranceCoverage {
+    id: InsuranceCoverageId,
+    patient_id: PatientId,
+    payer: String,
+    plan: String,
+    member_id: String,
+    group_number: Option<String>,
+    // YYYY-MM-DD, inclusive
+    effective_date: String,
+    expiry_date: Option<String>,
+    order: CoverageOrder,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-impl Storable for PatientRecord {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum CoverageOrder {
+    #[default]
+    Primary,
+    Secondary,
+    Tertiary,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum Eligibility {
+    // The highest-order coverage in effect on the appointment date
+    Covered(InsuranceCoverageId),
+    // Nothing in effect; the most recently expired coverage
+    Expired(InsuranceCoverageId),
+    // Nothing in effect; the next coverage to start
+    NotYetEffective(InsuranceCoverageId),
+    NoCoverage,
 }
 
-impl BoundedStorable for PatientRecord {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Allergy {
+    id: AllergyId,
+    patient_id: PatientId,
+    substance: String,
+    reaction: Option<String>,
+    severity: AllergySeverity,
+    status: AllergyStatus,
+    recorded_by: DoctorId,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-impl Storable for Medication {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// A condition on the patient's problem list
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Problem {
+    id: ProblemId,
+    patient_id: PatientId,
+    condition: String,
+    // ICD-10 code from the code table
+    code: Option<String>,
+    // YYYY-MM-DD
+    onset_date: Option<String>,
+    status: ProblemStatus,
+    severity: ProblemSeverity,
+    // YYYY-MM-DD, set while the problem is resolved
+    resolved_date: Option<String>,
+    recorded_by: DoctorId,
+    // The patient records that added and resolved the problem
+    source_record_id: Option<PatientRecordId>,
+    resolved_record_id: Option<PatientRecordId>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum ProblemStatus {
+    #[default]
+    Active,
+    InRemission,
+    Resolved,
 }
 
-impl BoundedStorable for Medication {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum ProblemSeverity {
+    Mild,
+    Moderate,
+    Severe,
+    #[default]
+    Unknown,
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// A condition in one of the patient's relatives
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FamilyHistory {
+    id: FamilyHistoryId,
+    patient_id: PatientId,
+    relative: Relative,
+    condition: String,
+    // ICD-10 code from the code table
+    code: Option<String>,
+    // The relative's age when the condition started, in years
+    age_at_onset: Option<u32>,
+    notes: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum Relative {
+    #[default]
+    Mother,
+    Father,
+    Sister,
+    Brother,
+    Daughter,
+    Son,
+    MaternalGrandmother,
+    MaternalGrandfather,
+    PaternalGrandmother,
+    PaternalGrandfather,
+    Aunt,
+    Uncle,
+    Cousin,
+    Other(String),
+}
 
-    static DOCTORS_STORAGE: RefCell<StableBTreeMap<u64, Doctor, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+// The patient's habits and circumstances; a patient has at most one
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SocialHistory {
+    patient_id: PatientId,
+    smoking: SmokingStatus,
+    // Packs a day times years smoked, for current and former smokers
+    pack_years: Option<u32>,
+    alcohol: AlcoholUse,
+    // Standard drinks in a typical week, for drinkers
+    drinks_per_week: Option<u32>,
+    occupation: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
 
-    static PATIENTS_STORAGE: RefCell<StableBTreeMap<u64, Patient, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum SmokingStatus {
+    Never,
+    Former,
+    Current,
+    #[default]
+    Unknown,
+}
 
-    static APPOINTMENTS_STORAGE: RefCell<StableBTreeMap<u64, Appointment, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum AlcoholUse {
+    NonDrinker,
+    Occasional,
+    Moderate,
+    Heavy,
+    #[default]
+    Unknown,
+}
 
-    static PATIENT_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, PatientRecord, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-    ));
+// A longitudinal plan of care for a patient
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CarePlan {
+    id: CarePlanId,
+    patient_id: PatientId,
+    // The author
+    doctor_id: DoctorId,
+    title: String,
+    description: Option<String>,
+    status: CarePlanStatus,
+    // YYYY-MM-DD
+    start_date: String,
+    end_date: Option<String>,
+    goals: Vec<CareGoal>,
+    interventions: Vec<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
 
-    static MEDICATIONS_STORAGE: RefCell<StableBTreeMap<u64, Medication, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-    ));
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum CarePlanStatus {
+    #[default]
+    Active,
+    OnHold,
+    Completed,
+    Cancelled,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct DoctorPayload {
-    name: String,
-    speciality: String,
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct CareGoal {
+    description: String,
+    // The observation values that meet the goal; None for goals that are not measured
+    target: Option<TargetMeasure>,
+    // YYYY-MM-DD
+    target_date: Option<String>,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct PatientPayload {
-    name: String,
-    age: u32,
-    gender: String,
+// Bounds an observation must fall within. For blood pressure both the systolic and the
+// diastolic value are checked.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct TargetMeasure {
+    observation_type: ObservationType,
+    // Stored in the type's standard unit
+    unit: ObservationUnit,
+    minimum: Option<ObservationValue>,
+    maximum: Option<ObservationValue>,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct AppointmentPayload {
-    patient_id: u64,
-    doctor_id: u64,
-    date_time: u64,
-    duration: u32,
+// A task on a care plan for one member of the care team
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CareTask {
+    id: CareTaskId,
+    care_plan_id: CarePlanId,
     description: String,
+    assigned_to: DoctorId,
+    // YYYY-MM-DD
+    due_date: String,
+    status: CareTaskStatus,
+    completed_at: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct PatientRecordPayload {
-    patient_id: u64,
-    doctor_id: u64,
-    diagnosis: String,
-    treatment: String,
-    medications: Vec<String>,
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum CareTaskStatus {
+    #[default]
+    Open,
+    Done,
+    Cancelled,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct MedicationPayload {
-    name: String,
-    dosage: String,
-    frequency: String,
-    patient_id: u64,
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum AllergySeverity {
+    Mild,
+    Moderate,
+    Severe,
+    LifeThreatening,
+    #[default]
+    Unknown,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Message {
-    Success(String),
-    Error(String),
-    NotFound(String),
-    InvalidPayload(String),
+// Only active allergies are checked when prescribing
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum AllergyStatus {
+    #[default]
+    Active,
+    Inactive,
+    Resolved,
+    EnteredInError,
 }
 
-// Function to create a doctor
-#[ic_cdk::update]
-fn create_doctor(payload: DoctorPayload) -> Result<Doctor, Message> {
-    if payload.name.is_empty() || payload.speciality.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Ensure 'name' and 'speciality' are provided.".to_string(),
-        ));
-    }
-
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Observation {
+    id: ObservationId,
+    patient_id: PatientId,
+    appointment_id: Option<AppointmentId>,
+    observation_type: ObservationType,
+    // Stored in the type's standard unit
+    value: ObservationValue,
+    unit: ObservationUnit,
+    observed_at: u64,
+    note: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
 
-    let doctor = Doctor {
-        id,
-        name: payload.name,
-        speciality: payload.speciality,
-        created_at: current_time(),
-    };
-    DOCTORS_STORAGE.with(|storage| storage.borrow_mut().insert(id, doctor.clone()));
-    Ok(doctor)
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum ObservationType {
+    BloodPressure,
+    #[default]
+    HeartRate,
+    Temperature,
+    OxygenSaturation,
+    Weight,
+    Height,
 }
 
-// Function to get all doctors
-#[ic_cdk::query]
-fn get_doctors() -> Result<Vec<Doctor>, Message> {
-    DOCTORS_STORAGE.with(|storage| {
-        let doctors: Vec<Doctor> = storage
-            .borrow()
-            .iter()
-            .map(|(_, doctor)| doctor.clone())
-            .collect();
+impl ObservationType {
+    // Stable code used in the observation index
+    fn code(self) -> u8 {
+        match self {
+            ObservationType::BloodPressure => 1,
+            ObservationType::HeartRate => 2,
+            ObservationType::Temperature => 3,
+            ObservationType::OxygenSaturation => 4,
+            ObservationType::Weight => 5,
+            ObservationType::Height => 6,
+        }
+    }
 
-        if doctors.is_empty() {
-            Err(Message::NotFound("No doctors found".to_string()))
-        } else {
-            Ok(doctors)
+    fn standard_unit(self) -> ObservationUnit {
+        match self {
+            ObservationType::BloodPressure => ObservationUnit::MillimetersOfMercury,
+            ObservationType::HeartRate => ObservationUnit::BeatsPerMinute,
+            ObservationType::Temperature => ObservationUnit::Celsius,
+            ObservationType::OxygenSaturation => ObservationUnit::Percent,
+            ObservationType::Weight => ObservationUnit::Kilograms,
+            ObservationType::Height => ObservationUnit::Centimeters,
         }
-    })
+    }
+
+    // Plausible range of a value in the standard unit
+    fn valid_range(self) -> (f64, f64) {
+        match self {
+            ObservationType::BloodPressure => (20.0, 300.0),
+            ObservationType::HeartRate => (20.0, 300.0),
+            ObservationType::Temperature => (25.0, 45.0),
+            ObservationType::OxygenSaturation => (0.0, 100.0),
+            ObservationType::Weight => (0.2, 700.0),
+            ObservationType::Height => (20.0, 280.0),
+        }
+    }
 }
 
-// Function to get a doctor by ID
-#[ic_cdk::query]
-fn get_doctor_id(doctor_id: u64) -> Result<Doctor, Message> {
-    DOCTORS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, doctor)| doctor.id == doctor_id)
-            .map(|(_, doctor)| doctor.clone())
-            .ok_or(Message::NotFound("Doctor not found".to_string()))
-    })
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum ObservationValue {
+    Quantity(f64),
+    BloodPressure { systolic: f64, diastolic: f64 },
 }
 
-#[ic_cdk::update]
-fn update_doctor(id: u64, name: String, speciality: String) -> Result<Doctor, Message> {
-    DOCTORS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        let id_entry = storage.iter().find(|(_, doctor)| doctor.id == id);
-        match id_entry {
-            Some((key, _)) => {
-                let updated_doctor = Doctor {
-                    id,
-                    name,
-                    speciality,
-                    created_at: current_time(),
-                };
-                storage.insert(key, updated_doctor.clone());
-                Ok(updated_doctor)
-            }
-            None => Err(Message::NotFound("Doctor not found".to_string())),
-        }
-    })
+impl Default for ObservationValue {
+    fn default() -> Self {
+        ObservationValue::Quantity(0.0)
+    }
 }
 
-#[ic_cdk::update]
-fn delete_doctor(id: u64) -> Result<(), Message> {
-    DOCTORS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if storage.remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Message::NotFound("Doctor not found".to_string()))
-        }
-    })
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum ObservationUnit {
+    MillimetersOfMercury,
+    #[default]
+    BeatsPerMinute,
+    Celsius,
+    Fahrenheit,
+    Percent,
+    Kilograms,
+    Pounds,
+    Centimeters,
+    Inches,
 }
 
-#[ic_cdk::update]
-fn create_patient(payload: PatientPayload) -> Result<Patient, Message> {
-    if payload.name.is_empty() || payload.gender.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Ensure 'name', 'age' and 'gender' are provided.".to_string(),
-        ));
-    }
+// Aggregates of the observations in one bucket of a downsampled series
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeriesPoint {
+    start: u64,
+    end: u64,
+    count: u32,
+    // The value, or the systolic pressure for blood pressure
+    primary: SeriesStats,
+    // The diastolic pressure for blood pressure
+    secondary: Option<SeriesStats>,
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeriesStats {
+    min: f64,
+    max: f64,
+    mean: f64,
+}
 
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LabOrder {
+    id: LabOrderId,
+    patient_id: PatientId,
+    // The ordering doctor, who also reviews the results
+    doctor_id: DoctorId,
+    tests: Vec<String>,
+    status: LabOrderStatus,
+    collected_at: Option<u64>,
+    resulted_at: Option<u64>,
+    reviewed_at: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Orders move forward one step at a time. Adding or correcting a result moves a
+// reviewed order back to resulted, so every result is reviewed.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum LabOrderStatus {
+    #[default]
+    Ordered,
+    Collected,
+    Resulted,
+    Reviewed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LabResult {
+    id: LabResultId,
+    order_id: LabOrderId,
+    analyte: String,
+    value: f64,
+    unit: String,
+    reference_range: Option<ReferenceRange>,
+    // Set from the reference range; None when there is no range to compare against
+    flag: Option<AbnormalFlag>,
+    performed_at: u64,
+    comment: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Bounds are inclusive; values beyond a critical bound are flagged critical
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct ReferenceRange {
+    low: Option<f64>,
+    high: Option<f64>,
+    critical_low: Option<f64>,
+    critical_high: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum AbnormalFlag {
+    #[default]
+    Normal,
+    Low,
+    High,
+    CriticalLow,
+    CriticalHigh,
+}
+
+impl AbnormalFlag {
+    fn is_critical(self) -> bool {
+        matches!(self, AbnormalFlag::CriticalLow | AbnormalFlag::CriticalHigh)
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Immunization {
+    id: ImmunizationId,
+    patient_id: PatientId,
+    vaccine: String,
+    // 1 for the first dose of a vaccine
+    dose_number: u32,
+    lot_number: Option<String>,
+    site: Option<AdministrationSite>,
+    administered_by: DoctorId,
+    // YYYY-MM-DD
+    administered_on: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum AdministrationSite {
+    LeftArm,
+    RightArm,
+    LeftThigh,
+    RightThigh,
+    Oral,
+    Intranasal,
+    Other(String),
+}
+
+// One dose of the immunization schedule. A dose is due once the patient reaches
+// min_age_days and min_interval_days have passed since the previous dose.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ScheduleDose {
+    vaccine: String,
+    dose_number: u32,
+    min_age_days: u32,
+    min_interval_days: u32,
+    // The dose is no longer recommended after this age
+    max_age_days: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ImmunizationDue {
+    vaccine: String,
+    dose_number: u32,
+    due_date: String,
+    status: DueStatus,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum DueStatus {
+    Overdue,
+    Upcoming,
+}
+
+// A clinical note in SOAP form. Only drafts can be changed or deleted.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ClinicalNote {
+    id: ClinicalNoteId,
+    patient_id: PatientId,
+    // The author
+    doctor_id: DoctorId,
+    // The appointment during which the note was written
+    appointment_id: Option<AppointmentId>,
+    subjective: String,
+    objective: String,
+    assessment: String,
+    plan: String,
+    status: NoteStatus,
+    signed_at: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum NoteStatus {
+    #[default]
+    Draft,
+    Signed,
+}
+
+// Starting text for the sections of new notes written by doctors of a speciality
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct NoteTemplate {
+    speciality: String,
+    subjective: String,
+    objective: String,
+    assessment: String,
+    plan: String,
+}
+
+// The metadata of an uploaded document; the content is stored separately in chunks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Document {
+    id: DocumentId,
+    patient_id: PatientId,
+    title: String,
+    category: DocumentCategory,
+    mime_type: String,
+    size: u64,
+    // Hex-encoded SHA-256 of the content, set when the upload is finished
+    sha256: Option<String>,
+    // Checked against the content when the upload is finished
+    expected_sha256: Option<String>,
+    status: DocumentStatus,
+    uploaded_by: Principal,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum DocumentCategory {
+    ReferralLetter,
+    ConsentForm,
+    ImagingReport,
+    Other,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum DocumentStatus {
+    Uploading,
+    Available,
+}
+
+// The raw bytes of one chunk of a document
+struct DocumentBytes(Vec<u8>);
+
+// A short-lived grant to download a document over HTTP
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DownloadGrant {
+    document_id: DocumentId,
+    created_by: Principal,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DownloadLink {
+    // Relative to the canister's raw HTTP domain
+    path: String,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+struct StreamingToken {
+    document_id: DocumentId,
+    index: u32,
+    token: String,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingToken>,
+}
+
+candid::define_function!(StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
+
+// Left behind when a patient is merged into another, so the old id still resolves
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PatientRedirect {
+    merged_into: PatientId,
+    merged_at: u64,
+}
+
+// A pair of patients that may be the same person
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DuplicateCandidate {
+    first: PatientId,
+    second: PatientId,
+    // Out of 100: name 40, date of birth 30, phone 15, email 15
+    score: u32,
+    matched_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DuplicatePage {
+    // Best match first within the page
+    candidates: Vec<DuplicateCandidate>,
+    // Pass as `after` to continue; None once every block was compared
+    next_after: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DrugInteraction {
+    drug_a: String,
+    drug_b: String,
+    severity: InteractionSeverity,
+    description: String,
+}
+
+// Contraindicated pairs block prescribing; the others are returned as warnings
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum InteractionSeverity {
+    #[default]
+    Minor,
+    Moderate,
+    Major,
+    Contraindicated,
+}
+
+// An interaction between a medication being prescribed and one the patient already takes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InteractionWarning {
+    medication_id: MedicationId,
+    medication_name: String,
+    severity: InteractionSeverity,
+    description: String,
+}
+
+// A record's medications, checked but not saved yet
+struct PreparedMedications {
+    medications: Vec<Medication>,
+    // A warning about an earlier new medication of the same record holds its index in
+    // `medications`, as its id is only allocated when it is saved
+    interaction_warnings: Vec<(Option<usize>, InteractionWarning)>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Icd10Code {
+    code: String,
+    description: String,
+}
+
+// An audited emergency read of a patient's contacts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BreakGlassEvent {
+    patient_id: PatientId,
+    caller: Principal,
+    reason: String,
+    accessed_at: u64,
+}
+
+impl Storable for Doctor {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Speciality {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Speciality {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Facility {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Department {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Department {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Room {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Room {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Problem {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Problem {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for FamilyHistory {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FamilyHistory {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SocialHistory {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SocialHistory {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CarePlan {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for CareTask {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CareTask {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Patient {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Appointment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Appointment {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PatientRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Medication {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for EmergencyContact {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EmergencyContact {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BreakGlassEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BreakGlassEvent {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DrugInteraction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DrugInteraction {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Icd10Code {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Icd10Code {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for InsuranceCoverage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InsuranceCoverage {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Allergy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Allergy {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Observation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Observation {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LabOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LabOrder {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LabResult {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LabResult {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Immunization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Immunization {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ScheduleDose {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScheduleDose {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ClinicalNote {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for NoteTemplate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Document {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Document {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DocumentBytes {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl Storable for DownloadGrant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DownloadGrant {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PatientRedirect {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PatientRedirect {
+    const MAX_SIZE: u32 = BOUNDED_ENTITY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A map for values with no size bound. Each value is split into chunks stored
+// under (key, chunk index), so a value of any length fits in a BTreeMap.
+struct ChunkedMap<K, V>
+where
+    K: BoundedStorable + Ord + Copy + Default,
+    V: Storable,
+{
+    chunks: StableBTreeMap<(K, u32), Chunk, Memory>,
+    value: PhantomData<V>,
+}
+
+impl<K, V> ChunkedMap<K, V>
+where
+    K: BoundedStorable + Ord + Copy + Default,
+    V: Storable,
+{
+    fn init(memory: Memory) -> Self {
+        Self {
+            chunks: StableBTreeMap::init(memory),
+            value: PhantomData,
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let bytes: Vec<u8> = self
+            .chunks
+            .range((*key, 0)..=(*key, u32::MAX))
+            .flat_map(|(_, chunk)| chunk.as_slice().to_vec())
+            .collect();
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(V::from_bytes(Cow::Owned(bytes)))
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.chunks.contains_key(&(*key, 0))
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.remove(&key);
+        for (index, chunk) in value.to_bytes().chunks(CHUNK_SIZE).enumerate() {
+            let chunk = Chunk::try_from(chunk).expect("Chunk exceeds CHUNK_SIZE");
+            self.chunks.insert((key, index as u32), chunk);
+        }
+        previous
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let previous = self.get(key);
+        let indexes: Vec<u32> = self
+            .chunks
+            .range((*key, 0)..=(*key, u32::MAX))
+            .map(|((_, index), _)| index)
+            .collect();
+        for index in indexes {
+            self.chunks.remove(&(*key, index));
+        }
+        previous
+    }
+
+    fn iter(&self) -> ChunkedIter<'_, K, V> {
+        ChunkedIter {
+            chunks: self.chunks.iter().peekable(),
+            value: PhantomData,
+        }
+    }
+}
+
+struct ChunkedIter<'a, K, V>
+where
+    K: BoundedStorable + Ord + Copy + Default,
+{
+    chunks: Peekable<btreemap::Iter<'a, (K, u32), Chunk, Memory>>,
+    value: PhantomData<V>,
+}
+
+impl<K, V> Iterator for ChunkedIter<'_, K, V>
+where
+    K: BoundedStorable + Ord + Copy + Default,
+    V: Storable,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ((key, _), first_chunk) = self.chunks.next()?;
+        let mut bytes = first_chunk.as_slice().to_vec();
+        while let Some(((next_key, _), _)) = self.chunks.peek() {
+            if *next_key != key {
+                break;
+            }
+            let (_, chunk) = self.chunks.next()?;
+            bytes.extend_from_slice(chunk.as_slice());
+        }
+        Some((key, V::from_bytes(Cow::Owned(bytes))))
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // Memory 0 held the shared id counter used before per-entity sequences. Memories 0, 1,
+    // 2, 4 and 5 are left empty by the migration but keep their buckets, because the memory
+    // manager cannot release them; their ids are free for future structures
+    static ID_SEQUENCES: RefCell<StableBTreeMap<u8, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static STORED_SCHEMA_VERSION: RefCell<VersionCell> = RefCell::new(
+        VersionCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))), 0)
+            .expect("Cannot create the schema version cell")
+    );
+
+    static MIGRATION_PROGRESS: RefCell<Cell<MigrationProgress, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))),
+            MigrationProgress::default(),
+        )
+        .expect("Cannot create the migration progress cell")
+    );
+
+    // Rows the migration could not decode, by (memory id, row id), kept for controllers
+    static MIGRATION_REJECTS: RefCell<StableBTreeMap<(u8, u64), BoundedEntityBytes, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    // Secondary indexes, maintained by the save_* and remove_* functions on every write
+    static APPOINTMENTS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, AppointmentId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static APPOINTMENTS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, AppointmentId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static APPOINTMENTS_BY_DATE: RefCell<StableBTreeMap<(u64, AppointmentId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static PATIENT_RECORDS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, PatientRecordId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static PATIENT_RECORDS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, PatientRecordId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static PATIENT_RECORDS_BY_MEDICATION: RefCell<StableBTreeMap<(MedicationId, PatientRecordId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static PATIENT_RECORDS_BY_DIAGNOSIS: RefCell<StableBTreeMap<(Icd10CodeKey, PatientRecordId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // The ICD-10 code table, uploaded in batches by a controller
+    static ICD10_CODES: RefCell<StableBTreeMap<Icd10CodeKey, Icd10Code, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static ICD10_CODES_BY_KEYWORD: RefCell<StableBTreeMap<(Icd10Keyword, Icd10CodeKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    static ALLERGIES_STORAGE: RefCell<StableBTreeMap<AllergyId, Allergy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static ALLERGIES_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, AllergyId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static ALLERGIES_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, AllergyId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Each interaction is stored under both (drug_a, drug_b) and (drug_b, drug_a)
+    static DRUG_INTERACTIONS: RefCell<StableBTreeMap<(DrugKey, DrugKey), DrugInteraction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    static OBSERVATIONS_STORAGE: RefCell<StableBTreeMap<ObservationId, Observation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static OBSERVATIONS_BY_PATIENT_TYPE_TIME: RefCell<StableBTreeMap<ObservationKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    static OBSERVATIONS_BY_APPOINTMENT: RefCell<StableBTreeMap<(AppointmentId, ObservationId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static LAB_ORDERS_STORAGE: RefCell<StableBTreeMap<LabOrderId, LabOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    static LAB_ORDERS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, LabOrderId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    static LAB_ORDERS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, LabOrderId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // The resulted orders of each doctor that have not been reviewed yet
+    static LAB_ORDERS_AWAITING_REVIEW: RefCell<StableBTreeMap<(DoctorId, LabOrderId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    static LAB_RESULTS_STORAGE: RefCell<StableBTreeMap<LabResultId, LabResult, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    static LAB_RESULTS_BY_ORDER: RefCell<StableBTreeMap<(LabOrderId, LabResultId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    static IMMUNIZATIONS_STORAGE: RefCell<StableBTreeMap<ImmunizationId, Immunization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    static IMMUNIZATIONS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, ImmunizationId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    static IMMUNIZATIONS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, ImmunizationId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    static IMMUNIZATION_SCHEDULE: RefCell<StableBTreeMap<(VaccineKey, u32), ScheduleDose, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    static CLINICAL_NOTES_STORAGE: RefCell<ChunkedMap<ClinicalNoteId, ClinicalNote>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    static CLINICAL_NOTES_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, ClinicalNoteId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    static CLINICAL_NOTES_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, ClinicalNoteId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    static CLINICAL_NOTES_BY_APPOINTMENT: RefCell<StableBTreeMap<(AppointmentId, ClinicalNoteId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    static NOTE_TEMPLATES: RefCell<ChunkedMap<SpecialityKey, NoteTemplate>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    static DOCUMENTS_STORAGE: RefCell<StableBTreeMap<DocumentId, Document, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+
+    static DOCUMENTS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, DocumentId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    // Document content, keyed by (document, chunk index)
+    static DOCUMENT_CONTENT: RefCell<ChunkedMap<(DocumentId, u32), DocumentBytes>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    static DOWNLOAD_GRANTS: RefCell<StableBTreeMap<DownloadToken, DownloadGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    static PATIENT_REDIRECTS: RefCell<StableBTreeMap<PatientId, PatientRedirect, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    static TIMELINE_BY_PATIENT: RefCell<StableBTreeMap<TimelineKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // Patients by the duplicate blocks they fall in
+    static DUPLICATE_BLOCKS: RefCell<StableBTreeMap<(DuplicateBlockKey, PatientId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // Merged patient ids by the patient they redirect to
+    static PATIENT_REDIRECTS_BY_TARGET: RefCell<StableBTreeMap<(PatientId, PatientId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+
+    static SPECIALITIES: RefCell<StableBTreeMap<SpecialityKey, Speciality, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    static DOCTORS_BY_SPECIALITY: RefCell<StableBTreeMap<(SpecialityKey, DoctorId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    static DOCTORS_BY_LANGUAGE: RefCell<StableBTreeMap<(LanguageKey, DoctorId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    static FACILITIES_STORAGE: RefCell<ChunkedMap<FacilityId, Facility>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+
+    static DEPARTMENTS_STORAGE: RefCell<StableBTreeMap<DepartmentId, Department, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+
+    static DEPARTMENTS_BY_FACILITY: RefCell<StableBTreeMap<(FacilityId, DepartmentId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+
+    static ROOMS_STORAGE: RefCell<StableBTreeMap<RoomId, Room, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    static ROOMS_BY_DEPARTMENT: RefCell<StableBTreeMap<(DepartmentId, RoomId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    // Doctors can work in several departments, so membership is indexed both ways
+    static DEPARTMENT_DOCTORS: RefCell<StableBTreeMap<(DepartmentId, DoctorId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+
+    static DOCTOR_DEPARTMENTS: RefCell<StableBTreeMap<(DoctorId, DepartmentId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    static APPOINTMENTS_BY_ROOM: RefCell<StableBTreeMap<RoomBookingKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    static PROBLEMS_STORAGE: RefCell<StableBTreeMap<ProblemId, Problem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    static PROBLEMS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, ProblemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    static PROBLEMS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, ProblemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    // Problems by the record that added or resolved them
+    static PROBLEMS_BY_RECORD: RefCell<StableBTreeMap<(PatientRecordId, ProblemId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    static FAMILY_HISTORY_STORAGE: RefCell<StableBTreeMap<FamilyHistoryId, FamilyHistory, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    static FAMILY_HISTORY_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, FamilyHistoryId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+
+    static SOCIAL_HISTORY_STORAGE: RefCell<StableBTreeMap<PatientId, SocialHistory, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    static CARE_PLANS_STORAGE: RefCell<ChunkedMap<CarePlanId, CarePlan>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+
+    static CARE_PLANS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, CarePlanId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76)))
+    ));
+
+    static CARE_PLANS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, CarePlanId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
+    ));
+
+    static CARE_TASKS_STORAGE: RefCell<StableBTreeMap<CareTaskId, CareTask, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    static CARE_TASKS_BY_PLAN: RefCell<StableBTreeMap<(CarePlanId, CareTaskId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+
+    // Tasks by the care-team member they are assigned to
+    static CARE_TASKS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, CareTaskId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+
+    static MEDICATIONS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, MedicationId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static MEDICATIONS_BY_DOCTOR: RefCell<StableBTreeMap<(DoctorId, MedicationId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static EMERGENCY_CONTACTS_STORAGE: RefCell<StableBTreeMap<EmergencyContactId, EmergencyContact, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static EMERGENCY_CONTACTS_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, EmergencyContactId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // Append-only log of break-glass reads, keyed by patient and event number
+    static BREAK_GLASS_LOG: RefCell<StableBTreeMap<(PatientId, u64), BreakGlassEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static INSURANCE_COVERAGES_STORAGE: RefCell<StableBTreeMap<InsuranceCoverageId, InsuranceCoverage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    static INSURANCE_COVERAGES_BY_PATIENT: RefCell<StableBTreeMap<(PatientId, InsuranceCoverageId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Memory 1 held doctors in a bounded map before profiles were added
+    static DOCTORS_STORAGE: RefCell<ChunkedMap<DoctorId, Doctor>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    // Memory 2 held patients in a bounded map before demographics were added
+    static PATIENTS_STORAGE: RefCell<ChunkedMap<PatientId, Patient>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Appointments have stayed in memory 3 since the original layout
+    static APPOINTMENTS_STORAGE: RefCell<StableBTreeMap<AppointmentId, Appointment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // Memory 4 held patient records in a bounded map before they moved to chunked storage
+    static PATIENT_RECORDS_STORAGE: RefCell<ChunkedMap<PatientRecordId, PatientRecord>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Memory 5 held medications in a bounded map before dosing was structured
+    static MEDICATIONS_STORAGE: RefCell<ChunkedMap<MedicationId, Medication>> =
+        RefCell::new(ChunkedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FacilityPayload {
+    name: String,
+    address: Option<Address>,
+    phone: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DepartmentPayload {
+    facility_id: FacilityId,
+    name: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RoomPayload {
+    department_id: DepartmentId,
+    name: String,
+    kind: RoomKind,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DoctorPayload {
+    name: String,
+    specialities: Vec<String>,
+    license_number: Option<String>,
+    qualifications: Vec<String>,
+    languages: Vec<String>,
+    consultation_fee: Option<ConsultationFee>,
+    bio: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientPayload {
+    name: String,
+    preferred_name: Option<String>,
+    date_of_birth: String,
+    sex: Sex,
+    gender_identity: Option<GenderIdentity>,
+    phone: Option<String>,
+    email: Option<String>,
+    address: Option<Address>,
+    preferred_language: Option<String>,
+    medical_record_number: Option<String>,
+    national_id: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AppointmentPayload {
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    date_time: u64,
+    duration: u32,
+    description: String,
+    room_id: Option<RoomId>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientRecordPayload {
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    diagnosis: String,
+    coded_diagnoses: Vec<String>,
+    treatment: String,
+    medications: Vec<RecordMedication>,
+    problems: Vec<RecordProblem>,
+}
+
+// A record's medication: one the patient already has, or a new one prescribed by the record's doctor
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum RecordMedication {
+    Existing(MedicationId),
+    New(RecordMedicationPayload),
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RecordMedicationPayload {
+    name: String,
+    dose: Dose,
+    route: Route,
+    frequency: String,
+    start_date: String,
+    end_date: Option<String>,
+    indication: Option<String>,
+    allergy_override_reason: Option<String>,
+}
+
+// A change a record makes to the patient's problem list
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum RecordProblem {
+    // Add an active problem recorded by the record's doctor
+    Add(RecordProblemPayload),
+    // Resolve one of the patient's problems as of today
+    Resolve(ProblemId),
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RecordProblemPayload {
+    condition: String,
+    code: Option<String>,
+    onset_date: Option<String>,
+    severity: ProblemSeverity,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ProblemPayload {
+    patient_id: PatientId,
+    condition: String,
+    code: Option<String>,
+    onset_date: Option<String>,
+    status: ProblemStatus,
+    severity: ProblemSeverity,
+    // Defaults to today for a resolved problem
+    resolved_date: Option<String>,
+    recorded_by: DoctorId,
+    source_record_id: Option<PatientRecordId>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FamilyHistoryPayload {
+    patient_id: PatientId,
+    relative: Relative,
+    condition: String,
+    code: Option<String>,
+    age_at_onset: Option<u32>,
+    notes: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct SocialHistoryPayload {
+    patient_id: PatientId,
+    smoking: SmokingStatus,
+    pack_years: Option<u32>,
+    alcohol: AlcoholUse,
+    drinks_per_week: Option<u32>,
+    occupation: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CarePlanPayload {
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    title: String,
+    description: Option<String>,
+    // Defaults to today
+    start_date: Option<String>,
+    end_date: Option<String>,
+    // Target bounds may be given in any unit that applies to the observation type
+    goals: Vec<CareGoal>,
+    interventions: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CareTaskPayload {
+    care_plan_id: CarePlanId,
+    description: String,
+    assigned_to: DoctorId,
+    due_date: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MedicationPayload {
+    name: String,
+    dose: Dose,
+    route: Route,
+    // One of QD, BID, TID, QID, PRN or qNh (for example q8h)
+    frequency: String,
+    start_date: String,
+    end_date: Option<String>,
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    indication: Option<String>,
+    // Required when the medication matches an active allergy of the patient
+    allergy_override_reason: Option<String>,
+}
+
+// Update payloads only carry the fields the caller wants to change. Optional fields are
+// doubly wrapped: `Some(None)` clears them.
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct DoctorUpdatePayload {
+    name: Option<String>,
+    specialities: Option<Vec<String>>,
+    license_number: Option<Option<String>>,
+    qualifications: Option<Vec<String>>,
+    languages: Option<Vec<String>>,
+    consultation_fee: Option<Option<ConsultationFee>>,
+    bio: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct PatientUpdatePayload {
+    name: Option<String>,
+    preferred_name: Option<Option<String>>,
+    date_of_birth: Option<String>,
+    sex: Option<Sex>,
+    gender_identity: Option<Option<GenderIdentity>>,
+    phone: Option<Option<String>>,
+    email: Option<Option<String>>,
+    address: Option<Option<Address>>,
+    preferred_language: Option<Option<String>>,
+    medical_record_number: Option<Option<String>>,
+    national_id: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct AppointmentUpdatePayload {
+    patient_id: Option<PatientId>,
+    doctor_id: Option<DoctorId>,
+    date_time: Option<u64>,
+    duration: Option<u32>,
+    description: Option<String>,
+    room_id: Option<Option<RoomId>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct FacilityUpdatePayload {
+    name: Option<String>,
+    address: Option<Option<Address>>,
+    phone: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct DepartmentUpdatePayload {
+    facility_id: Option<FacilityId>,
+    name: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct RoomUpdatePayload {
+    department_id: Option<DepartmentId>,
+    name: Option<String>,
+    kind: Option<RoomKind>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct PatientRecordUpdatePayload {
+    patient_id: Option<PatientId>,
+    doctor_id: Option<DoctorId>,
+    diagnosis: Option<String>,
+    coded_diagnoses: Option<Vec<String>>,
+    treatment: Option<String>,
+    medications: Option<Vec<RecordMedication>>,
+    // Further changes to the problem list; problems already changed stay as they are
+    problems: Option<Vec<RecordProblem>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct MedicationUpdatePayload {
+    name: Option<String>,
+    dose: Option<Dose>,
+    route: Option<Route>,
+    frequency: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<Option<String>>,
+    patient_id: Option<PatientId>,
+    doctor_id: Option<DoctorId>,
+    indication: Option<Option<String>>,
+    allergy_override_reason: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct EmergencyContactPayload {
+    patient_id: PatientId,
+    name: String,
+    relationship: Relationship,
+    phones: Vec<String>,
+    priority: u32,
+    next_of_kin: bool,
+    consent_to_share: bool,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct EmergencyContactUpdatePayload {
+    name: Option<String>,
+    relationship: Option<Relationship>,
+    phones: Option<Vec<String>>,
+    priority: Option<u32>,
+    next_of_kin: Option<bool>,
+    consent_to_share: Option<bool>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct InsuranceCoveragePayload {
+    patient_id: PatientId,
+    payer: String,
+    plan: String,
+    member_id: String,
+    group_number: Option<String>,
+    effective_date: String,
+    expiry_date: Option<String>,
+    order: CoverageOrder,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct InsuranceCoverageUpdatePayload {
+    payer: Option<String>,
+    plan: Option<String>,
+    member_id: Option<String>,
+    group_number: Option<Option<String>>,
+    effective_date: Option<String>,
+    expiry_date: Option<Option<String>>,
+    order: Option<CoverageOrder>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AllergyPayload {
+    patient_id: PatientId,
+    substance: String,
+    reaction: Option<String>,
+    severity: AllergySeverity,
+    status: AllergyStatus,
+    recorded_by: DoctorId,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct ProblemUpdatePayload {
+    condition: Option<String>,
+    code: Option<Option<String>>,
+    onset_date: Option<Option<String>>,
+    status: Option<ProblemStatus>,
+    severity: Option<ProblemSeverity>,
+    resolved_date: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct FamilyHistoryUpdatePayload {
+    relative: Option<Relative>,
+    condition: Option<String>,
+    code: Option<Option<String>>,
+    age_at_onset: Option<Option<u32>>,
+    notes: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct SocialHistoryUpdatePayload {
+    smoking: Option<SmokingStatus>,
+    pack_years: Option<Option<u32>>,
+    alcohol: Option<AlcoholUse>,
+    drinks_per_week: Option<Option<u32>>,
+    occupation: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct CarePlanUpdatePayload {
+    title: Option<String>,
+    description: Option<Option<String>>,
+    status: Option<CarePlanStatus>,
+    start_date: Option<String>,
+    end_date: Option<Option<String>>,
+    // Replace the whole list
+    goals: Option<Vec<CareGoal>>,
+    interventions: Option<Vec<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct CareTaskUpdatePayload {
+    description: Option<String>,
+    assigned_to: Option<DoctorId>,
+    due_date: Option<String>,
+    status: Option<CareTaskStatus>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct AllergyUpdatePayload {
+    substance: Option<String>,
+    reaction: Option<Option<String>>,
+    severity: Option<AllergySeverity>,
+    status: Option<AllergyStatus>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ObservationPayload {
+    patient_id: PatientId,
+    appointment_id: Option<AppointmentId>,
+    observation_type: ObservationType,
+    value: ObservationValue,
+    unit: ObservationUnit,
+    observed_at: u64,
+    note: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct LabOrderPayload {
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    tests: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct LabResultPayload {
+    order_id: LabOrderId,
+    analyte: String,
+    value: f64,
+    unit: String,
+    reference_range: Option<ReferenceRange>,
+    performed_at: u64,
+    comment: Option<String>,
+}
+
+// The order of a result cannot change
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct LabResultUpdatePayload {
+    analyte: Option<String>,
+    value: Option<f64>,
+    unit: Option<String>,
+    reference_range: Option<Option<ReferenceRange>>,
+    performed_at: Option<u64>,
+    comment: Option<Option<String>>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ImmunizationPayload {
+    patient_id: PatientId,
+    vaccine: String,
+    dose_number: u32,
+    lot_number: Option<String>,
+    site: Option<AdministrationSite>,
+    administered_by: DoctorId,
+    administered_on: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct ImmunizationUpdatePayload {
+    vaccine: Option<String>,
+    dose_number: Option<u32>,
+    lot_number: Option<Option<String>>,
+    site: Option<Option<AdministrationSite>>,
+    administered_on: Option<String>,
+}
+
+// Sections left out are filled from the template for the doctor's speciality
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ClinicalNotePayload {
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    appointment_id: Option<AppointmentId>,
+    subjective: Option<String>,
+    objective: Option<String>,
+    assessment: Option<String>,
+    plan: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct ClinicalNoteUpdatePayload {
+    appointment_id: Option<Option<AppointmentId>>,
+    subjective: Option<String>,
+    objective: Option<String>,
+    assessment: Option<String>,
+    plan: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DocumentPayload {
+    patient_id: PatientId,
+    title: String,
+    category: DocumentCategory,
+    mime_type: String,
+    size: u64,
+    sha256: Option<String>,
+}
+
+// The type of an observation cannot change; the value and unit are given together
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct ObservationUpdatePayload {
+    appointment_id: Option<Option<AppointmentId>>,
+    measurement: Option<(ObservationValue, ObservationUnit)>,
+    observed_at: Option<u64>,
+    note: Option<Option<String>>,
+}
+
+// Update responses return the stored entity and the names of the fields that changed
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DoctorUpdateResponse {
+    doctor: Doctor,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FacilityUpdateResponse {
+    facility: Facility,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DepartmentUpdateResponse {
+    department: Department,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RoomUpdateResponse {
+    room: Room,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientUpdateResponse {
+    patient: Patient,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AppointmentUpdateResponse {
+    appointment: Appointment,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientRecordUpdateResponse {
+    patient_record: PatientRecord,
+    changed_fields: Vec<String>,
+    interaction_warnings: Vec<InteractionWarning>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientRecordCreateResponse {
+    patient_record: PatientRecord,
+    interaction_warnings: Vec<InteractionWarning>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ProblemUpdateResponse {
+    problem: Problem,
+    changed_fields: Vec<String>,
+}
+
+// A patient's problems grouped by status, each group with the latest onset first
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct ProblemList {
+    active: Vec<Problem>,
+    in_remission: Vec<Problem>,
+    resolved: Vec<Problem>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FamilyHistoryUpdateResponse {
+    family_history: FamilyHistory,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct SocialHistoryUpdateResponse {
+    social_history: SocialHistory,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CarePlanUpdateResponse {
+    care_plan: CarePlan,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CareTaskUpdateResponse {
+    care_task: CareTask,
+    changed_fields: Vec<String>,
+}
+
+// A care plan's goals compared against the patient's observations, with its task counts
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CarePlanProgress {
+    care_plan: CarePlan,
+    goals: Vec<GoalProgress>,
+    open_tasks: u32,
+    // Open tasks past their due date
+    overdue_tasks: u32,
+    done_tasks: u32,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct GoalProgress {
+    goal: CareGoal,
+    status: GoalStatus,
+    // The first observation of the target type since the plan started
+    baseline: Option<Observation>,
+    latest: Option<Observation>,
+    // Whether the latest observation is closer to the target than the baseline; None
+    // until there is an observation after the baseline
+    improving: Option<bool>,
+    // The target date has passed without the goal being met
+    overdue: bool,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum GoalStatus {
+    NotMeasured,
+    NoData,
+    Met,
+    NotMet,
+}
+
+// What a clinician needs at a glance when opening a patient's chart
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientSummary {
+    patient: Patient,
+    active_problems: Vec<Problem>,
+    active_allergies: Vec<Allergy>,
+    active_medications: Vec<Medication>,
+    latest_observations: Vec<Observation>,
+    family_history: Vec<FamilyHistory>,
+    social_history: Option<SocialHistory>,
+    active_care_plans: Vec<CarePlan>,
+    // Soonest first
+    upcoming_appointments: Vec<Appointment>,
+}
+
+// One dated event in a patient's history
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct TimelineEntry {
+    // Nanoseconds since the epoch; events recorded by date fall at midnight UTC
+    at: u64,
+    event: TimelineEvent,
+    // The entity the event is about; None for social history updates
+    entity_id: Option<u64>,
+    summary: String,
+}
+
+// Where a timeline page ends. Several events can share a timestamp, so the event kind and
+// entity id break ties.
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct TimelineCursor {
+    at: u64,
+    event: TimelineEvent,
+    entity_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientTimeline {
+    entries: Vec<TimelineEntry>,
+    // Pass as `before` to get the next page; None once the history is exhausted
+    next_before: Option<TimelineCursor>,
+}
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum TimelineEvent {
+    Appointment,
+    PatientRecord,
+    MedicationStarted,
+    MedicationEnded,
+    ProblemOnset,
+    ProblemResolved,
+    AllergyRecorded,
+    Immunization,
+    LabOrdered,
+    ClinicalNoteSigned,
+    DocumentAdded,
+    FamilyHistoryRecorded,
+    SocialHistoryUpdated,
+    CarePlanStarted,
+}
+
+// Codes follow the declaration order, so the timeline index sorts like TimelineCursor
+impl TimelineEvent {
+    fn code(self) -> u8 {
+        match self {
+            TimelineEvent::Appointment => 1,
+            TimelineEvent::PatientRecord => 2,
+            TimelineEvent::MedicationStarted => 3,
+            TimelineEvent::MedicationEnded => 4,
+            TimelineEvent::ProblemOnset => 5,
+            TimelineEvent::ProblemResolved => 6,
+            TimelineEvent::AllergyRecorded => 7,
+            TimelineEvent::Immunization => 8,
+            TimelineEvent::LabOrdered => 9,
+            TimelineEvent::ClinicalNoteSigned => 10,
+            TimelineEvent::DocumentAdded => 11,
+            TimelineEvent::FamilyHistoryRecorded => 12,
+            TimelineEvent::SocialHistoryUpdated => 13,
+            TimelineEvent::CarePlanStarted => 14,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<TimelineEvent> {
+        match code {
+            1 => Some(TimelineEvent::Appointment),
+            2 => Some(TimelineEvent::PatientRecord),
+            3 => Some(TimelineEvent::MedicationStarted),
+            4 => Some(TimelineEvent::MedicationEnded),
+            5 => Some(TimelineEvent::ProblemOnset),
+            6 => Some(TimelineEvent::ProblemResolved),
+            7 => Some(TimelineEvent::AllergyRecorded),
+            8 => Some(TimelineEvent::Immunization),
+            9 => Some(TimelineEvent::LabOrdered),
+            10 => Some(TimelineEvent::ClinicalNoteSigned),
+            11 => Some(TimelineEvent::DocumentAdded),
+            12 => Some(TimelineEvent::FamilyHistoryRecorded),
+            13 => Some(TimelineEvent::SocialHistoryUpdated),
+            14 => Some(TimelineEvent::CarePlanStarted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientRecordWithMedications {
+    patient_record: PatientRecord,
+    medications: Vec<Medication>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MedicationUpdateResponse {
+    medication: Medication,
+    changed_fields: Vec<String>,
+    interaction_warnings: Vec<InteractionWarning>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MedicationCreateResponse {
+    medication: Medication,
+    interaction_warnings: Vec<InteractionWarning>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct EmergencyContactUpdateResponse {
+    emergency_contact: EmergencyContact,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct InsuranceCoverageUpdateResponse {
+    insurance_coverage: InsuranceCoverage,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AllergyUpdateResponse {
+    allergy: Allergy,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ObservationUpdateResponse {
+    observation: Observation,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct LabResultUpdateResponse {
+    result: LabResult,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ImmunizationUpdateResponse {
+    immunization: Immunization,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ClinicalNoteUpdateResponse {
+    note: ClinicalNote,
+    changed_fields: Vec<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct LabOrderWithResults {
+    order: LabOrder,
+    results: Vec<LabResult>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+enum EntityKind {
+    Doctor,
+    Patient,
+    Appointment,
+    PatientRecord,
+    Medication,
+    EmergencyContact,
+    InsuranceCoverage,
+    Allergy,
+    Observation,
+    LabOrder,
+    LabResult,
+    Immunization,
+    ClinicalNote,
+    Document,
+    Facility,
+    Department,
+    Room,
+    Problem,
+    FamilyHistory,
+    CarePlan,
+    CareTask,
+}
+
+impl EntityKind {
+    fn tag(self) -> u8 {
+        match self {
+            EntityKind::Doctor => 1,
+            EntityKind::Patient => 2,
+            EntityKind::Appointment => 3,
+            EntityKind::PatientRecord => 4,
+            EntityKind::Medication => 5,
+            EntityKind::EmergencyContact => 6,
+            EntityKind::InsuranceCoverage => 7,
+            EntityKind::Allergy => 8,
+            EntityKind::Observation => 9,
+            EntityKind::LabOrder => 10,
+            EntityKind::LabResult => 11,
+            EntityKind::Immunization => 12,
+            EntityKind::ClinicalNote => 13,
+            EntityKind::Document => 14,
+            EntityKind::Facility => 15,
+            EntityKind::Department => 16,
+            EntityKind::Room => 17,
+            EntityKind::Problem => 18,
+            EntityKind::FamilyHistory => 19,
+            EntityKind::CarePlan => 20,
+            EntityKind::CareTask => 21,
+        }
+    }
+
+    // Returns None for legacy ids, which were allocated without a kind tag
+    fn from_id(id: u64) -> Option<EntityKind> {
+        match (id >> ID_TAG_SHIFT) as u8 {
+            1 => Some(EntityKind::Doctor),
+            2 => Some(EntityKind::Patient),
+            3 => Some(EntityKind::Appointment),
+            4 => Some(EntityKind::PatientRecord),
+            5 => Some(EntityKind::Medication),
+            6 => Some(EntityKind::EmergencyContact),
+            7 => Some(EntityKind::InsuranceCoverage),
+            8 => Some(EntityKind::Allergy),
+            9 => Some(EntityKind::Observation),
+            10 => Some(EntityKind::LabOrder),
+            11 => Some(EntityKind::LabResult),
+            12 => Some(EntityKind::Immunization),
+            13 => Some(EntityKind::ClinicalNote),
+            14 => Some(EntityKind::Document),
+            15 => Some(EntityKind::Facility),
+            16 => Some(EntityKind::Department),
+            17 => Some(EntityKind::Room),
+            18 => Some(EntityKind::Problem),
+            19 => Some(EntityKind::FamilyHistory),
+            20 => Some(EntityKind::CarePlan),
+            21 => Some(EntityKind::CareTask),
+            _ => None,
+        }
+    }
+}
+
+// What to do with rows that still reference a patient or doctor being deleted
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize)]
+enum DependentAction {
+    // Refuse the deletion while dependents exist
+    Restrict,
+    // Remove the dependents together with their owner
+    Cascade,
+}
+
+// Future appointments of a departing doctor can also be moved to another doctor
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize)]
+enum FutureAppointmentAction {
+    Restrict,
+    Cascade,
+    Reassign(DoctorId),
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientDeleteOptions {
+    appointments: DependentAction,
+    patient_records: DependentAction,
+    medications: DependentAction,
+    clinical_notes: DependentAction,
+    emergency_contacts: DependentAction,
+    insurance_coverages: DependentAction,
+    allergies: DependentAction,
+    observations: DependentAction,
+    lab_orders: DependentAction,
+    immunizations: DependentAction,
+    documents: DependentAction,
+    problems: DependentAction,
+    family_history: DependentAction,
+    care_plans: DependentAction,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DoctorDeleteOptions {
+    future_appointments: FutureAppointmentAction,
+    past_appointments: DependentAction,
+    patient_records: DependentAction,
+    medications: DependentAction,
+    allergies: DependentAction,
+    lab_orders: DependentAction,
+    immunizations: DependentAction,
+    clinical_notes: DependentAction,
+    problems: DependentAction,
+    // Care plans the doctor wrote
+    care_plans: DependentAction,
+    // Care plan tasks assigned to the doctor
+    care_tasks: DependentAction,
+}
+
+// The dependents a deletion removes or reassigns
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct DeleteImpact {
+    removed_appointments: Vec<AppointmentId>,
+    removed_patient_records: Vec<PatientRecordId>,
+    removed_medications: Vec<MedicationId>,
+    removed_emergency_contacts: Vec<EmergencyContactId>,
+    removed_insurance_coverages: Vec<InsuranceCoverageId>,
+    removed_allergies: Vec<AllergyId>,
+    removed_observations: Vec<ObservationId>,
+    // Removing a lab order also removes its results
+    removed_lab_orders: Vec<LabOrderId>,
+    removed_immunizations: Vec<ImmunizationId>,
+    removed_clinical_notes: Vec<ClinicalNoteId>,
+    removed_documents: Vec<DocumentId>,
+    removed_problems: Vec<ProblemId>,
+    removed_family_history: Vec<FamilyHistoryId>,
+    // Removing a care plan also removes its tasks
+    removed_care_plans: Vec<CarePlanId>,
+    removed_care_tasks: Vec<CareTaskId>,
+    reassigned_appointments: Vec<AppointmentId>,
+    reassigned_to: Option<DoctorId>,
+}
+
+// The dependents a merge moves from the duplicate to the survivor
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct PatientMergeResponse {
+    survivor: Patient,
+    // Fields the survivor lacked and took from the duplicate
+    filled_fields: Vec<String>,
+    moved_appointments: Vec<AppointmentId>,
+    moved_patient_records: Vec<PatientRecordId>,
+    moved_medications: Vec<MedicationId>,
+    moved_emergency_contacts: Vec<EmergencyContactId>,
+    moved_insurance_coverages: Vec<InsuranceCoverageId>,
+    moved_allergies: Vec<AllergyId>,
+    moved_observations: Vec<ObservationId>,
+    moved_lab_orders: Vec<LabOrderId>,
+    moved_immunizations: Vec<ImmunizationId>,
+    moved_clinical_notes: Vec<ClinicalNoteId>,
+    moved_documents: Vec<DocumentId>,
+    moved_problems: Vec<ProblemId>,
+    moved_family_history: Vec<FamilyHistoryId>,
+    moved_care_plans: Vec<CarePlanId>,
+    // Set when the survivor had no social history and took the duplicate's
+    moved_social_history: bool,
+}
+
+// A foreign key that points at a row which no longer exists
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DanglingReference {
+    entity: EntityKind,
+    entity_id: u64,
+    field: String,
+    missing: EntityKind,
+    missing_id: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Message {
+    Success(String),
+    Error(String),
+    NotFound(String),
+    InvalidPayload(String),
+    Unauthorized(String),
+}
+
+#[ic_cdk::init]
+fn init() {
+    STORED_SCHEMA_VERSION
+        .with(|version| version.borrow_mut().set(SCHEMA_VERSION))
+        .expect("Cannot set the schema version");
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    if !migration_finished() {
+        continue_migration();
+    }
+}
+
+// Ingress updates are refused until the migration has finished, so none of them sees rows
+// that are still in the original layout
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if migration_finished() {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+// Function to create a doctor
+#[ic_cdk::update]
+fn create_doctor(payload: DoctorPayload) -> Result<Doctor, Message> {
+    let mut doctor = Doctor {
+        id: DoctorId::default(),
+        name: payload.name,
+        specialities: catalogue_specialities(&payload.specialities)?,
+        license_number: payload.license_number,
+        qualifications: payload.qualifications,
+        languages: payload.languages,
+        consultation_fee: payload.consultation_fee,
+        bio: payload.bio,
+        created_at: current_time(),
+    };
+    validate_doctor(&doctor)?;
+
+    doctor.id = DoctorId(next_id(EntityKind::Doctor));
+    save_doctor(&doctor);
+    Ok(doctor)
+}
+
+// Function to get all doctors
+#[ic_cdk::query]
+fn get_doctors() -> Result<Vec<Doctor>, Message> {
+    DOCTORS_STORAGE.with(|storage| {
+        let doctors: Vec<Doctor> = storage.borrow().iter().map(|(_, doctor)| doctor).collect();
+
+        if doctors.is_empty() {
+            Err(Message::NotFound("No doctors found".to_string()))
+        } else {
+            Ok(doctors)
+        }
+    })
+}
+
+// Function to get a doctor by ID
+#[ic_cdk::query]
+fn get_doctor_id(doctor_id: DoctorId) -> Result<Doctor, Message> {
+    ensure_id_kind(doctor_id.0, EntityKind::Doctor)?;
+    DOCTORS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&doctor_id)
+            .ok_or(Message::NotFound("Doctor not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_doctor(
+    id: DoctorId,
+    payload: DoctorUpdatePayload,
+) -> Result<DoctorUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Doctor)?;
+    let mut doctor = DOCTORS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Doctor not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(&mut doctor.name, payload.name, "name", &mut changed_fields);
+    apply_field(
+        &mut doctor.specialities,
+        payload
+            .specialities
+            .map(|names| catalogue_specialities(&names))
+            .transpose()?,
+        "specialities",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut doctor.license_number,
+        payload.license_number,
+        "license_number",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut doctor.qualifications,
+        payload.qualifications,
+        "qualifications",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut doctor.languages,
+        payload.languages,
+        "languages",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut doctor.consultation_fee,
+        payload.consultation_fee,
+        "consultation_fee",
+        &mut changed_fields,
+    );
+    apply_field(&mut doctor.bio, payload.bio, "bio", &mut changed_fields);
+    validate_doctor(&doctor)?;
+
+    if !changed_fields.is_empty() {
+        save_doctor(&doctor);
+    }
+    Ok(DoctorUpdateResponse {
+        doctor,
+        changed_fields,
+    })
+}
+
+// Function to preview what deleting a doctor would do to its dependents
+#[ic_cdk::query]
+fn preview_delete_doctor(
+    id: DoctorId,
+    options: DoctorDeleteOptions,
+) -> Result<DeleteImpact, Message> {
+    plan_doctor_delete(id, &options)
+}
+
+#[ic_cdk::update]
+fn delete_doctor(id: DoctorId, options: DoctorDeleteOptions) -> Result<DeleteImpact, Message> {
+    let impact = plan_doctor_delete(id, &options)?;
+    apply_delete_impact(&impact);
+    remove_doctor(id);
+    Ok(impact)
+}
+
+// Function to find doctors by speciality and spoken language for booking. Either filter can
+// be left out. Languages match on their primary subtag, so "pt" also finds "pt-BR" speakers.
+#[ic_cdk::query]
+fn find_doctors(
+    speciality: Option<String>,
+    language: Option<String>,
+) -> Result<Vec<Doctor>, Message> {
+    let language = match language {
+        Some(language) => {
+            validate_language_tag("language", &language)?;
+            Some(language_key(&language))
+        }
+        None => None,
+    };
+    let speciality = match speciality {
+        Some(name) => Some(
+            find_speciality(&name).ok_or(Message::NotFound("Speciality not found".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let mut doctors: Vec<Doctor> = match (&speciality, language) {
+        (Some(speciality), _) => doctor_ids_for_speciality(speciality_key(&speciality.name))
+            .into_iter()
+            .filter(|id| match language {
+                Some(key) => {
+                    DOCTORS_BY_LANGUAGE.with(|index| index.borrow().contains_key(&(key, *id)))
+                }
+                None => true,
+            })
+            .filter_map(|id| DOCTORS_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .collect(),
+        (None, Some(key)) => doctor_ids_for_language(key)
+            .into_iter()
+            .filter_map(|id| DOCTORS_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .collect(),
+        (None, None) => DOCTORS_STORAGE
+            .with(|storage| storage.borrow().iter().map(|(_, doctor)| doctor).collect()),
+    };
+    doctors.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(doctors)
+}
+
+// Function to add a speciality to the catalogue or replace its description
+#[ic_cdk::update]
+fn set_speciality(speciality: Speciality) -> Result<Speciality, Message> {
+    ensure_controller()?;
+    let speciality = Speciality {
+        name: speciality.name.trim().to_string(),
+        ..speciality
+    };
+    let key = normalize_words(&speciality.name);
+    if key.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' is provided.".to_string(),
+        ));
+    }
+    validate_length("name", &speciality.name, MAX_LABEL_LENGTH)?;
+    validate_length("name", &key, MAX_LABEL_LENGTH)?;
+    validate_length(
+        "description",
+        &speciality.description,
+        MAX_DESCRIPTION_LENGTH,
+    )?;
+
+    let key = speciality_key(&speciality.name);
+    let previous =
+        SPECIALITIES.with(|catalogue| catalogue.borrow_mut().insert(key, speciality.clone()));
+
+    // Doctors list specialities by catalogue name, so follow a change of spelling
+    if let Some(previous) = previous {
+        if previous.name != speciality.name {
+            for id in doctor_ids_for_speciality(key) {
+                if let Some(mut doctor) = DOCTORS_STORAGE.with(|storage| storage.borrow().get(&id))
+                {
+                    for name in doctor.specialities.iter_mut() {
+                        if *name == previous.name {
+                            *name = speciality.name.clone();
+                        }
+                    }
+                    save_doctor(&doctor);
+                }
+            }
+        }
+    }
+    Ok(speciality)
+}
+
+#[ic_cdk::query]
+fn get_specialities() -> Vec<Speciality> {
+    SPECIALITIES.with(|catalogue| {
+        catalogue
+            .borrow()
+            .iter()
+            .map(|(_, speciality)| speciality)
+            .collect()
+    })
+}
+
+// Function to remove a speciality that no doctor or note template uses any more
+#[ic_cdk::update]
+fn delete_speciality(name: String) -> Result<(), Message> {
+    ensure_controller()?;
+    let speciality =
+        find_speciality(&name).ok_or(Message::NotFound("Speciality not found".to_string()))?;
+    let key = speciality_key(&speciality.name);
+
+    let doctors = doctor_ids_for_speciality(key).len();
+    if doctors > 0 {
+        return Err(Message::InvalidPayload(format!(
+            "Speciality '{}' is listed by {} doctor(s); remove it from them first.",
+            speciality.name, doctors
+        )));
+    }
+    if NOTE_TEMPLATES.with(|templates| templates.borrow().contains_key(&key)) {
+        return Err(Message::InvalidPayload(format!(
+            "Delete the note template of '{}' first.",
+            speciality.name
+        )));
+    }
+    SPECIALITIES.with(|catalogue| catalogue.borrow_mut().remove(&key));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn create_facility(payload: FacilityPayload) -> Result<Facility, Message> {
+    let mut facility = Facility {
+        id: FacilityId::default(),
+        name: payload.name,
+        address: payload.address,
+        phone: payload.phone,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_facility(&facility)?;
+
+    facility.id = FacilityId(next_id(EntityKind::Facility));
+    FACILITIES_STORAGE.with(|storage| storage.borrow_mut().insert(facility.id, facility.clone()));
+    Ok(facility)
+}
+
+#[ic_cdk::query]
+fn get_facilities() -> Vec<Facility> {
+    FACILITIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, facility)| facility)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_facility_by_id(id: FacilityId) -> Result<Facility, Message> {
+    ensure_id_kind(id.0, EntityKind::Facility)?;
+    FACILITIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Facility not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_facility(
+    id: FacilityId,
+    payload: FacilityUpdatePayload,
+) -> Result<FacilityUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Facility)?;
+    let mut facility = FACILITIES_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Facility not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut facility.name,
+        payload.name,
+        "name",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut facility.address,
+        payload.address,
+        "address",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut facility.phone,
+        payload.phone,
+        "phone",
+        &mut changed_fields,
+    );
+    validate_facility(&facility)?;
+
+    if !changed_fields.is_empty() {
+        facility.updated_at = Some(current_time());
+        FACILITIES_STORAGE.with(|storage| storage.borrow_mut().insert(id, facility.clone()));
+    }
+    Ok(FacilityUpdateResponse {
+        facility,
+        changed_fields,
+    })
+}
+
+// Function to delete a facility that has no departments left
+#[ic_cdk::update]
+fn delete_facility(id: FacilityId) -> Result<(), Message> {
+    ensure_facility_exists(id)?;
+    resolve_dependents(
+        "departments",
+        department_ids_for_facility(id),
+        DependentAction::Restrict,
+    )?;
+    FACILITIES_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn create_department(payload: DepartmentPayload) -> Result<Department, Message> {
+    validate_location_name(&payload.name)?;
+
+    // Validate facility id
+    ensure_facility_exists(payload.facility_id)?;
+
+    let department = Department {
+        id: DepartmentId(next_id(EntityKind::Department)),
+        facility_id: payload.facility_id,
+        name: payload.name,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_department(&department);
+    Ok(department)
+}
+
+#[ic_cdk::query]
+fn get_department_by_id(id: DepartmentId) -> Result<Department, Message> {
+    ensure_id_kind(id.0, EntityKind::Department)?;
+    DEPARTMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Department not found".to_string()))
+    })
+}
+
+#[ic_cdk::query]
+fn get_facility_departments(facility_id: FacilityId) -> Result<Vec<Department>, Message> {
+    ensure_facility_exists(facility_id)?;
+    Ok(department_ids_for_facility(facility_id)
+        .into_iter()
+        .filter_map(|id| DEPARTMENTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+#[ic_cdk::update]
+fn update_department(
+    id: DepartmentId,
+    payload: DepartmentUpdatePayload,
+) -> Result<DepartmentUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Department)?;
+    let mut department = DEPARTMENTS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Department not found".to_string()))?;
+
+    // Validate any facility id being changed
+    if let Some(facility_id) = payload.facility_id {
+        ensure_facility_exists(facility_id)?;
+    }
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut department.facility_id,
+        payload.facility_id,
+        "facility_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut department.name,
+        payload.name,
+        "name",
+        &mut changed_fields,
+    );
+    validate_location_name(&department.name)?;
+
+    if !changed_fields.is_empty() {
+        department.updated_at = Some(current_time());
+        save_department(&department);
+    }
+    Ok(DepartmentUpdateResponse {
+        department,
+        changed_fields,
+    })
+}
+
+// Function to delete a department that has no rooms left; its doctors are detached from it
+#[ic_cdk::update]
+fn delete_department(id: DepartmentId) -> Result<(), Message> {
+    ensure_department_exists(id)?;
+    resolve_dependents(
+        "rooms",
+        room_ids_for_department(id),
+        DependentAction::Restrict,
+    )?;
+    remove_department(id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn add_doctor_to_department(
+    department_id: DepartmentId,
+    doctor_id: DoctorId,
+) -> Result<(), Message> {
+    ensure_department_exists(department_id)?;
+    ensure_doctor_exists(doctor_id)?;
+    add_department_member(department_id, doctor_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_doctor_from_department(
+    department_id: DepartmentId,
+    doctor_id: DoctorId,
+) -> Result<(), Message> {
+    ensure_department_exists(department_id)?;
+    ensure_doctor_exists(doctor_id)?;
+    if remove_department_member(department_id, doctor_id) {
+        Ok(())
+    } else {
+        Err(Message::NotFound(
+            "Doctor is not in the department".to_string(),
+        ))
+    }
+}
+
+#[ic_cdk::query]
+fn get_department_doctors(department_id: DepartmentId) -> Result<Vec<Doctor>, Message> {
+    ensure_department_exists(department_id)?;
+    Ok(doctor_ids_for_department(department_id)
+        .into_iter()
+        .filter_map(|id| DOCTORS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+#[ic_cdk::query]
+fn get_doctor_departments(doctor_id: DoctorId) -> Result<Vec<Department>, Message> {
+    ensure_doctor_exists(doctor_id)?;
+    Ok(department_ids_for_doctor(doctor_id)
+        .into_iter()
+        .filter_map(|id| DEPARTMENTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+#[ic_cdk::update]
+fn create_room(payload: RoomPayload) -> Result<Room, Message> {
+    validate_location_name(&payload.name)?;
+
+    // Validate department id
+    ensure_department_exists(payload.department_id)?;
+
+    let room = Room {
+        id: RoomId(next_id(EntityKind::Room)),
+        department_id: payload.department_id,
+        name: payload.name,
+        kind: payload.kind,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_room(&room);
+    Ok(room)
+}
+
+#[ic_cdk::query]
+fn get_room_by_id(id: RoomId) -> Result<Room, Message> {
+    ensure_id_kind(id.0, EntityKind::Room)?;
+    ROOMS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Room not found".to_string()))
+    })
+}
+
+#[ic_cdk::query]
+fn get_department_rooms(department_id: DepartmentId) -> Result<Vec<Room>, Message> {
+    ensure_department_exists(department_id)?;
+    Ok(room_ids_for_department(department_id)
+        .into_iter()
+        .filter_map(|id| ROOMS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+#[ic_cdk::update]
+fn update_room(id: RoomId, payload: RoomUpdatePayload) -> Result<RoomUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Room)?;
+    let mut room = ROOMS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Room not found".to_string()))?;
+
+    // Validate any department id being changed
+    if let Some(department_id) = payload.department_id {
+        ensure_department_exists(department_id)?;
+    }
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut room.department_id,
+        payload.department_id,
+        "department_id",
+        &mut changed_fields,
+    );
+    apply_field(&mut room.name, payload.name, "name", &mut changed_fields);
+    apply_field(&mut room.kind, payload.kind, "kind", &mut changed_fields);
+    validate_location_name(&room.name)?;
+
+    if !changed_fields.is_empty() {
+        room.updated_at = Some(current_time());
+        save_room(&room);
+    }
+    Ok(RoomUpdateResponse {
+        room,
+        changed_fields,
+    })
+}
+
+// Function to delete a room with no upcoming appointments; past appointments in it are kept
+// and unlinked from it
+#[ic_cdk::update]
+fn delete_room(id: RoomId) -> Result<(), Message> {
+    ensure_room_exists(id)?;
+    let now = current_time();
+    let (upcoming, past): (Vec<Appointment>, Vec<Appointment>) =
+        load_appointments(appointment_ids_for_room(id, 0, u64::MAX))
+            .into_iter()
+            .partition(|appointment| {
+                appointment_end(appointment.date_time, appointment.duration) > now
+            });
+    resolve_dependents(
+        "upcoming appointments",
+        upcoming.iter().map(|appointment| appointment.id).collect(),
+        DependentAction::Restrict,
+    )?;
+
+    for mut appointment in past {
+        appointment.room_id = None;
+        appointment.updated_at = Some(now);
+        save_appointment(&appointment);
+    }
+    remove_room(id);
+    Ok(())
+}
+
+// Function to get a room's appointments on a day (UTC), ordered by start time, including
+// one that started the day before and runs into it
+#[ic_cdk::query]
+fn get_room_schedule(room_id: RoomId, date: String) -> Result<Vec<Appointment>, Message> {
+    ensure_room_exists(room_id)?;
+    let start = parse_date(&date)
+        .and_then(timestamp_from_date)
+        .ok_or(Message::InvalidPayload(
+            "'date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?;
+    Ok(room_appointments_overlapping(
+        room_id,
+        start,
+        start.saturating_add(NANOS_PER_DAY),
+    ))
+}
+
+#[ic_cdk::update]
+fn create_patient(payload: PatientPayload) -> Result<Patient, Message> {
+    let mut patient = Patient {
+        id: PatientId::default(),
+        name: payload.name,
+        preferred_name: payload.preferred_name,
+        date_of_birth: payload.date_of_birth,
+        date_of_birth_estimated: false,
+        age: 0,
+        sex: payload.sex,
+        gender_identity: payload.gender_identity,
+        phone: payload.phone,
+        email: payload.email,
+        address: payload.address,
+        preferred_language: payload.preferred_language,
+        medical_record_number: payload.medical_record_number,
+        national_id: payload.national_id,
+        created_at: current_time(),
+    };
+    validate_patient(&patient)?;
+
+    patient.id = PatientId(next_id(EntityKind::Patient));
+    save_patient(&patient);
+    Ok(with_current_age(patient))
+}
+
+#[ic_cdk::query]
+fn get_patients() -> Result<Vec<Patient>, Message> {
+    PATIENTS_STORAGE.with(|storage| {
+        let patients: Vec<Patient> = storage
+            .borrow()
+            .iter()
+            .map(|(_, patient)| with_current_age(patient))
+            .collect();
+
+        if patients.is_empty() {
+            Err(Message::NotFound("No patients found".to_string()))
+        } else {
+            Ok(patients)
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_patient_by_id(id: PatientId) -> Result<Patient, Message> {
+    // A merged patient resolves to the patient it was merged into
+    let id = resolve_patient(id)?;
+    PATIENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .map(with_current_age)
+            .ok_or(Message::NotFound("Patient not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_patient(
+    id: PatientId,
+    payload: PatientUpdatePayload,
+) -> Result<PatientUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Patient)?;
+    let mut patient = PATIENTS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Patient not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(&mut patient.name, payload.name, "name", &mut changed_fields);
+    apply_field(
+        &mut patient.preferred_name,
+        payload.preferred_name,
+        "preferred_name",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.date_of_birth,
+        payload.date_of_birth,
+        "date_of_birth",
+        &mut changed_fields,
+    );
+    if changed_fields.iter().any(|field| field == "date_of_birth") {
+        patient.date_of_birth_estimated = false;
+    }
+    apply_field(&mut patient.sex, payload.sex, "sex", &mut changed_fields);
+    apply_field(
+        &mut patient.gender_identity,
+        payload.gender_identity,
+        "gender_identity",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.phone,
+        payload.phone,
+        "phone",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.email,
+        payload.email,
+        "email",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.address,
+        payload.address,
+        "address",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.preferred_language,
+        payload.preferred_language,
+        "preferred_language",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.medical_record_number,
+        payload.medical_record_number,
+        "medical_record_number",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient.national_id,
+        payload.national_id,
+        "national_id",
+        &mut changed_fields,
+    );
+    validate_patient(&patient)?;
+
+    if !changed_fields.is_empty() {
+        save_patient(&patient);
+    }
+    Ok(PatientUpdateResponse {
+        patient: with_current_age(patient),
+        changed_fields,
+    })
+}
+
+// Function to preview what deleting a patient would do to its dependents
+#[ic_cdk::query]
+fn preview_delete_patient(
+    id: PatientId,
+    options: PatientDeleteOptions,
+) -> Result<DeleteImpact, Message> {
+    plan_patient_delete(id, &options)
+}
+
+#[ic_cdk::update]
+fn delete_patient(id: PatientId, options: PatientDeleteOptions) -> Result<DeleteImpact, Message> {
+    let impact = plan_patient_delete(id, &options)?;
+    apply_delete_impact(&impact);
+    // The social history is part of the patient
+    remove_social_history(id);
+    remove_patient(id);
+    // Ids merged into the patient have nothing left to resolve to
+    for merged_id in redirect_ids_to(id) {
+        remove_patient_redirect(merged_id);
+    }
+    Ok(impact)
+}
+
+// Function to find pairs of patients that may be the same person. Only patients sharing a
+// name, exact date of birth, phone number or email are compared, block by block from the
+// blocking-key index. A page ends after `limit` candidates or MAX_DUPLICATE_COMPARISONS
+// comparisons, but never inside a block, so it can hold a few more than `limit`.
+#[ic_cdk::query]
+fn find_duplicate_patients(
+    min_score: u32,
+    after: Option<Vec<u8>>,
+    limit: u32,
+) -> Result<DuplicatePage, Message> {
+    let mut next = match after {
+        Some(after) => Bound::Excluded((
+            DuplicateBlockKey::try_from(after.as_slice()).map_err(|_| {
+                Message::InvalidPayload(
+                    "'after' must be a cursor returned by find_duplicate_patients.".to_string(),
+                )
+            })?,
+            PatientId(u64::MAX),
+        )),
+        None => Bound::Unbounded,
+    };
+    let limit = search_limit(limit);
+    let mut candidates = Vec::new();
+    let mut compared = 0;
+    let mut last_block = None;
+    while candidates.len() < limit && compared < MAX_DUPLICATE_COMPARISONS {
+        let block = match next_duplicate_block(next) {
+            Some(block) => block,
+            None => {
+                last_block = None;
+                break;
+            }
+        };
+        next = Bound::Excluded((block, PatientId(u64::MAX)));
+        last_block = Some(block);
+        let members = duplicate_block_members(block);
+        if members.len() > MAX_DUPLICATE_BLOCK_SIZE {
+            continue;
+        }
+
+        let patients: Vec<Patient> = members
+            .into_iter()
+            .filter_map(|id| PATIENTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .collect();
+        for (n, first) in patients.iter().enumerate() {
+            for second in &patients[n + 1..] {
+                compared += 1;
+                // A pair sharing several blocks is reported in the first of them
+                if first_shared_block(first, second) != Some(block) {
+                    continue;
+                }
+                let (score, matched_fields) = duplicate_score(first, second);
+                if score >= min_score {
+                    candidates.push(DuplicateCandidate {
+                        first: first.id,
+                        second: second.id,
+                        score,
+                        matched_fields,
+                    });
+                }
+            }
+        }
+    }
+    candidates.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then(a.first.cmp(&b.first))
+            .then(a.second.cmp(&b.second))
+    });
+    Ok(DuplicatePage {
+        candidates,
+        next_after: last_block
+            .filter(|_| next_duplicate_block(next).is_some())
+            .map(|block| block.as_slice().to_vec()),
+    })
+}
+
+// Function to merge a duplicate patient into the survivor. Everything linked to the
+// duplicate moves to the survivor, empty fields of the survivor are filled from the
+// duplicate, and the duplicate's id is kept as a redirect to the survivor.
+#[ic_cdk::update]
+fn merge_patients(
+    survivor: PatientId,
+    duplicate: PatientId,
+) -> Result<PatientMergeResponse, Message> {
+    if survivor == duplicate {
+        return Err(Message::InvalidPayload(
+            "A patient cannot be merged into itself.".to_string(),
+        ));
+    }
+    ensure_patient_exists(survivor)?;
+    ensure_patient_exists(duplicate)?;
+    let mut patient = PATIENTS_STORAGE
+        .with(|storage| storage.borrow().get(&survivor))
+        .ok_or(Message::NotFound("Patient not found".to_string()))?;
+    let other =
+        remove_patient(duplicate).ok_or(Message::NotFound("Patient not found".to_string()))?;
+
+    let mut filled_fields = Vec::new();
+    fill_field(
+        &mut patient.preferred_name,
+        other.preferred_name,
+        "preferred_name",
+        &mut filled_fields,
+    );
+    fill_field(
+        &mut patient.gender_identity,
+        other.gender_identity,
+        "gender_identity",
+        &mut filled_fields,
+    );
+    fill_field(&mut patient.phone, other.phone, "phone", &mut filled_fields);
+    fill_field(&mut patient.email, other.email, "email", &mut filled_fields);
+    fill_field(
+        &mut patient.address,
+        other.address,
+        "address",
+        &mut filled_fields,
+    );
+    fill_field(
+        &mut patient.preferred_language,
+        other.preferred_language,
+        "preferred_language",
+        &mut filled_fields,
+    );
+    fill_field(
+        &mut patient.medical_record_number,
+        other.medical_record_number,
+        "medical_record_number",
+        &mut filled_fields,
+    );
+    fill_field(
+        &mut patient.national_id,
+        other.national_id,
+        "national_id",
+        &mut filled_fields,
+    );
+    save_patient(&patient);
+
+    let now = current_time();
+    let mut response = PatientMergeResponse {
+        filled_fields,
+        ..Default::default()
+    };
+    for id in appointment_ids_for_patient(duplicate) {
+        if let Some(mut appointment) =
+            APPOINTMENTS_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            appointment.patient_id = survivor;
+            appointment.updated_at = Some(now);
+            save_appointment(&appointment);
+            response.moved_appointments.push(id);
+        }
+    }
+    for id in patient_record_ids_for_patient(duplicate) {
+        if let Some(mut patient_record) =
+            PATIENT_RECORDS_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            patient_record.patient_id = survivor;
+            save_patient_record(&patient_record);
+            response.moved_patient_records.push(id);
+        }
+    }
+    for id in medication_ids_for_patient(duplicate) {
+        if let Some(mut medication) = MEDICATIONS_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            medication.patient_id = survivor;
+            medication.updated_at = Some(now);
+            save_medication(&medication);
+            response.moved_medications.push(id);
+        }
+    }
+    for id in emergency_contact_ids_for_patient(duplicate) {
+        if let Some(mut emergency_contact) =
+            EMERGENCY_CONTACTS_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            emergency_contact.patient_id = survivor;
+            emergency_contact.updated_at = Some(now);
+            save_emergency_contact(&emergency_contact);
+            response.moved_emergency_contacts.push(id);
+        }
+    }
+    for id in insurance_coverage_ids_for_patient(duplicate) {
+        if let Some(mut insurance_coverage) =
+            INSURANCE_COVERAGES_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            insurance_coverage.patient_id = survivor;
+            insurance_coverage.updated_at = Some(now);
+            save_insurance_coverage(&insurance_coverage);
+            response.moved_insurance_coverages.push(id);
+        }
+    }
+    for id in allergy_ids_for_patient(duplicate) {
+        if let Some(mut allergy) = ALLERGIES_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            allergy.patient_id = survivor;
+            allergy.updated_at = Some(now);
+            save_allergy(&allergy);
+            response.moved_allergies.push(id);
+        }
+    }
+    for id in observation_ids_for_patient(duplicate) {
+        if let Some(mut observation) =
+            OBSERVATIONS_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            observation.patient_id = survivor;
+            observation.updated_at = Some(now);
+            save_observation(&observation);
+            response.moved_observations.push(id);
+        }
+    }
+    for id in lab_order_ids_for_patient(duplicate) {
+        if let Some(mut order) = LAB_ORDERS_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            order.patient_id = survivor;
+            order.updated_at = Some(now);
+            save_lab_order(&order);
+            response.moved_lab_orders.push(id);
+        }
+    }
+    for id in immunization_ids_for_patient(duplicate) {
+        if let Some(mut immunization) =
+            IMMUNIZATIONS_STORAGE.with(|storage| storage.borrow().get(&id))
+        {
+            immunization.patient_id = survivor;
+            immunization.updated_at = Some(now);
+            save_immunization(&immunization);
+            response.moved_immunizations.push(id);
+        }
+    }
+    for id in clinical_note_ids_for_patient(duplicate) {
+        if let Some(mut note) = CLINICAL_NOTES_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            note.patient_id = survivor;
+            note.updated_at = Some(now);
+            save_clinical_note(&note);
+            response.moved_clinical_notes.push(id);
+        }
+    }
+    for id in document_ids_for_patient(duplicate) {
+        if let Some(mut document) = DOCUMENTS_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            document.patient_id = survivor;
+            document.updated_at = Some(now);
+            save_document(&document);
+            response.moved_documents.push(id);
+        }
+    }
+    for id in problem_ids_for_patient(duplicate) {
+        if let Some(mut problem) = PROBLEMS_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            problem.patient_id = survivor;
+            problem.updated_at = Some(now);
+            save_problem(&problem);
+            response.moved_problems.push(id);
+        }
+    }
+    for id in family_history_ids_for_patient(duplicate) {
+        if let Some(mut entry) = FAMILY_HISTORY_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            entry.patient_id = survivor;
+            entry.updated_at = Some(now);
+            save_family_history(&entry);
+            response.moved_family_history.push(id);
+        }
+    }
+    for id in care_plan_ids_for_patient(duplicate) {
+        if let Some(mut plan) = CARE_PLANS_STORAGE.with(|storage| storage.borrow().get(&id)) {
+            plan.patient_id = survivor;
+            plan.updated_at = Some(now);
+            save_care_plan(&plan);
+            response.moved_care_plans.push(id);
+        }
+    }
+    // The survivor keeps its own social history; the duplicate's is used only if it has none
+    if let Some(mut social_history) = remove_social_history(duplicate) {
+        if !SOCIAL_HISTORY_STORAGE.with(|storage| storage.borrow().contains_key(&survivor)) {
+            social_history.patient_id = survivor;
+            social_history.updated_at = Some(now);
+            save_social_history(&social_history);
+            response.moved_social_history = true;
+        }
+    }
+
+    // Break-glass events keep the id they were logged under, filed under the survivor
+    BREAK_GLASS_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let events: Vec<((PatientId, u64), BreakGlassEvent)> =
+            log.range((duplicate, 0)..=(duplicate, u64::MAX)).collect();
+        for ((_, event_number), event) in events {
+            log.remove(&(duplicate, event_number));
+            log.insert((survivor, event_number), event);
+        }
+    });
+
+    // Earlier redirects to the duplicate now point at the survivor, so each is one hop
+    let earlier = redirect_ids_to(duplicate);
+    for id in earlier.into_iter().chain(std::iter::once(duplicate)) {
+        save_patient_redirect(
+            id,
+            PatientRedirect {
+                merged_into: survivor,
+                merged_at: now,
+            },
+        );
+    }
+
+    response.survivor = with_current_age(patient);
+    Ok(response)
+}
+
+// Function to find the patient a merged patient id now refers to
+#[ic_cdk::query]
+fn resolve_patient_id(id: PatientId) -> Result<PatientId, Message> {
+    resolve_patient(id)
+}
+
+#[ic_cdk::update]
+fn create_appointment(payload: AppointmentPayload) -> Result<Appointment, Message> {
+    validate_appointment(&payload.description)?;
+    validate_appointment_duration(payload.duration)?;
+
+    // Validate the patient, doctor and room ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.doctor_id)?;
+    if let Some(room_id) = payload.room_id {
+        ensure_room_exists(room_id)?;
+        ensure_room_available(room_id, payload.date_time, payload.duration, None)?;
+    }
+
+    let id = AppointmentId(next_id(EntityKind::Appointment));
+
+    let appointment = Appointment {
+        id,
+        patient_id: payload.patient_id,
+        doctor_id: payload.doctor_id,
+        date_time: payload.date_time,
+        duration: payload.duration,
+        description: payload.description,
+        room_id: payload.room_id,
+        eligibility: Some(check_eligibility_on(
+            payload.patient_id,
+            date_from_timestamp(payload.date_time),
+        )),
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_appointment(&appointment);
+    Ok(appointment)
+}
+
+#[ic_cdk::query]
+fn get_appointments() -> Result<Vec<Appointment>, Message> {
+    APPOINTMENTS_STORAGE.with(|storage| {
+        let appointments: Vec<Appointment> = storage
+            .borrow()
+            .iter()
+            .map(|(_, appointment)| appointment.clone())
+            .collect();
+
+        if appointments.is_empty() {
+            Err(Message::NotFound("No appointments found".to_string()))
+        } else {
+            Ok(appointments)
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_appointment_id(id: AppointmentId) -> Result<Appointment, Message> {
+    ensure_id_kind(id.0, EntityKind::Appointment)?;
+    APPOINTMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Appointment not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_appointment(
+    id: AppointmentId,
+    payload: AppointmentUpdatePayload,
+) -> Result<AppointmentUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Appointment)?;
+    let mut appointment = APPOINTMENTS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Appointment not found".to_string()))?;
+
+    // Validate any patient, doctor or room id being changed
+    if let Some(patient_id) = payload.patient_id {
+        ensure_patient_exists(patient_id)?;
+    }
+    if let Some(doctor_id) = payload.doctor_id {
+        ensure_doctor_exists(doctor_id)?;
+    }
+    if let Some(Some(room_id)) = payload.room_id {
+        ensure_room_exists(room_id)?;
+    }
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut appointment.patient_id,
+        payload.patient_id,
+        "patient_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut appointment.doctor_id,
+        payload.doctor_id,
+        "doctor_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut appointment.date_time,
+        payload.date_time,
+        "date_time",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut appointment.duration,
+        payload.duration,
+        "duration",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut appointment.description,
+        payload.description,
+        "description",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut appointment.room_id,
+        payload.room_id,
+        "room_id",
+        &mut changed_fields,
+    );
+    validate_appointment(&appointment.description)?;
+    // Appointments booked before durations were limited keep theirs until it is changed, but
+    // a room can only be booked for a duration in range
+    if changed_fields.iter().any(|field| field == "duration")
+        || (appointment.room_id.is_some() && changed_fields.iter().any(|field| field == "room_id"))
+    {
+        validate_appointment_duration(appointment.duration)?;
+    }
+
+    if let Some(room_id) = appointment.room_id {
+        if changed_fields
+            .iter()
+            .any(|field| field == "room_id" || field == "date_time" || field == "duration")
+        {
+            ensure_room_available(
+                room_id,
+                appointment.date_time,
+                appointment.duration,
+                Some(appointment.id),
+            )?;
+        }
+    }
+
+    if changed_fields
+        .iter()
+        .any(|field| field == "patient_id" || field == "date_time")
+    {
+        appointment.eligibility = Some(check_eligibility_on(
+            appointment.patient_id,
+            date_from_timestamp(appointment.date_time),
+        ));
+    }
+    if !changed_fields.is_empty() {
+        appointment.updated_at = Some(current_time());
+        save_appointment(&appointment);
+    }
+    Ok(AppointmentUpdateResponse {
+        appointment,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_appointment(id: AppointmentId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::Appointment)?;
+    if remove_appointment(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Appointment not found".to_string()))
+    }
+}
+
+#[ic_cdk::update]
+fn create_patient_record(
+    payload: PatientRecordPayload,
+) -> Result<PatientRecordCreateResponse, Message> {
+    validate_patient_record(&payload.diagnosis, &payload.treatment)?;
+    let coded_diagnoses = normalize_coded_diagnoses(payload.coded_diagnoses)?;
+
+    // Validate the patient and doctor ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.doctor_id)?;
+
+    // Nothing is saved until every medication has been checked
+    let medications =
+        prepare_record_medications(payload.patient_id, payload.doctor_id, payload.medications)?;
+    let problems =
+        prepare_record_problems(payload.patient_id, payload.doctor_id, payload.problems)?;
+
+    let id = PatientRecordId(next_id(EntityKind::PatientRecord));
+    let (medications, interaction_warnings) = save_record_medications(medications);
+
+    let patient_record = PatientRecord {
+        id,
+        patient_id: payload.patient_id,
+        doctor_id: payload.doctor_id,
+        diagnosis: payload.diagnosis,
+        coded_diagnoses,
+        treatment: payload.treatment,
+        medications,
+        created_at: current_time(),
+    };
+    save_patient_record(&patient_record);
+    save_record_problems(problems, id);
+    Ok(PatientRecordCreateResponse {
+        patient_record,
+        interaction_warnings,
+    })
+}
+
+#[ic_cdk::query]
+fn get_patient_records() -> Result<Vec<PatientRecord>, Message> {
+    PATIENT_RECORDS_STORAGE.with(|storage| {
+        let patient_records: Vec<PatientRecord> = storage
+            .borrow()
+            .iter()
+            .map(|(_, patient_record)| patient_record.clone())
+            .collect();
+
+        if patient_records.is_empty() {
+            Err(Message::NotFound("No patient records found".to_string()))
+        } else {
+            Ok(patient_records)
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_patient_record_by_id(id: PatientRecordId) -> Result<PatientRecord, Message> {
+    ensure_id_kind(id.0, EntityKind::PatientRecord)?;
+    PATIENT_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Patient record not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_patient_record(
+    id: PatientRecordId,
+    payload: PatientRecordUpdatePayload,
+) -> Result<PatientRecordUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::PatientRecord)?;
+    let mut patient_record = PATIENT_RECORDS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Patient record not found".to_string()))?;
+
+    // Validate any patient or doctor id being changed
+    if let Some(patient_id) = payload.patient_id {
+        ensure_patient_exists(patient_id)?;
+    }
+    if let Some(doctor_id) = payload.doctor_id {
+        ensure_doctor_exists(doctor_id)?;
+    }
+
+    let coded_diagnoses = payload
+        .coded_diagnoses
+        .map(normalize_coded_diagnoses)
+        .transpose()?;
+
+    // Check the medications against the patient and doctor the record will have
+    let patient_id = payload.patient_id.unwrap_or(patient_record.patient_id);
+    let doctor_id = payload.doctor_id.unwrap_or(patient_record.doctor_id);
+    let medications = match payload.medications {
+        Some(medications) => Some(prepare_record_medications(
+            patient_id,
+            doctor_id,
+            medications,
+        )?),
+        None if patient_id != patient_record.patient_id => {
+            let existing = patient_record
+                .medications
+                .iter()
+                .map(|id| RecordMedication::Existing(*id))
+                .collect();
+            prepare_record_medications(patient_id, doctor_id, existing)?;
+            None
+        }
+        None => None,
+    };
+    let problems = payload
+        .problems
+        .map(|changes| prepare_record_problems(patient_id, doctor_id, changes))
+        .transpose()?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut patient_record.patient_id,
+        payload.patient_id,
+        "patient_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient_record.doctor_id,
+        payload.doctor_id,
+        "doctor_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient_record.diagnosis,
+        payload.diagnosis,
+        "diagnosis",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient_record.coded_diagnoses,
+        coded_diagnoses,
+        "coded_diagnoses",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut patient_record.treatment,
+        payload.treatment,
+        "treatment",
+        &mut changed_fields,
+    );
+    validate_patient_record(&patient_record.diagnosis, &patient_record.treatment)?;
+    let mut interaction_warnings = Vec::new();
+    if let Some(medications) = medications {
+        let (medications, warnings) = save_record_medications(medications);
+        interaction_warnings = warnings;
+        apply_field(
+            &mut patient_record.medications,
+            Some(medications),
+            "medications",
+            &mut changed_fields,
+        );
+    }
+    if let Some(problems) = problems.filter(|problems| !problems.is_empty()) {
+        save_record_problems(problems, id);
+        changed_fields.push("problems".to_string());
+    }
+
+    if !changed_fields.is_empty() {
+        save_patient_record(&patient_record);
+    }
+    Ok(PatientRecordUpdateResponse {
+        patient_record,
+        changed_fields,
+        interaction_warnings,
+    })
+}
+
+// Function to get a patient record together with its medications
+#[ic_cdk::query]
+fn get_patient_record_with_medications(
+    id: PatientRecordId,
+) -> Result<PatientRecordWithMedications, Message> {
+    let patient_record = get_patient_record_by_id(id)?;
+    let medications = patient_record
+        .medications
+        .iter()
+        .filter_map(|id| MEDICATIONS_STORAGE.with(|storage| storage.borrow().get(id)))
+        .collect();
+    Ok(PatientRecordWithMedications {
+        patient_record,
+        medications,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_patient_record(id: PatientRecordId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::PatientRecord)?;
+    if remove_patient_record(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Patient record not found".to_string()))
+    }
+}
+
+#[ic_cdk::update]
+fn create_medication(payload: MedicationPayload) -> Result<MedicationCreateResponse, Message> {
+    let frequency = parse_frequency_code(&payload.frequency)?;
+    validate_route(payload.route)?;
+
+    // Validate the patient and prescribing doctor ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.doctor_id)?;
+
+    let mut medication = Medication {
+        id: MedicationId::default(),
+        name: payload.name,
+        dose: Some(payload.dose),
+        route: payload.route,
+        frequency: Some(frequency),
+        start_date: Some(payload.start_date),
+        end_date: payload.end_date,
+        patient_id: payload.patient_id,
+        doctor_id: Some(payload.doctor_id),
+        indication: payload.indication,
+        legacy_dosage: None,
+        legacy_frequency: None,
+        allergy_override: None,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_medication(&medication)?;
+    medication.allergy_override = check_allergies(
+        payload.patient_id,
+        &medication.name,
+        payload.allergy_override_reason,
+    )?;
+    let interaction_warnings = check_prescribing_interactions(payload.patient_id, &medication)?;
+
+    medication.id = MedicationId(next_id(EntityKind::Medication));
+    save_medication(&medication);
+    Ok(MedicationCreateResponse {
+        medication,
+        interaction_warnings,
+    })
+}
+
+#[ic_cdk::query]
+fn get_medications() -> Result<Vec<Medication>, Message> {
+    MEDICATIONS_STORAGE.with(|storage| {
+        let medications: Vec<Medication> = storage
+            .borrow()
+            .iter()
+            .map(|(_, medication)| medication.clone())
+            .collect();
+
+        if medications.is_empty() {
+            Err(Message::NotFound("No medications found".to_string()))
+        } else {
+            Ok(medications)
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_medication_by_id(id: MedicationId) -> Result<Medication, Message> {
+    ensure_id_kind(id.0, EntityKind::Medication)?;
+    MEDICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Medication not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_medication(
+    id: MedicationId,
+    payload: MedicationUpdatePayload,
+) -> Result<MedicationUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Medication)?;
+    let mut medication = MEDICATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Medication not found".to_string()))?;
+
+    // Validate the patient and doctor ids if they are being changed
+    if let Some(patient_id) = payload.patient_id {
+        ensure_patient_exists(patient_id)?;
+        if patient_id != medication.patient_id {
+            ensure_medication_unreferenced(id, &[])?;
+        }
+    }
+    if let Some(doctor_id) = payload.doctor_id {
+        ensure_doctor_exists(doctor_id)?;
+    }
+    if let Some(route) = payload.route {
+        validate_route(route)?;
+    }
+    let frequency = payload
+        .frequency
+        .map(|code| parse_frequency_code(&code))
+        .transpose()?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut medication.name,
+        payload.name,
+        "name",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.dose,
+        payload.dose.map(Some),
+        "dose",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.route,
+        payload.route,
+        "route",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.frequency,
+        frequency.map(Some),
+        "frequency",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.start_date,
+        payload.start_date.map(Some),
+        "start_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.end_date,
+        payload.end_date,
+        "end_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.patient_id,
+        payload.patient_id,
+        "patient_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.doctor_id,
+        payload.doctor_id.map(Some),
+        "doctor_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut medication.indication,
+        payload.indication,
+        "indication",
+        &mut changed_fields,
+    );
+    validate_medication(&medication)?;
+
+    // A new name or patient is checked against the patient's allergies again
+    if changed_fields
+        .iter()
+        .any(|field| field == "name" || field == "patient_id")
+    {
+        let allergy_override = check_allergies(
+            medication.patient_id,
+            &medication.name,
+            payload.allergy_override_reason,
+        )?;
+        apply_field(
+            &mut medication.allergy_override,
+            Some(allergy_override),
+            "allergy_override",
+            &mut changed_fields,
+        );
+    }
+
+    // Interactions are checked again whenever the name, patient or dates change
+    let interaction_warnings = if changed_fields.iter().any(|field| {
+        field == "name" || field == "patient_id" || field == "start_date" || field == "end_date"
+    }) {
+        check_prescribing_interactions(medication.patient_id, &medication)?
+    } else {
+        Vec::new()
+    };
+
+    if !changed_fields.is_empty() {
+        medication.updated_at = Some(current_time());
+        save_medication(&medication);
+    }
+    Ok(MedicationUpdateResponse {
+        medication,
+        changed_fields,
+        interaction_warnings,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_medication(id: MedicationId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::Medication)?;
+    ensure_medication_unreferenced(id, &[])?;
+    if remove_medication(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Medication not found".to_string()))
+    }
+}
+
+#[ic_cdk::update]
+fn create_emergency_contact(payload: EmergencyContactPayload) -> Result<EmergencyContact, Message> {
+    ensure_controller()?;
+    validate_emergency_contact(&payload.name, &payload.relationship, &payload.phones)?;
+
+    // Validate patient id
+    ensure_patient_exists(payload.patient_id)?;
+
+    let mut emergency_contact = EmergencyContact {
+        id: EmergencyContactId::default(),
+        patient_id: payload.patient_id,
+        name: payload.name,
+        relationship: payload.relationship,
+        phones: payload.phones,
+        priority: payload.priority,
+        next_of_kin: payload.next_of_kin,
+        consent_to_share: payload.consent_to_share,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    ensure_fits_bounded(&emergency_contact, "emergency contact")?;
+
+    emergency_contact.id = EmergencyContactId(next_id(EntityKind::EmergencyContact));
+    save_emergency_contact(&emergency_contact);
+    Ok(emergency_contact)
+}
+
+// Function to get an emergency contact for administration; clinical access goes through
+// break_glass_get_emergency_contacts so that it is logged
+#[ic_cdk::query]
+fn get_emergency_contact_by_id(id: EmergencyContactId) -> Result<EmergencyContact, Message> {
+    ensure_controller()?;
+    ensure_id_kind(id.0, EntityKind::EmergencyContact)?;
+    EMERGENCY_CONTACTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Emergency contact not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_emergency_contact(
+    id: EmergencyContactId,
+    payload: EmergencyContactUpdatePayload,
+) -> Result<EmergencyContactUpdateResponse, Message> {
+    ensure_controller()?;
+    ensure_id_kind(id.0, EntityKind::EmergencyContact)?;
+    let mut emergency_contact = EMERGENCY_CONTACTS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Emergency contact not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut emergency_contact.name,
+        payload.name,
+        "name",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut emergency_contact.relationship,
+        payload.relationship,
+        "relationship",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut emergency_contact.phones,
+        payload.phones,
+        "phones",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut emergency_contact.priority,
+        payload.priority,
+        "priority",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut emergency_contact.next_of_kin,
+        payload.next_of_kin,
+        "next_of_kin",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut emergency_contact.consent_to_share,
+        payload.consent_to_share,
+        "consent_to_share",
+        &mut changed_fields,
+    );
+    validate_emergency_contact(
+        &emergency_contact.name,
+        &emergency_contact.relationship,
+        &emergency_contact.phones,
+    )?;
+    ensure_fits_bounded(&emergency_contact, "emergency contact")?;
+
+    if !changed_fields.is_empty() {
+        emergency_contact.updated_at = Some(current_time());
+        save_emergency_contact(&emergency_contact);
+    }
+    Ok(EmergencyContactUpdateResponse {
+        emergency_contact,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_emergency_contact(id: EmergencyContactId) -> Result<(), Message> {
+    ensure_controller()?;
+    ensure_id_kind(id.0, EntityKind::EmergencyContact)?;
+    if remove_emergency_contact(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Emergency contact not found".to_string()))
+    }
+}
+
+// Function to get a patient's emergency contacts in the order they should be called, for
+// administration; clinical access goes through break_glass_get_emergency_contacts so that
+// it is logged
+#[ic_cdk::query]
+fn get_emergency_contacts(patient_id: PatientId) -> Result<Vec<EmergencyContact>, Message> {
+    ensure_controller()?;
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(load_emergency_contacts(patient_id))
+}
+
+// Function for break-glass access: returns the contacts and records who asked and why
+#[ic_cdk::update]
+fn break_glass_get_emergency_contacts(
+    patient_id: PatientId,
+    reason: String,
+) -> Result<Vec<EmergencyContact>, Message> {
+    let accessor = caller();
+    if accessor == Principal::anonymous() {
+        return Err(Message::Unauthorized(
+            "Break-glass access needs an authenticated caller.".to_string(),
+        ));
+    }
+    if reason.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure a 'reason' for the break-glass access is provided.".to_string(),
+        ));
+    }
+    validate_length("reason", &reason, MAX_DESCRIPTION_LENGTH)?;
+    let patient_id = resolve_patient(patient_id)?;
+
+    BREAK_GLASS_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let event_number = log.len();
+        log.insert(
+            (patient_id, event_number),
+            BreakGlassEvent {
+                patient_id,
+                caller: accessor,
+                reason,
+                accessed_at: current_time(),
+            },
+        );
+    });
+    Ok(load_emergency_contacts(patient_id))
+}
+
+// Function to get the break-glass accesses to a patient's emergency contacts, for audit.
+// The log outlives the patient, so a deleted patient's accesses can still be reviewed.
+#[ic_cdk::query]
+fn get_break_glass_events(patient_id: PatientId) -> Result<Vec<BreakGlassEvent>, Message> {
+    ensure_controller()?;
+    ensure_id_kind(patient_id.0, EntityKind::Patient)?;
+    // A merged patient's events were filed under the survivor
+    let patient_id = merged_into(patient_id).unwrap_or(patient_id);
+    Ok(BREAK_GLASS_LOG.with(|log| {
+        log.borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, event)| event)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn create_insurance_coverage(
+    payload: InsuranceCoveragePayload,
+) -> Result<InsuranceCoverage, Message> {
+    validate_insurance_coverage(
+        &payload.payer,
+        &payload.plan,
+        &payload.member_id,
+        &payload.group_number,
+        &payload.effective_date,
+        &payload.expiry_date,
+    )?;
+
+    // Validate patient id
+    ensure_patient_exists(payload.patient_id)?;
+
+    let id = InsuranceCoverageId(next_id(EntityKind::InsuranceCoverage));
+
+    let insurance_coverage = InsuranceCoverage {
+        id,
+        patient_id: payload.patient_id,
+        payer: payload.payer,
+        plan: payload.plan,
+        member_id: payload.member_id,
+        group_number: payload.group_number,
+        effective_date: payload.effective_date,
+        expiry_date: payload.expiry_date,
+        order: payload.order,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_insurance_coverage(&insurance_coverage);
+    Ok(insurance_coverage)
+}
+
+#[ic_cdk::query]
+fn get_insurance_coverage_by_id(id: InsuranceCoverageId) -> Result<InsuranceCoverage, Message> {
+    ensure_id_kind(id.0, EntityKind::InsuranceCoverage)?;
+    INSURANCE_COVERAGES_STORAGE.with(|storage| {
+        storage.borrow().get(&id).ok_or(Message::NotFound(
+            "Insurance coverage not found".to_string(),
+        ))
+    })
+}
+
+#[ic_cdk::update]
+fn update_insurance_coverage(
+    id: InsuranceCoverageId,
+    payload: InsuranceCoverageUpdatePayload,
+) -> Result<InsuranceCoverageUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::InsuranceCoverage)?;
+    let mut insurance_coverage = INSURANCE_COVERAGES_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound(
+            "Insurance coverage not found".to_string(),
+        ))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut insurance_coverage.payer,
+        payload.payer,
+        "payer",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut insurance_coverage.plan,
+        payload.plan,
+        "plan",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut insurance_coverage.member_id,
+        payload.member_id,
+        "member_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut insurance_coverage.group_number,
+        payload.group_number,
+        "group_number",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut insurance_coverage.effective_date,
+        payload.effective_date,
+        "effective_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut insurance_coverage.expiry_date,
+        payload.expiry_date,
+        "expiry_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut insurance_coverage.order,
+        payload.order,
+        "order",
+        &mut changed_fields,
+    );
+    validate_insurance_coverage(
+        &insurance_coverage.payer,
+        &insurance_coverage.plan,
+        &insurance_coverage.member_id,
+        &insurance_coverage.group_number,
+        &insurance_coverage.effective_date,
+        &insurance_coverage.expiry_date,
+    )?;
+
+    if !changed_fields.is_empty() {
+        insurance_coverage.updated_at = Some(current_time());
+        save_insurance_coverage(&insurance_coverage);
+    }
+    Ok(InsuranceCoverageUpdateResponse {
+        insurance_coverage,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_insurance_coverage(id: InsuranceCoverageId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::InsuranceCoverage)?;
+    if remove_insurance_coverage(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound(
+            "Insurance coverage not found".to_string(),
+        ))
+    }
+}
+
+// Function to get a patient's insurance coverages, primary first
+#[ic_cdk::query]
+fn get_insurance_coverages(patient_id: PatientId) -> Result<Vec<InsuranceCoverage>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(load_insurance_coverages(patient_id))
+}
+
+// Function to check a patient's coverage for a date (nanoseconds, like appointment times)
+#[ic_cdk::query]
+fn check_eligibility(patient_id: PatientId, date_time: u64) -> Result<Eligibility, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(check_eligibility_on(
+        patient_id,
+        date_from_timestamp(date_time),
+    ))
+}
+
+// Function to load a batch of ICD-10 codes; large tables are uploaded over several calls
+#[ic_cdk::update]
+fn upload_icd10_codes(codes: Vec<Icd10Code>) -> Result<u64, Message> {
+    ensure_controller()?;
+
+    // Check the whole batch before storing any of it
+    let mut normalized = Vec::with_capacity(codes.len());
+    for code in codes {
+        let key = normalize_icd10_code(&code.code)?;
+        if code.description.trim().is_empty() {
+            return Err(Message::InvalidPayload(format!(
+                "ICD-10 code {} has no description.",
+                key
+            )));
+        }
+        validate_length("description", &code.description, MAX_DESCRIPTION_LENGTH)?;
+        normalized.push(Icd10Code {
+            code: key,
+            description: code.description.trim().to_string(),
+        });
+    }
+
+    for code in normalized {
+        save_icd10_code(code);
+    }
+    Ok(ICD10_CODES.with(|codes| codes.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn get_icd10_code(code: String) -> Result<Icd10Code, Message> {
+    let code = normalize_icd10_code(&code)?;
+    ICD10_CODES
+        .with(|codes| codes.borrow().get(&icd10_key(&code)))
+        .ok_or(Message::NotFound(format!("ICD-10 code {} not found", code)))
+}
+
+// Function to find ICD-10 codes starting with a prefix, e.g. "J45" or "j45.9"
+#[ic_cdk::query]
+fn search_icd10_codes_by_prefix(prefix: String, limit: u32) -> Result<Vec<Icd10Code>, Message> {
+    let prefix = normalize_icd10_prefix(&prefix)?;
+    let start = icd10_key(&prefix);
+    Ok(ICD10_CODES.with(|codes| {
+        codes
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.as_slice().starts_with(prefix.as_bytes()))
+            .take(search_limit(limit))
+            .map(|(_, code)| code)
+            .collect()
+    }))
+}
+
+// Function to find ICD-10 codes whose description has words starting with every search word
+#[ic_cdk::query]
+fn search_icd10_codes_by_keyword(keywords: String, limit: u32) -> Result<Vec<Icd10Code>, Message> {
+    let words = description_words(&keywords);
+    let (first, rest) = words.split_first().ok_or(Message::InvalidPayload(
+        "Ensure 'keywords' has a word of at least 3 characters.".to_string(),
+    ))?;
+
+    let first_key = keyword_key(first);
+    let start = (first_key, Icd10CodeKey::default());
+    let mut keys: Vec<Icd10CodeKey> = ICD10_CODES_BY_KEYWORD.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|((keyword, _), _)| keyword.as_slice().starts_with(first_key.as_slice()))
+            .map(|((_, key), _)| key)
+            .collect()
+    });
+    keys.sort();
+    keys.dedup();
+
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| ICD10_CODES.with(|codes| codes.borrow().get(&key)))
+        .filter(|code| {
+            let code_words = description_words(&code.description);
+            rest.iter().all(|word| {
+                code_words
+                    .iter()
+                    .any(|code_word| code_word.starts_with(word))
+            })
+        })
+        .take(search_limit(limit))
+        .collect())
+}
+
+// Function to get the patient records coded with a diagnosis, or with any code under a prefix
+#[ic_cdk::query]
+fn get_records_with_diagnosis(code_prefix: String) -> Result<Vec<PatientRecord>, Message> {
+    let prefix = normalize_icd10_prefix(&code_prefix)?;
+    let start = (icd10_key(&prefix), PatientRecordId(0));
+    let mut ids: Vec<PatientRecordId> = PATIENT_RECORDS_BY_DIAGNOSIS.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|((code, _), _)| code.as_slice().starts_with(prefix.as_bytes()))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.sort();
+    ids.dedup();
+    Ok(ids
+        .into_iter()
+        .filter_map(|id| PATIENT_RECORDS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+#[ic_cdk::update]
+fn create_allergy(payload: AllergyPayload) -> Result<Allergy, Message> {
+    validate_allergy(&payload.substance, &payload.reaction)?;
+
+    // Validate the patient and recording doctor ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.recorded_by)?;
+
+    let id = AllergyId(next_id(EntityKind::Allergy));
+
+    let allergy = Allergy {
+        id,
+        patient_id: payload.patient_id,
+        substance: payload.substance,
+        reaction: payload.reaction,
+        severity: payload.severity,
+        status: payload.status,
+        recorded_by: payload.recorded_by,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_allergy(&allergy);
+    Ok(allergy)
+}
+
+#[ic_cdk::query]
+fn get_allergy_by_id(id: AllergyId) -> Result<Allergy, Message> {
+    ensure_id_kind(id.0, EntityKind::Allergy)?;
+    ALLERGIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Allergy not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_allergy(
+    id: AllergyId,
+    payload: AllergyUpdatePayload,
+) -> Result<AllergyUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Allergy)?;
+    let mut allergy = ALLERGIES_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Allergy not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut allergy.substance,
+        payload.substance,
+        "substance",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut allergy.reaction,
+        payload.reaction,
+        "reaction",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut allergy.severity,
+        payload.severity,
+        "severity",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut allergy.status,
+        payload.status,
+        "status",
+        &mut changed_fields,
+    );
+    validate_allergy(&allergy.substance, &allergy.reaction)?;
+
+    if !changed_fields.is_empty() {
+        allergy.updated_at = Some(current_time());
+        save_allergy(&allergy);
+    }
+    Ok(AllergyUpdateResponse {
+        allergy,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_allergy(id: AllergyId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::Allergy)?;
+    if remove_allergy(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Allergy not found".to_string()))
+    }
+}
+
+// Function to get all allergies of a patient, whatever their status
+#[ic_cdk::query]
+fn get_patient_allergies(patient_id: PatientId) -> Result<Vec<Allergy>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(load_allergies(patient_id))
+}
+
+#[ic_cdk::update]
+fn create_problem(payload: ProblemPayload) -> Result<Problem, Message> {
+    // Validate the patient, doctor and source record ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.recorded_by)?;
+    if let Some(record_id) = payload.source_record_id {
+        ensure_record_of_patient(record_id, payload.patient_id)?;
+    }
+
+    let resolved_date = match payload.status {
+        ProblemStatus::Resolved => payload
+            .resolved_date
+            .or_else(|| Some(current_date().to_string())),
+        _ => payload.resolved_date,
+    };
+    let mut problem = Problem {
+        id: ProblemId::default(),
+        patient_id: payload.patient_id,
+        condition: payload.condition,
+        code: payload
+            .code
+            .map(|code| known_icd10_code(&code))
+            .transpose()?,
+        onset_date: payload.onset_date,
+        status: payload.status,
+        severity: payload.severity,
+        resolved_date,
+        recorded_by: payload.recorded_by,
+        source_record_id: payload.source_record_id,
+        resolved_record_id: None,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_problem(&problem)?;
+    ensure_not_on_problem_list(&problem, &[])?;
+
+    problem.id = ProblemId(next_id(EntityKind::Problem));
+    save_problem(&problem);
+    Ok(problem)
+}
+
+#[ic_cdk::query]
+fn get_problem_by_id(id: ProblemId) -> Result<Problem, Message> {
+    ensure_id_kind(id.0, EntityKind::Problem)?;
+    PROBLEMS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Problem not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_problem(
+    id: ProblemId,
+    payload: ProblemUpdatePayload,
+) -> Result<ProblemUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Problem)?;
+    let mut problem = PROBLEMS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Problem not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut problem.condition,
+        payload.condition,
+        "condition",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut problem.code,
+        payload
+            .code
+            .map(|code| code.map(|code| known_icd10_code(&code)).transpose())
+            .transpose()?,
+        "code",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut problem.onset_date,
+        payload.onset_date,
+        "onset_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut problem.status,
+        payload.status,
+        "status",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut problem.severity,
+        payload.severity,
+        "severity",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut problem.resolved_date,
+        payload.resolved_date,
+        "resolved_date",
+        &mut changed_fields,
+    );
+
+    // A problem that becomes resolved is resolved today unless told otherwise, and one that
+    // is no longer resolved loses its resolution
+    if changed_fields.iter().any(|field| field == "status") {
+        match problem.status {
+            ProblemStatus::Resolved if problem.resolved_date.is_none() => {
+                problem.resolved_date = Some(current_date().to_string());
+                changed_fields.push("resolved_date".to_string());
+            }
+            ProblemStatus::Resolved => {}
+            _ => {
+                problem.resolved_record_id = None;
+                if !changed_fields.iter().any(|field| field == "resolved_date")
+                    && problem.resolved_date.take().is_some()
+                {
+                    changed_fields.push("resolved_date".to_string());
+                }
+            }
+        }
+    }
+    validate_problem(&problem)?;
+    if changed_fields
+        .iter()
+        .any(|field| field == "condition" || field == "code" || field == "status")
+    {
+        ensure_not_on_problem_list(&problem, &[])?;
+    }
+
+    if !changed_fields.is_empty() {
+        problem.updated_at = Some(current_time());
+        save_problem(&problem);
+    }
+    Ok(ProblemUpdateResponse {
+        problem,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_problem(id: ProblemId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::Problem)?;
+    if remove_problem(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Problem not found".to_string()))
+    }
+}
+
+// Function to get a patient's problem list as a consolidated summary
+#[ic_cdk::query]
+fn get_problem_list(patient_id: PatientId) -> Result<ProblemList, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    let mut problems = load_problems(patient_id);
+    // Latest onset first; problems without an onset date go last
+    problems.sort_by(|a, b| b.onset_date.cmp(&a.onset_date));
+
+    let mut list = ProblemList::default();
+    for problem in problems {
+        match problem.status {
+            ProblemStatus::Active => list.active.push(problem),
+            ProblemStatus::InRemission => list.in_remission.push(problem),
+            ProblemStatus::Resolved => list.resolved.push(problem),
+        }
+    }
+    Ok(list)
+}
+
+// Function to get the problems a patient record added or resolved
+#[ic_cdk::query]
+fn get_record_problems(record_id: PatientRecordId) -> Result<Vec<Problem>, Message> {
+    get_patient_record_by_id(record_id)?;
+    Ok(problem_ids_for_record(record_id)
+        .into_iter()
+        .filter_map(|id| PROBLEMS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+#[ic_cdk::update]
+fn create_family_history(payload: FamilyHistoryPayload) -> Result<FamilyHistory, Message> {
+    ensure_patient_exists(payload.patient_id)?;
+
+    let mut entry = FamilyHistory {
+        id: FamilyHistoryId::default(),
+        patient_id: payload.patient_id,
+        relative: payload.relative,
+        condition: payload.condition,
+        code: payload
+            .code
+            .map(|code| known_icd10_code(&code))
+            .transpose()?,
+        age_at_onset: payload.age_at_onset,
+        notes: payload.notes,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_family_history(&entry)?;
+
+    entry.id = FamilyHistoryId(next_id(EntityKind::FamilyHistory));
+    save_family_history(&entry);
+    Ok(entry)
+}
+
+#[ic_cdk::query]
+fn get_family_history_by_id(id: FamilyHistoryId) -> Result<FamilyHistory, Message> {
+    ensure_id_kind(id.0, EntityKind::FamilyHistory)?;
+    FAMILY_HISTORY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Family history not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_family_history(
+    id: FamilyHistoryId,
+    payload: FamilyHistoryUpdatePayload,
+) -> Result<FamilyHistoryUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::FamilyHistory)?;
+    let mut entry = FAMILY_HISTORY_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Family history not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut entry.relative,
+        payload.relative,
+        "relative",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut entry.condition,
+        payload.condition,
+        "condition",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut entry.code,
+        payload
+            .code
+            .map(|code| code.map(|code| known_icd10_code(&code)).transpose())
+            .transpose()?,
+        "code",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut entry.age_at_onset,
+        payload.age_at_onset,
+        "age_at_onset",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut entry.notes,
+        payload.notes,
+        "notes",
+        &mut changed_fields,
+    );
+    validate_family_history(&entry)?;
+
+    if !changed_fields.is_empty() {
+        entry.updated_at = Some(current_time());
+        save_family_history(&entry);
+    }
+    Ok(FamilyHistoryUpdateResponse {
+        family_history: entry,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_family_history(id: FamilyHistoryId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::FamilyHistory)?;
+    if remove_family_history(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Family history not found".to_string()))
+    }
+}
+
+// Function to get a patient's family history, grouped by relative
+#[ic_cdk::query]
+fn get_patient_family_history(patient_id: PatientId) -> Result<Vec<FamilyHistory>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(load_family_history_by_relative(patient_id))
+}
+
+#[ic_cdk::update]
+fn create_social_history(payload: SocialHistoryPayload) -> Result<SocialHistory, Message> {
+    ensure_patient_exists(payload.patient_id)?;
+    if SOCIAL_HISTORY_STORAGE.with(|storage| storage.borrow().contains_key(&payload.patient_id)) {
+        return Err(Message::Error(
+            "The patient already has a social history; update it instead.".to_string(),
+        ));
+    }
+
+    let social_history = SocialHistory {
+        patient_id: payload.patient_id,
+        smoking: payload.smoking,
+        pack_years: payload.pack_years,
+        alcohol: payload.alcohol,
+        drinks_per_week: payload.drinks_per_week,
+        occupation: payload.occupation,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_social_history(&social_history)?;
+
+    save_social_history(&social_history);
+    Ok(social_history)
+}
+
+#[ic_cdk::query]
+fn get_social_history(patient_id: PatientId) -> Result<SocialHistory, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    SOCIAL_HISTORY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&patient_id)
+            .ok_or(Message::NotFound("Social history not found".to_string()))
+    })
+}
+
+// Function to update a patient's social history. A smoking status or alcohol use that no
+// longer allows pack years or drinks per week clears them.
+#[ic_cdk::update]
+fn update_social_history(
+    patient_id: PatientId,
+    payload: SocialHistoryUpdatePayload,
+) -> Result<SocialHistoryUpdateResponse, Message> {
+    ensure_patient_exists(patient_id)?;
+    let mut social_history = SOCIAL_HISTORY_STORAGE
+        .with(|storage| storage.borrow().get(&patient_id))
+        .ok_or(Message::NotFound("Social history not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut social_history.smoking,
+        payload.smoking,
+        "smoking",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut social_history.pack_years,
+        payload.pack_years,
+        "pack_years",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut social_history.alcohol,
+        payload.alcohol,
+        "alcohol",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut social_history.drinks_per_week,
+        payload.drinks_per_week,
+        "drinks_per_week",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut social_history.occupation,
+        payload.occupation,
+        "occupation",
+        &mut changed_fields,
+    );
+
+    if !matches!(
+        social_history.smoking,
+        SmokingStatus::Former | SmokingStatus::Current
+    ) && !changed_fields.iter().any(|field| field == "pack_years")
+        && social_history.pack_years.take().is_some()
+    {
+        changed_fields.push("pack_years".to_string());
+    }
+    if matches!(
+        social_history.alcohol,
+        AlcoholUse::NonDrinker | AlcoholUse::Unknown
+    ) && !changed_fields
+        .iter()
+        .any(|field| field == "drinks_per_week")
+        && social_history.drinks_per_week.take().is_some()
+    {
+        changed_fields.push("drinks_per_week".to_string());
+    }
+    validate_social_history(&social_history)?;
+
+    if !changed_fields.is_empty() {
+        social_history.updated_at = Some(current_time());
+        save_social_history(&social_history);
+    }
+    Ok(SocialHistoryUpdateResponse {
+        social_history,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_social_history(patient_id: PatientId) -> Result<(), Message> {
+    ensure_patient_exists(patient_id)?;
+    if remove_social_history(patient_id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Social history not found".to_string()))
+    }
+}
+
+// Function to get the summary shown when a patient's chart is opened
+#[ic_cdk::query]
+fn get_patient_summary(patient_id: PatientId) -> Result<PatientSummary, Message> {
+    // A merged patient resolves to the patient it was merged into
+    let patient = get_patient_by_id(patient_id)?;
+    let patient_id = patient.id;
+    let today = current_date();
+    let now = current_time();
+
+    let mut active_problems: Vec<Problem> = load_problems(patient_id)
+        .into_iter()
+        .filter(|problem| problem.status == ProblemStatus::Active)
+        .collect();
+    active_problems.sort_by(|a, b| b.onset_date.cmp(&a.onset_date));
+    let mut upcoming_appointments: Vec<Appointment> =
+        load_appointments(appointment_ids_for_patient(patient_id))
+            .into_iter()
+            .filter(|appointment| appointment.date_time >= now)
+            .collect();
+    upcoming_appointments.sort_by_key(|appointment| appointment.date_time);
+
+    Ok(PatientSummary {
+        patient,
+        active_problems,
+        active_allergies: load_allergies(patient_id)
+            .into_iter()
+            .filter(|allergy| allergy.status == AllergyStatus::Active)
+            .collect(),
+        active_medications: medication_ids_for_patient(patient_id)
+            .into_iter()
+            .filter_map(|id| MEDICATIONS_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .filter(|medication| is_active_medication(medication, today))
+            .collect(),
+        latest_observations: get_latest_observations(patient_id)?,
+        family_history: load_family_history_by_relative(patient_id),
+        social_history: SOCIAL_HISTORY_STORAGE.with(|storage| storage.borrow().get(&patient_id)),
+        active_care_plans: load_care_plans(patient_id)
+            .into_iter()
+            .filter(|plan| plan.status == CarePlanStatus::Active)
+            .collect(),
+        upcoming_appointments,
+    })
+}
+
+// Function to get a patient's history as dated events, newest first. Upcoming appointments
+// come first; pass the returned `next_before` as `before` to page back. Vital signs are left
+// to the observation queries, and documents are listed only for callers who may read them.
+// Pages are read backwards from the timeline index, so a page costs its own entries.
+#[ic_cdk::query]
+fn get_patient_timeline(
+    patient_id: PatientId,
+    before: Option<TimelineCursor>,
+    limit: u32,
+) -> Result<PatientTimeline, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    let limit = search_limit(limit);
+    let mut bound = match before {
+        Some(before) => (
+            patient_id,
+            (
+                before.at,
+                (before.event.code(), before.entity_id.unwrap_or(0)),
+            ),
+        ),
+        None => (patient_id, (u64::MAX, (u8::MAX, u64::MAX))),
+    };
+    let mut entries = Vec::new();
+    // One entry past the limit tells whether there is another page
+    while entries.len() <= limit {
+        let key = TIMELINE_BY_PATIENT.with(|index| {
+            index
+                .borrow()
+                .iter_upper_bound(&bound)
+                .next()
+                .map(|(key, _)| key)
+                .filter(|(key_patient, _)| *key_patient == patient_id)
+        });
+        match key {
+            Some(key) => {
+                bound = key;
+                entries.extend(timeline_entry(key));
+            }
+            None => break,
+        }
+    }
+
+    let next_before = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| TimelineCursor {
+            at: entry.at,
+            event: entry.event,
+            entity_id: entry.entity_id,
+        })
+    } else {
+        None
+    };
+    Ok(PatientTimeline {
+        entries,
+        next_before,
+    })
+}
+
+// Function to build the timeline entry for an index key. Medications that have not ended
+// yet and documents the caller may not read give None.
+fn timeline_entry(key: TimelineKey) -> Option<TimelineEntry> {
+    let (patient_id, (at, (code, entity_id))) = key;
+    let event = TimelineEvent::from_code(code)?;
+    let summary = match event {
+        TimelineEvent::Appointment => {
+            APPOINTMENTS_STORAGE
+                .with(|storage| storage.borrow().get(&AppointmentId(entity_id)))?
+                .description
+        }
+        TimelineEvent::PatientRecord => {
+            PATIENT_RECORDS_STORAGE
+                .with(|storage| storage.borrow().get(&PatientRecordId(entity_id)))?
+                .diagnosis
+        }
+        TimelineEvent::MedicationStarted | TimelineEvent::MedicationEnded => {
+            if event == TimelineEvent::MedicationEnded && date_from_timestamp(at) > current_date() {
+                return None;
+            }
+            MEDICATIONS_STORAGE
+                .with(|storage| storage.borrow().get(&MedicationId(entity_id)))?
+                .name
+        }
+        TimelineEvent::ProblemOnset | TimelineEvent::ProblemResolved => {
+            PROBLEMS_STORAGE
+                .with(|storage| storage.borrow().get(&ProblemId(entity_id)))?
+                .condition
+        }
+        TimelineEvent::AllergyRecorded => {
+            ALLERGIES_STORAGE
+                .with(|storage| storage.borrow().get(&AllergyId(entity_id)))?
+                .substance
+        }
+        TimelineEvent::Immunization => {
+            let immunization = IMMUNIZATIONS_STORAGE
+                .with(|storage| storage.borrow().get(&ImmunizationId(entity_id)))?;
+            format!("{} dose {}", immunization.vaccine, immunization.dose_number)
+        }
+        TimelineEvent::LabOrdered => LAB_ORDERS_STORAGE
+            .with(|storage| storage.borrow().get(&LabOrderId(entity_id)))?
+            .tests
+            .join(", "),
+        TimelineEvent::ClinicalNoteSigned => {
+            CLINICAL_NOTES_STORAGE
+                .with(|storage| storage.borrow().get(&ClinicalNoteId(entity_id)))?
+                .assessment
+        }
+        TimelineEvent::DocumentAdded => {
+            let document =
+                DOCUMENTS_STORAGE.with(|storage| storage.borrow().get(&DocumentId(entity_id)))?;
+            ensure_document_access(&document).ok()?;
+            document.title
+        }
+        TimelineEvent::FamilyHistoryRecorded => {
+            FAMILY_HISTORY_STORAGE
+                .with(|storage| storage.borrow().get(&FamilyHistoryId(entity_id)))?
+                .condition
+        }
+        TimelineEvent::SocialHistoryUpdated => {
+            SOCIAL_HISTORY_STORAGE.with(|storage| storage.borrow().get(&patient_id))?;
+            "Social history".to_string()
+        }
+        TimelineEvent::CarePlanStarted => {
+            CARE_PLANS_STORAGE
+                .with(|storage| storage.borrow().get(&CarePlanId(entity_id)))?
+                .title
+        }
+    };
+    Some(TimelineEntry {
+        at,
+        event,
+        entity_id: (event != TimelineEvent::SocialHistoryUpdated).then_some(entity_id),
+        summary: truncate_text(&summary, MAX_TIMELINE_SUMMARY_LENGTH),
+    })
+}
+
+#[ic_cdk::update]
+fn create_care_plan(payload: CarePlanPayload) -> Result<CarePlan, Message> {
+    // Validate the patient and authoring doctor ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.doctor_id)?;
+
+    let mut plan = CarePlan {
+        id: CarePlanId::default(),
+        patient_id: payload.patient_id,
+        doctor_id: payload.doctor_id,
+        title: payload.title,
+        description: payload.description,
+        status: CarePlanStatus::Active,
+        start_date: payload
+            .start_date
+            .unwrap_or_else(|| current_date().to_string()),
+        end_date: payload.end_date,
+        goals: standardize_goals(payload.goals)?,
+        interventions: payload.interventions,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_care_plan(&plan)?;
+
+    plan.id = CarePlanId(next_id(EntityKind::CarePlan));
+    save_care_plan(&plan);
+    Ok(plan)
+}
+
+#[ic_cdk::query]
+fn get_care_plan_by_id(id: CarePlanId) -> Result<CarePlan, Message> {
+    ensure_id_kind(id.0, EntityKind::CarePlan)?;
+    CARE_PLANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Care plan not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_care_plan(
+    id: CarePlanId,
+    payload: CarePlanUpdatePayload,
+) -> Result<CarePlanUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::CarePlan)?;
+    let mut plan = CARE_PLANS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Care plan not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(&mut plan.title, payload.title, "title", &mut changed_fields);
+    apply_field(
+        &mut plan.description,
+        payload.description,
+        "description",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut plan.status,
+        payload.status,
+        "status",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut plan.start_date,
+        payload.start_date,
+        "start_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut plan.end_date,
+        payload.end_date,
+        "end_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut plan.goals,
+        payload.goals.map(standardize_goals).transpose()?,
+        "goals",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut plan.interventions,
+        payload.interventions,
+        "interventions",
+        &mut changed_fields,
+    );
+
+    // A plan that is completed or cancelled ends today unless told otherwise
+    if changed_fields.iter().any(|field| field == "status")
+        && matches!(
+            plan.status,
+            CarePlanStatus::Completed | CarePlanStatus::Cancelled
+        )
+        && plan.end_date.is_none()
+    {
+        plan.end_date = Some(current_date().to_string());
+        changed_fields.push("end_date".to_string());
+    }
+    if plan.end_date.is_none()
+        && matches!(
+            plan.status,
+            CarePlanStatus::Completed | CarePlanStatus::Cancelled
+        )
+    {
+        return Err(Message::InvalidPayload(
+            "A completed or cancelled care plan keeps its 'end_date'.".to_string(),
+        ));
+    }
+    validate_care_plan(&plan)?;
+
+    if !changed_fields.is_empty() {
+        plan.updated_at = Some(current_time());
+        save_care_plan(&plan);
+    }
+    Ok(CarePlanUpdateResponse {
+        care_plan: plan,
+        changed_fields,
+    })
+}
+
+// Function to delete a care plan together with its tasks
+#[ic_cdk::update]
+fn delete_care_plan(id: CarePlanId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::CarePlan)?;
+    if remove_care_plan(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Care plan not found".to_string()))
+    }
+}
+
+// Function to get a patient's care plans, latest start first
+#[ic_cdk::query]
+fn get_patient_care_plans(patient_id: PatientId) -> Result<Vec<CarePlan>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    let mut plans = load_care_plans(patient_id);
+    plans.sort_by(|a, b| b.start_date.cmp(&a.start_date));
+    Ok(plans)
+}
+
+// Function to compare a care plan's goals against the patient's latest observations
+#[ic_cdk::query]
+fn get_care_plan_progress(id: CarePlanId) -> Result<CarePlanProgress, Message> {
+    let plan = get_care_plan_by_id(id)?;
+    let today = current_date();
+    let plan_start = parse_date(&plan.start_date)
+        .and_then(timestamp_from_date)
+        .unwrap_or(plan.created_at);
+
+    let goals = plan
+        .goals
+        .iter()
+        .map(|goal| goal_progress(goal, plan.patient_id, plan_start, today))
+        .collect();
+    let (mut open_tasks, mut overdue_tasks, mut done_tasks) = (0, 0, 0);
+    for task in load_care_tasks(care_task_ids_for_plan(id)) {
+        match task.status {
+            CareTaskStatus::Open => {
+                open_tasks += 1;
+                if matches!(parse_date(&task.due_date), Some(due) if due < today) {
+                    overdue_tasks += 1;
+                }
+            }
+            CareTaskStatus::Done => done_tasks += 1,
+            CareTaskStatus::Cancelled => {}
+        }
+    }
+    Ok(CarePlanProgress {
+        care_plan: plan,
+        goals,
+        open_tasks,
+        overdue_tasks,
+        done_tasks,
+    })
+}
+
+#[ic_cdk::update]
+fn create_care_task(payload: CareTaskPayload) -> Result<CareTask, Message> {
+    // Validate the care plan and assignee ids
+    let plan = get_care_plan_by_id(payload.care_plan_id)?;
+    if matches!(
+        plan.status,
+        CarePlanStatus::Completed | CarePlanStatus::Cancelled
+    ) {
+        return Err(Message::Error(
+            "Tasks can only be added to an active or on-hold care plan.".to_string(),
+        ));
+    }
+    ensure_doctor_exists(payload.assigned_to)?;
+
+    let mut task = CareTask {
+        id: CareTaskId::default(),
+        care_plan_id: payload.care_plan_id,
+        description: payload.description,
+        assigned_to: payload.assigned_to,
+        due_date: payload.due_date,
+        status: CareTaskStatus::Open,
+        completed_at: None,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_care_task(&task)?;
+
+    task.id = CareTaskId(next_id(EntityKind::CareTask));
+    save_care_task(&task);
+    Ok(task)
+}
+
+#[ic_cdk::query]
+fn get_care_task_by_id(id: CareTaskId) -> Result<CareTask, Message> {
+    ensure_id_kind(id.0, EntityKind::CareTask)?;
+    CARE_TASKS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Care task not found".to_string()))
+    })
+}
+
+// Function to update a care task. Marking it done records when; reopening or cancelling
+// it clears that.
+#[ic_cdk::update]
+fn update_care_task(
+    id: CareTaskId,
+    payload: CareTaskUpdatePayload,
+) -> Result<CareTaskUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::CareTask)?;
+    let mut task = CARE_TASKS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Care task not found".to_string()))?;
+    if let Some(assigned_to) = payload.assigned_to {
+        ensure_doctor_exists(assigned_to)?;
+    }
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut task.description,
+        payload.description,
+        "description",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut task.assigned_to,
+        payload.assigned_to,
+        "assigned_to",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut task.due_date,
+        payload.due_date,
+        "due_date",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut task.status,
+        payload.status,
+        "status",
+        &mut changed_fields,
+    );
+    if changed_fields.iter().any(|field| field == "status") {
+        task.completed_at = (task.status == CareTaskStatus::Done).then(current_time);
+    }
+    validate_care_task(&task)?;
+
+    if !changed_fields.is_empty() {
+        task.updated_at = Some(current_time());
+        save_care_task(&task);
+    }
+    Ok(CareTaskUpdateResponse {
+        care_task: task,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_care_task(id: CareTaskId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::CareTask)?;
+    if remove_care_task(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Care task not found".to_string()))
+    }
+}
+
+// Function to get a care plan's tasks, earliest due first
+#[ic_cdk::query]
+fn get_care_plan_tasks(care_plan_id: CarePlanId) -> Result<Vec<CareTask>, Message> {
+    get_care_plan_by_id(care_plan_id)?;
+    Ok(load_care_tasks(care_task_ids_for_plan(care_plan_id)))
+}
+
+// Function to get the open tasks assigned to a care-team member, earliest due first
+#[ic_cdk::query]
+fn get_assigned_care_tasks(doctor_id: DoctorId) -> Result<Vec<CareTask>, Message> {
+    ensure_doctor_exists(doctor_id)?;
+    Ok(load_care_tasks(care_task_ids_for_doctor(doctor_id))
+        .into_iter()
+        .filter(|task| task.status == CareTaskStatus::Open)
+        .collect())
+}
+
+// Function to load a batch of drug interaction pairs; uploading a pair again replaces it
+#[ic_cdk::update]
+fn upload_drug_interactions(interactions: Vec<DrugInteraction>) -> Result<u64, Message> {
+    ensure_controller()?;
+
+    // Check the whole batch before storing any of it
+    let mut normalized = Vec::with_capacity(interactions.len());
+    for interaction in interactions {
+        let drug_a = normalize_words(&interaction.drug_a);
+        let drug_b = normalize_words(&interaction.drug_b);
+        if drug_a.is_empty() || drug_b.is_empty() || drug_a == drug_b {
+            return Err(Message::InvalidPayload(
+                "Ensure each interaction names two different drugs.".to_string(),
+            ));
+        }
+        validate_length("drug_a", &drug_a, MAX_DRUG_NAME_LENGTH)?;
+        validate_length("drug_b", &drug_b, MAX_DRUG_NAME_LENGTH)?;
+        validate_length(
+            "description",
+            &interaction.description,
+            MAX_DESCRIPTION_LENGTH,
+        )?;
+        normalized.push(DrugInteraction {
+            drug_a,
+            drug_b,
+            ..interaction
+        });
+    }
+
+    DRUG_INTERACTIONS.with(|map| {
+        let mut map = map.borrow_mut();
+        for interaction in normalized {
+            let (a, b) = (drug_key(&interaction.drug_a), drug_key(&interaction.drug_b));
+            map.insert((a, b), interaction.clone());
+            map.insert((b, a), interaction);
+        }
+        // Each pair is stored twice
+        Ok(map.len() / 2)
+    })
+}
+
+// Function to check a candidate medication name against the patient's medications, as if it
+// were started today with no end date
+#[ic_cdk::query]
+fn check_interactions(
+    patient_id: PatientId,
+    candidate: String,
+) -> Result<Vec<InteractionWarning>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    let candidate = Medication {
+        name: candidate,
+        ..Default::default()
+    };
+    Ok(find_interactions(patient_id, &candidate))
+}
+
+#[ic_cdk::update]
+fn create_observation(payload: ObservationPayload) -> Result<Observation, Message> {
+    // Validate the patient and appointment ids
+    ensure_patient_exists(payload.patient_id)?;
+    if let Some(appointment_id) = payload.appointment_id {
+        ensure_appointment_of_patient(appointment_id, payload.patient_id)?;
+    }
+
+    let value = standardize_observation(payload.observation_type, payload.value, payload.unit)?;
+    validate_observation(&payload.observed_at, &payload.note)?;
+
+    let id = ObservationId(next_id(EntityKind::Observation));
+
+    let observation = Observation {
+        id,
+        patient_id: payload.patient_id,
+        appointment_id: payload.appointment_id,
+        observation_type: payload.observation_type,
+        value,
+        unit: payload.observation_type.standard_unit(),
+        observed_at: payload.observed_at,
+        note: payload.note,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_observation(&observation);
+    Ok(observation)
+}
+
+#[ic_cdk::query]
+fn get_observation_by_id(id: ObservationId) -> Result<Observation, Message> {
+    ensure_id_kind(id.0, EntityKind::Observation)?;
+    OBSERVATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Observation not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_observation(
+    id: ObservationId,
+    payload: ObservationUpdatePayload,
+) -> Result<ObservationUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Observation)?;
+    let mut observation = OBSERVATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Observation not found".to_string()))?;
+
+    // Validate the appointment id if it is being changed
+    if let Some(Some(appointment_id)) = payload.appointment_id {
+        ensure_appointment_of_patient(appointment_id, observation.patient_id)?;
+    }
+    let value = payload
+        .measurement
+        .map(|(value, unit)| standardize_observation(observation.observation_type, value, unit))
+        .transpose()?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut observation.appointment_id,
+        payload.appointment_id,
+        "appointment_id",
+        &mut changed_fields,
+    );
+    apply_field(&mut observation.value, value, "value", &mut changed_fields);
+    apply_field(
+        &mut observation.observed_at,
+        payload.observed_at,
+        "observed_at",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut observation.note,
+        payload.note,
+        "note",
+        &mut changed_fields,
+    );
+    validate_observation(&observation.observed_at, &observation.note)?;
+
+    if !changed_fields.is_empty() {
+        observation.updated_at = Some(current_time());
+        save_observation(&observation);
+    }
+    Ok(ObservationUpdateResponse {
+        observation,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_observation(id: ObservationId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::Observation)?;
+    if remove_observation(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Observation not found".to_string()))
+    }
+}
+
+// Function to get a patient's observations of one type between two times, oldest first
+#[ic_cdk::query]
+fn get_observations(
+    patient_id: PatientId,
+    observation_type: ObservationType,
+    from: u64,
+    to: u64,
+) -> Result<Vec<Observation>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(
+        observation_ids_between(patient_id, observation_type, from, to)
+            .into_iter()
+            .take(MAX_OBSERVATION_RESULTS)
+            .filter_map(|id| OBSERVATIONS_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .collect(),
+    )
+}
+
+// Function to get a patient's most recent observation of each type
+#[ic_cdk::query]
+fn get_latest_observations(patient_id: PatientId) -> Result<Vec<Observation>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    let types = [
+        ObservationType::BloodPressure,
+        ObservationType::HeartRate,
+        ObservationType::Temperature,
+        ObservationType::OxygenSaturation,
+        ObservationType::Weight,
+        ObservationType::Height,
+    ];
+    Ok(types
+        .into_iter()
+        .filter_map(|observation_type| latest_observation(patient_id, observation_type))
+        .collect())
+}
+
+// Function to get a series for charting: the range is split into equal buckets and each
+// non-empty bucket is reduced to the min, max and mean of its observations
+#[ic_cdk::query]
+fn get_observation_series(
+    patient_id: PatientId,
+    observation_type: ObservationType,
+    from: u64,
+    to: u64,
+    buckets: u32,
+) -> Result<Vec<SeriesPoint>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    if from >= to || buckets == 0 || buckets > MAX_SERIES_POINTS {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure 'from' is before 'to' and 'buckets' is between 1 and {}.",
+            MAX_SERIES_POINTS
+        )));
+    }
+
+    let observations = observation_ids_between(patient_id, observation_type, from, to)
+        .into_iter()
+        .filter_map(|id| OBSERVATIONS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .map(|observation| (observation.observed_at, observation.value));
+    Ok(downsample(observations, from, to, buckets))
+}
+
+// Function to summarize time-ordered values in `buckets` equal slices of [from, to]. Slices
+// are rounded up so there are never more than `buckets`, and the last one also takes `to`.
+fn downsample(
+    observations: impl IntoIterator<Item = (u64, ObservationValue)>,
+    from: u64,
+    to: u64,
+    buckets: u32,
+) -> Vec<SeriesPoint> {
+    let width = (to - from).div_ceil(buckets as u64);
+    let last_start = from.saturating_add((buckets as u64 - 1).saturating_mul(width));
+    let mut points: Vec<SeriesPoint> = Vec::new();
+    let mut values: Vec<(f64, Option<f64>)> = Vec::new();
+    let mut bucket_start = from;
+    for (observed_at, value) in observations {
+        // Observations come in time order, so a bucket is complete once one falls past it
+        if observed_at >= bucket_start.saturating_add(width) && bucket_start < last_start {
+            if !values.is_empty() {
+                points.push(series_point(bucket_start, width, to, &values));
+                values.clear();
+            }
+            let skipped = (observed_at - bucket_start) / width;
+            bucket_start = (bucket_start + skipped * width).min(last_start);
+        }
+        values.push(match value {
+            ObservationValue::Quantity(value) => (value, None),
+            ObservationValue::BloodPressure {
+                systolic,
+                diastolic,
+            } => (systolic, Some(diastolic)),
+        });
+    }
+    if !values.is_empty() {
+        points.push(series_point(bucket_start, width, to, &values));
+    }
+    points
+}
+
+#[ic_cdk::update]
+fn create_lab_order(payload: LabOrderPayload) -> Result<LabOrder, Message> {
+    validate_lab_tests(&payload.tests)?;
+
+    // Validate the patient and ordering doctor ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.doctor_id)?;
+
+    let id = LabOrderId(next_id(EntityKind::LabOrder));
+
+    let order = LabOrder {
+        id,
+        patient_id: payload.patient_id,
+        doctor_id: payload.doctor_id,
+        tests: payload.tests,
+        status: LabOrderStatus::Ordered,
+        collected_at: None,
+        resulted_at: None,
+        reviewed_at: None,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_lab_order(&order);
+    Ok(order)
+}
+
+#[ic_cdk::query]
+fn get_lab_order_by_id(id: LabOrderId) -> Result<LabOrderWithResults, Message> {
+    let order = load_lab_order(id)?;
+    Ok(LabOrderWithResults {
+        results: load_lab_results(id),
+        order,
+    })
+}
+
+// Function to record that the specimen for an order was collected
+#[ic_cdk::update]
+fn collect_lab_order(id: LabOrderId, collected_at: u64) -> Result<LabOrder, Message> {
+    let mut order = load_lab_order(id)?;
+    if order.status != LabOrderStatus::Ordered {
+        return Err(Message::Error(
+            "Only an ordered lab order can be collected.".to_string(),
+        ));
+    }
+    if collected_at < order.created_at || collected_at > current_time() {
+        return Err(Message::InvalidPayload(
+            "'collected_at' must be between the order time and now.".to_string(),
+        ));
+    }
+
+    order.status = LabOrderStatus::Collected;
+    order.collected_at = Some(collected_at);
+    order.updated_at = Some(current_time());
+    save_lab_order(&order);
+    Ok(order)
+}
+
+// Function to mark the results of an order as reviewed by the ordering doctor
+#[ic_cdk::update]
+fn review_lab_order(id: LabOrderId) -> Result<LabOrder, Message> {
+    let mut order = load_lab_order(id)?;
+    if order.status != LabOrderStatus::Resulted {
+        return Err(Message::Error(
+            "Only a resulted lab order can be reviewed.".to_string(),
+        ));
+    }
+
+    let now = current_time();
+    order.status = LabOrderStatus::Reviewed;
+    order.reviewed_at = Some(now);
+    order.updated_at = Some(now);
+    save_lab_order(&order);
+    Ok(order)
+}
+
+#[ic_cdk::update]
+fn delete_lab_order(id: LabOrderId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::LabOrder)?;
+    if remove_lab_order(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Lab order not found".to_string()))
+    }
+}
+
+#[ic_cdk::query]
+fn get_patient_lab_orders(patient_id: PatientId) -> Result<Vec<LabOrder>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(lab_order_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| LAB_ORDERS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+// Function to get a doctor's resulted orders that are awaiting review, orders with a
+// critical result first and otherwise the longest waiting first
+#[ic_cdk::query]
+fn get_results_awaiting_review(doctor_id: DoctorId) -> Result<Vec<LabOrderWithResults>, Message> {
+    ensure_doctor_exists(doctor_id)?;
+    let mut awaiting: Vec<LabOrderWithResults> = LAB_ORDERS_AWAITING_REVIEW
+        .with(|index| {
+            index
+                .borrow()
+                .range((doctor_id, LabOrderId(0))..=(doctor_id, LabOrderId(u64::MAX)))
+                .map(|((_, id), _)| id)
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .filter_map(|id| LAB_ORDERS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .map(|order| LabOrderWithResults {
+            results: load_lab_results(order.id),
+            order,
+        })
+        .collect();
+    awaiting.sort_by_key(|entry| {
+        let critical = entry
+            .results
+            .iter()
+            .any(|result| matches!(result.flag, Some(flag) if flag.is_critical()));
+        (!critical, entry.order.resulted_at)
+    });
+    Ok(awaiting)
+}
+
+// Function to add a result to a collected order; the order becomes resulted
+#[ic_cdk::update]
+fn create_lab_result(payload: LabResultPayload) -> Result<LabResult, Message> {
+    let mut order = load_lab_order(payload.order_id)?;
+    if order.status == LabOrderStatus::Ordered {
+        return Err(Message::Error(
+            "Results can only be added once the specimen is collected.".to_string(),
+        ));
+    }
+    validate_lab_result(
+        &order,
+        &payload.analyte,
+        payload.value,
+        &payload.unit,
+        &payload.reference_range,
+        payload.performed_at,
+        &payload.comment,
+    )?;
+
+    let id = LabResultId(next_id(EntityKind::LabResult));
+
+    let result = LabResult {
+        id,
+        order_id: payload.order_id,
+        analyte: payload.analyte,
+        value: payload.value,
+        unit: payload.unit,
+        flag: payload
+            .reference_range
+            .as_ref()
+            .map(|range| abnormal_flag(payload.value, range)),
+        reference_range: payload.reference_range,
+        performed_at: payload.performed_at,
+        comment: payload.comment,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_lab_result(&result);
+    mark_lab_order_resulted(&mut order);
+    Ok(result)
+}
+
+#[ic_cdk::query]
+fn get_lab_result_by_id(id: LabResultId) -> Result<LabResult, Message> {
+    ensure_id_kind(id.0, EntityKind::LabResult)?;
+    LAB_RESULTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Lab result not found".to_string()))
+    })
+}
+
+// Function to correct a result; the flag is recomputed and the order needs review again
+#[ic_cdk::update]
+fn update_lab_result(
+    id: LabResultId,
+    payload: LabResultUpdatePayload,
+) -> Result<LabResultUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::LabResult)?;
+    let mut result = LAB_RESULTS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Lab result not found".to_string()))?;
+    let mut order = load_lab_order(result.order_id)?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut result.analyte,
+        payload.analyte,
+        "analyte",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut result.value,
+        payload.value,
+        "value",
+        &mut changed_fields,
+    );
+    apply_field(&mut result.unit, payload.unit, "unit", &mut changed_fields);
+    apply_field(
+        &mut result.reference_range,
+        payload.reference_range,
+        "reference_range",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut result.performed_at,
+        payload.performed_at,
+        "performed_at",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut result.comment,
+        payload.comment,
+        "comment",
+        &mut changed_fields,
+    );
+    validate_lab_result(
+        &order,
+        &result.analyte,
+        result.value,
+        &result.unit,
+        &result.reference_range,
+        result.performed_at,
+        &result.comment,
+    )?;
+
+    if !changed_fields.is_empty() {
+        result.flag = result
+            .reference_range
+            .as_ref()
+            .map(|range| abnormal_flag(result.value, range));
+        result.updated_at = Some(current_time());
+        save_lab_result(&result);
+        mark_lab_order_resulted(&mut order);
+    }
+    Ok(LabResultUpdateResponse {
+        result,
+        changed_fields,
+    })
+}
+
+// Function to delete a result; an order left without results goes back to collected
+#[ic_cdk::update]
+fn delete_lab_result(id: LabResultId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::LabResult)?;
+    let result =
+        remove_lab_result(id).ok_or(Message::NotFound("Lab result not found".to_string()))?;
+
+    if let Some(mut order) =
+        LAB_ORDERS_STORAGE.with(|storage| storage.borrow().get(&result.order_id))
+    {
+        if lab_result_ids_for_order(order.id).is_empty() {
+            order.status = LabOrderStatus::Collected;
+            order.resulted_at = None;
+            order.reviewed_at = None;
+            order.updated_at = Some(current_time());
+            save_lab_order(&order);
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn create_immunization(payload: ImmunizationPayload) -> Result<Immunization, Message> {
+    // Validate the patient and administering doctor ids
+    ensure_patient_exists(payload.patient_id)?;
+    ensure_doctor_exists(payload.administered_by)?;
+
+    let id = ImmunizationId(next_id(EntityKind::Immunization));
+
+    let immunization = Immunization {
+        id,
+        patient_id: payload.patient_id,
+        vaccine: payload.vaccine,
+        dose_number: payload.dose_number,
+        lot_number: payload.lot_number,
+        site: payload.site,
+        administered_by: payload.administered_by,
+        administered_on: payload.administered_on,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_immunization(&immunization)?;
+    save_immunization(&immunization);
+    Ok(immunization)
+}
+
+#[ic_cdk::query]
+fn get_immunization_by_id(id: ImmunizationId) -> Result<Immunization, Message> {
+    ensure_id_kind(id.0, EntityKind::Immunization)?;
+    IMMUNIZATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Immunization not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_immunization(
+    id: ImmunizationId,
+    payload: ImmunizationUpdatePayload,
+) -> Result<ImmunizationUpdateResponse, Message> {
+    ensure_id_kind(id.0, EntityKind::Immunization)?;
+    let mut immunization = IMMUNIZATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Immunization not found".to_string()))?;
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut immunization.vaccine,
+        payload.vaccine,
+        "vaccine",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut immunization.dose_number,
+        payload.dose_number,
+        "dose_number",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut immunization.lot_number,
+        payload.lot_number,
+        "lot_number",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut immunization.site,
+        payload.site,
+        "site",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut immunization.administered_on,
+        payload.administered_on,
+        "administered_on",
+        &mut changed_fields,
+    );
+    validate_immunization(&immunization)?;
+
+    if !changed_fields.is_empty() {
+        immunization.updated_at = Some(current_time());
+        save_immunization(&immunization);
+    }
+    Ok(ImmunizationUpdateResponse {
+        immunization,
+        changed_fields,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_immunization(id: ImmunizationId) -> Result<(), Message> {
+    ensure_id_kind(id.0, EntityKind::Immunization)?;
+    if remove_immunization(id).is_some() {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Immunization not found".to_string()))
+    }
+}
+
+// Function to get a patient's immunization history, oldest first
+#[ic_cdk::query]
+fn get_patient_immunizations(patient_id: PatientId) -> Result<Vec<Immunization>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    let mut immunizations = load_immunizations(patient_id);
+    immunizations.sort_by(|a, b| a.administered_on.cmp(&b.administered_on));
+    Ok(immunizations)
+}
+
+// Function to replace the immunization schedule; only controllers can change it
+#[ic_cdk::update]
+fn set_immunization_schedule(doses: Vec<ScheduleDose>) -> Result<u64, Message> {
+    ensure_controller()?;
+    if doses.len() > MAX_SCHEDULE_DOSES {
+        return Err(Message::InvalidPayload(format!(
+            "A schedule can have at most {} doses.",
+            MAX_SCHEDULE_DOSES
+        )));
+    }
+
+    // Check the whole schedule before replacing the stored one
+    let mut keyed = Vec::with_capacity(doses.len());
+    for dose in doses {
+        let vaccine = normalize_words(&dose.vaccine);
+        if vaccine.is_empty() || dose.dose_number == 0 {
+            return Err(Message::InvalidPayload(
+                "Ensure each dose names a vaccine and a dose number from 1.".to_string(),
+            ));
+        }
+        validate_length("vaccine", &dose.vaccine, MAX_VACCINE_NAME_LENGTH)?;
+        validate_length("vaccine", &vaccine, MAX_VACCINE_NAME_LENGTH)?;
+        if matches!(dose.max_age_days, Some(max_age_days) if max_age_days < dose.min_age_days) {
+            return Err(Message::InvalidPayload(
+                "'max_age_days' must not be below 'min_age_days'.".to_string(),
+            ));
+        }
+        let key = (vaccine_key(&vaccine), dose.dose_number);
+        if keyed.iter().any(|(existing, _)| *existing == key) {
+            return Err(Message::InvalidPayload(format!(
+                "Dose {} of '{}' is listed more than once.",
+                dose.dose_number, dose.vaccine
+            )));
+        }
+        keyed.push((key, dose));
+    }
+
+    IMMUNIZATION_SCHEDULE.with(|schedule| {
+        let mut schedule = schedule.borrow_mut();
+        let old_keys: Vec<(VaccineKey, u32)> = schedule.iter().map(|(key, _)| key).collect();
+        for key in old_keys {
+            schedule.remove(&key);
+        }
+        for (key, dose) in keyed {
+            schedule.insert(key, dose);
+        }
+        Ok(schedule.len())
+    })
+}
+
+#[ic_cdk::query]
+fn get_immunization_schedule() -> Vec<ScheduleDose> {
+    IMMUNIZATION_SCHEDULE.with(|schedule| schedule.borrow().iter().map(|(_, dose)| dose).collect())
+}
+
+// Function to list the scheduled doses a patient has not received, earliest due first.
+// Due dates follow from the date of birth and the doses already given; a missed dose
+// pushes back the doses after it.
+#[ic_cdk::query]
+fn get_immunizations_due(patient_id: PatientId) -> Result<Vec<ImmunizationDue>, Message> {
+    // A merged patient resolves to the patient it was merged into
+    let patient = get_patient_by_id(patient_id)?;
+    let patient_id = patient.id;
+    let date_of_birth = parse_date(&patient.date_of_birth).ok_or(Message::Error(
+        "The patient's date of birth is not a valid date.".to_string(),
+    ))?;
+    let today = current_date();
+    let add_days = |date: NaiveDate, days: u32| {
+        date.checked_add_days(Days::new(days as u64))
+            .unwrap_or(NaiveDate::MAX)
+    };
+
+    let history: Vec<(String, u32, NaiveDate)> = load_immunizations(patient_id)
+        .into_iter()
+        .filter_map(|immunization| {
+            parse_date(&immunization.administered_on).map(|date| {
+                (
+                    normalize_words(&immunization.vaccine),
+                    immunization.dose_number,
+                    date,
+                )
+            })
+        })
+        .collect();
+
+    let mut due = Vec::new();
+    let mut current_vaccine = String::new();
+    let mut previous: Option<NaiveDate> = None;
+    // The schedule is ordered by vaccine and then dose number
+    for dose in get_immunization_schedule() {
+        let vaccine = normalize_words(&dose.vaccine);
+        if vaccine != current_vaccine {
+            current_vaccine = vaccine.clone();
+            previous = None;
+        }
+
+        let given = history
+            .iter()
+            .filter(|(name, number, _)| *name == vaccine && *number == dose.dose_number)
+            .map(|(_, _, date)| *date)
+            .min();
+        if given.is_some() {
+            previous = given;
+            continue;
+        }
+        if matches!(dose.max_age_days, Some(max_age_days) if today > add_days(date_of_birth, max_age_days))
+        {
+            continue;
+        }
+
+        let mut due_date = add_days(date_of_birth, dose.min_age_days);
+        if let Some(previous) = previous {
+            due_date = due_date.max(add_days(previous, dose.min_interval_days));
+        }
+        previous = Some(due_date);
+        due.push(ImmunizationDue {
+            vaccine: dose.vaccine,
+            dose_number: dose.dose_number,
+            due_date: due_date.to_string(),
+            status: if due_date < today {
+                DueStatus::Overdue
+            } else {
+                DueStatus::Upcoming
+            },
+        });
+    }
+    due.sort_by(|a, b| a.due_date.cmp(&b.due_date));
+    Ok(due)
+}
+
+#[ic_cdk::update]
+fn create_clinical_note(payload: ClinicalNotePayload) -> Result<ClinicalNote, Message> {
+    // Validate the patient, author and appointment ids
+    ensure_patient_exists(payload.patient_id)?;
+    let doctor = get_doctor_id(payload.doctor_id)?;
+    if let Some(appointment_id) = payload.appointment_id {
+        ensure_note_appointment(appointment_id, payload.patient_id, payload.doctor_id)?;
+    }
+
+    // Use the template of the doctor's first speciality that has one
+    let template = doctor
+        .specialities
+        .iter()
+        .find_map(|speciality| {
+            NOTE_TEMPLATES.with(|templates| templates.borrow().get(&speciality_key(speciality)))
+        })
+        .unwrap_or_default();
+    let id = ClinicalNoteId(next_id(EntityKind::ClinicalNote));
+
+    let note = ClinicalNote {
+        id,
+        patient_id: payload.patient_id,
+        doctor_id: payload.doctor_id,
+        appointment_id: payload.appointment_id,
+        subjective: payload.subjective.unwrap_or(template.subjective),
+        objective: payload.objective.unwrap_or(template.objective),
+        assessment: payload.assessment.unwrap_or(template.assessment),
+        plan: payload.plan.unwrap_or(template.plan),
+        status: NoteStatus::Draft,
+        signed_at: None,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    validate_clinical_note(&note)?;
+    save_clinical_note(&note);
+    Ok(note)
+}
+
+#[ic_cdk::query]
+fn get_clinical_note_by_id(id: ClinicalNoteId) -> Result<ClinicalNote, Message> {
+    ensure_id_kind(id.0, EntityKind::ClinicalNote)?;
+    CLINICAL_NOTES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&id)
+            .ok_or(Message::NotFound("Clinical note not found".to_string()))
+    })
+}
+
+#[ic_cdk::update]
+fn update_clinical_note(
+    id: ClinicalNoteId,
+    payload: ClinicalNoteUpdatePayload,
+) -> Result<ClinicalNoteUpdateResponse, Message> {
+    let mut note = load_draft_note(id)?;
+
+    // Validate the appointment id if it is being changed
+    if let Some(Some(appointment_id)) = payload.appointment_id {
+        ensure_note_appointment(appointment_id, note.patient_id, note.doctor_id)?;
+    }
+
+    let mut changed_fields = Vec::new();
+    apply_field(
+        &mut note.appointment_id,
+        payload.appointment_id,
+        "appointment_id",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut note.subjective,
+        payload.subjective,
+        "subjective",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut note.objective,
+        payload.objective,
+        "objective",
+        &mut changed_fields,
+    );
+    apply_field(
+        &mut note.assessment,
+        payload.assessment,
+        "assessment",
+        &mut changed_fields,
+    );
+    apply_field(&mut note.plan, payload.plan, "plan", &mut changed_fields);
+    validate_clinical_note(&note)?;
+
+    if !changed_fields.is_empty() {
+        note.updated_at = Some(current_time());
+        save_clinical_note(&note);
+    }
+    Ok(ClinicalNoteUpdateResponse {
+        note,
+        changed_fields,
+    })
+}
+
+// Function to sign a draft note; a signed note can no longer be changed
+#[ic_cdk::update]
+fn sign_clinical_note(id: ClinicalNoteId) -> Result<ClinicalNote, Message> {
+    let mut note = load_draft_note(id)?;
+    if note.assessment.trim().is_empty() || note.plan.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "A note needs an assessment and a plan to be signed.".to_string(),
+        ));
+    }
+
+    let now = current_time();
+    note.status = NoteStatus::Signed;
+    note.signed_at = Some(now);
+    note.updated_at = Some(now);
+    save_clinical_note(&note);
+    Ok(note)
+}
+
+#[ic_cdk::update]
+fn delete_clinical_note(id: ClinicalNoteId) -> Result<(), Message> {
+    load_draft_note(id)?;
+    remove_clinical_note(id);
+    Ok(())
+}
+
+// Function to get all clinical notes of a patient, oldest first
+#[ic_cdk::query]
+fn get_patient_clinical_notes(patient_id: PatientId) -> Result<Vec<ClinicalNote>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(clinical_note_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| CLINICAL_NOTES_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+// Function to get the clinical notes written during an appointment
+#[ic_cdk::query]
+fn get_appointment_clinical_notes(
+    appointment_id: AppointmentId,
+) -> Result<Vec<ClinicalNote>, Message> {
+    get_appointment_id(appointment_id)?;
+    Ok(clinical_note_ids_for_appointment(appointment_id)
+        .into_iter()
+        .filter_map(|id| CLINICAL_NOTES_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+// Function to add or replace the note template of a speciality
+#[ic_cdk::update]
+fn set_note_template(template: NoteTemplate) -> Result<NoteTemplate, Message> {
+    ensure_controller()?;
+    if normalize_words(&template.speciality).is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'speciality' is provided.".to_string(),
+        ));
+    }
+    let speciality = find_speciality(&template.speciality).ok_or_else(|| {
+        Message::InvalidPayload(format!(
+            "Speciality '{}' is not in the catalogue.",
+            template.speciality
+        ))
+    })?;
+    let template = NoteTemplate {
+        speciality: speciality.name,
+        ..template
+    };
+    validate_note_sections(
+        &template.subjective,
+        &template.objective,
+        &template.assessment,
+        &template.plan,
+    )?;
+
+    NOTE_TEMPLATES.with(|templates| {
+        templates
+            .borrow_mut()
+            .insert(speciality_key(&template.speciality), template.clone())
+    });
+    Ok(template)
+}
+
+#[ic_cdk::query]
+fn get_note_template(speciality: String) -> Result<NoteTemplate, Message> {
+    if normalize_words(&speciality).len() > MAX_LABEL_LENGTH {
+        return Err(Message::NotFound("Note template not found".to_string()));
+    }
+    NOTE_TEMPLATES
+        .with(|templates| templates.borrow().get(&speciality_key(&speciality)))
+        .ok_or(Message::NotFound("Note template not found".to_string()))
+}
+
+#[ic_cdk::query]
+fn get_note_templates() -> Vec<NoteTemplate> {
+    NOTE_TEMPLATES.with(|templates| {
+        templates
+            .borrow()
+            .iter()
+            .map(|(_, template)| template)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn delete_note_template(speciality: String) -> Result<(), Message> {
+    ensure_controller()?;
+    if normalize_words(&speciality).len() <= MAX_LABEL_LENGTH
+        && NOTE_TEMPLATES
+            .with(|templates| templates.borrow_mut().remove(&speciality_key(&speciality)))
+            .is_some()
+    {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Note template not found".to_string()))
+    }
+}
+
+// Function to start uploading a document; the content follows in chunks
+#[ic_cdk::update]
+fn begin_document_upload(payload: DocumentPayload) -> Result<Document, Message> {
+    let uploader = caller();
+    if uploader == Principal::anonymous() {
+        return Err(Message::Unauthorized(
+            "Documents cannot be uploaded anonymously.".to_string(),
+        ));
+    }
+    ensure_patient_exists(payload.patient_id)?;
+    validate_document(&payload)?;
+
+    let id = DocumentId(next_id(EntityKind::Document));
+
+    let document = Document {
+        id,
+        patient_id: payload.patient_id,
+        title: payload.title,
+        category: payload.category,
+        mime_type: payload.mime_type.to_lowercase(),
+        size: payload.size,
+        sha256: None,
+        expected_sha256: payload.sha256.map(|hash| hash.to_lowercase()),
+        status: DocumentStatus::Uploading,
+        uploaded_by: uploader,
+        created_at: current_time(),
+        updated_at: None,
+    };
+    save_document(&document);
+    Ok(document)
+}
+
+// Function to upload one chunk of a document; uploading a chunk again replaces it
+#[ic_cdk::update]
+fn upload_document_chunk(id: DocumentId, index: u32, data: Vec<u8>) -> Result<(), Message> {
+    let document = load_document(id)?;
+    if document.uploaded_by != caller() {
+        return Err(Message::Unauthorized(
+            "Only the uploader can add content to a document.".to_string(),
+        ));
+    }
+    if document.status != DocumentStatus::Uploading {
+        return Err(Message::Error(
+            "The document upload is already finished.".to_string(),
+        ));
+    }
+    if index >= chunk_count(document.size) || data.len() != chunk_length(document.size, index) {
+        return Err(Message::InvalidPayload(format!(
+            "Chunk {} must be {} bytes; the document has {} chunks.",
+            index,
+            chunk_length(document.size, index),
+            chunk_count(document.size)
+        )));
+    }
+
+    DOCUMENT_CONTENT.with(|content| {
+        content
+            .borrow_mut()
+            .insert((id, index), DocumentBytes(data))
+    });
+    Ok(())
+}
+
+// Function to finish an upload once every chunk is stored; the content hash is
+// computed and checked against the expected one
+#[ic_cdk::update]
+fn finish_document_upload(id: DocumentId) -> Result<Document, Message> {
+    let mut document = load_document(id)?;
+    if document.uploaded_by != caller() {
+        return Err(Message::Unauthorized(
+            "Only the uploader can finish a document upload.".to_string(),
+        ));
+    }
+    if document.status != DocumentStatus::Uploading {
+        return Err(Message::Error(
+            "The document upload is already finished.".to_string(),
+        ));
+    }
+
+    let mut hasher = Sha256::new();
+    for index in 0..chunk_count(document.size) {
+        let chunk = DOCUMENT_CONTENT
+            .with(|content| content.borrow().get(&(id, index)))
+            .ok_or(Message::Error(format!("Chunk {} is missing.", index)))?;
+        hasher.update(&chunk.0);
+    }
+    let sha256 = hex_encode(&hasher.finalize());
+    if matches!(&document.expected_sha256, Some(expected) if *expected != sha256) {
+        return Err(Message::InvalidPayload(format!(
+            "The content hash {} does not match 'sha256'; re-upload the chunks.",
+            sha256
+        )));
+    }
+
+    document.sha256 = Some(sha256);
+    document.status = DocumentStatus::Available;
+    document.updated_at = Some(current_time());
+    save_document(&document);
+    Ok(document)
+}
+
+#[ic_cdk::query]
+fn get_document(id: DocumentId) -> Result<Document, Message> {
+    let document = load_document(id)?;
+    ensure_document_access(&document)?;
+    Ok(document)
+}
+
+// Function to list the documents of a patient that the caller may read
+#[ic_cdk::query]
+fn get_patient_documents(patient_id: PatientId) -> Result<Vec<Document>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(document_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| DOCUMENTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .filter(|document| ensure_document_access(document).is_ok())
+        .collect())
+}
+
+// Function to download one chunk of an available document
+#[ic_cdk::query]
+fn get_document_chunk(id: DocumentId, index: u32) -> Result<Vec<u8>, Message> {
+    let document = load_document(id)?;
+    ensure_document_access(&document)?;
+    if document.status != DocumentStatus::Available {
+        return Err(Message::Error(
+            "The document upload is not finished.".to_string(),
+        ));
+    }
+    DOCUMENT_CONTENT
+        .with(|content| content.borrow().get(&(id, index)))
+        .map(|chunk| chunk.0)
+        .ok_or(Message::NotFound(format!("Chunk {} not found", index)))
+}
+
+#[ic_cdk::update]
+fn delete_document(id: DocumentId) -> Result<(), Message> {
+    let document = load_document(id)?;
+    ensure_document_access(&document)?;
+    remove_document(id);
+    Ok(())
+}
+
+// Function to create a short-lived link for downloading a document through http_request
+#[ic_cdk::update]
+async fn create_document_download_link(id: DocumentId) -> Result<DownloadLink, Message> {
+    let document = load_document(id)?;
+    ensure_document_access(&document)?;
+    if document.status != DocumentStatus::Available {
+        return Err(Message::Error(
+            "The document upload is not finished.".to_string(),
+        ));
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, reason)| Message::Error(format!("Cannot create a token: {}", reason)))?;
+    let token = DownloadToken::try_from(&random[..DOWNLOAD_TOKEN_LENGTH])
+        .expect("raw_rand returns 32 bytes");
+    let now = current_time();
+    let grant = DownloadGrant {
+        document_id: id,
+        created_by: caller(),
+        expires_at: now + DOWNLOAD_LINK_TTL,
+    };
+
+    DOWNLOAD_GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        // Drop expired grants while we are here
+        let expired: Vec<DownloadToken> = grants
+            .iter()
+            .filter(|(_, grant)| grant.expires_at <= now)
+            .map(|(token, _)| token)
+            .collect();
+        for token in expired {
+            grants.remove(&token);
+        }
+        grants.insert(token, grant.clone());
+    });
+    Ok(DownloadLink {
+        path: format!("/documents/{}?token={}", id.0, hex_encode(token.as_slice())),
+        expires_at: grant.expires_at,
+    })
+}
+
+// Function to serve document downloads at /documents/<id>?token=<token>. The first
+// chunk is returned here and the rest are streamed through the callback.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    let document_id = path
+        .strip_prefix("/documents/")
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(DocumentId);
+    let token = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .unwrap_or_default();
+
+    let document_id = match (request.method.as_str(), document_id) {
+        ("GET", Some(document_id)) => document_id,
+        ("GET", None) => return http_error(404, "Not found"),
+        _ => return http_error(405, "Method not allowed"),
+    };
+    let document = match authorize_download(document_id, token) {
+        Ok(document) => document,
+        Err(Message::NotFound(_)) => return http_error(404, "Not found"),
+        Err(_) => return http_error(403, "Forbidden"),
+    };
+
+    let body = DOCUMENT_CONTENT
+        .with(|content| content.borrow().get(&(document_id, 0)))
+        .map(|chunk| chunk.0)
+        .unwrap_or_default();
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), document.mime_type.clone()),
+            ("Content-Length".to_string(), document.size.to_string()),
+            ("Content-Disposition".to_string(), "attachment".to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ],
+        body,
+        streaming_strategy: next_streaming_token(&document, 0, token).map(|token| {
+            StreamingStrategy::Callback {
+                callback: StreamingCallback::new(
+                    ic_cdk::id(),
+                    "http_request_streaming_callback".to_string(),
+                ),
+                token,
+            }
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    let document = match authorize_download(token.document_id, &token.token) {
+        Ok(document) => document,
+        Err(_) => ic_cdk::trap("The download link is invalid or has expired."),
+    };
+    let body = DOCUMENT_CONTENT
+        .with(|content| content.borrow().get(&(token.document_id, token.index)))
+        .map(|chunk| chunk.0)
+        .unwrap_or_default();
+    StreamingCallbackHttpResponse {
+        body,
+        token: next_streaming_token(&document, token.index, &token.token),
+    }
+}
+
+// Function to get all appointments of a patient
+#[ic_cdk::query]
+fn get_patient_appointments(patient_id: PatientId) -> Result<Vec<Appointment>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(load_appointments(appointment_ids_for_patient(patient_id)))
+}
+
+// Function to get all appointments of a doctor
+#[ic_cdk::query]
+fn get_doctor_appointments(doctor_id: DoctorId) -> Result<Vec<Appointment>, Message> {
+    ensure_doctor_exists(doctor_id)?;
+    Ok(load_appointments(appointment_ids_for_doctor(doctor_id)))
+}
+
+// Function to get the appointments starting in [from, to), ordered by start time
+#[ic_cdk::query]
+fn get_appointments_between(from: u64, to: u64) -> Result<Vec<Appointment>, Message> {
+    if from >= to {
+        return Err(Message::InvalidPayload(
+            "Ensure 'from' is before 'to'.".to_string(),
+        ));
+    }
+    let ids: Vec<AppointmentId> = APPOINTMENTS_BY_DATE.with(|index| {
+        index
+            .borrow()
+            .range((from, AppointmentId(0))..(to, AppointmentId(0)))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    Ok(load_appointments(ids))
+}
+
+// Function to get all records of a patient
+#[ic_cdk::query]
+fn get_records_for_patient(patient_id: PatientId) -> Result<Vec<PatientRecord>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(patient_record_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| PATIENT_RECORDS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+// Function to get all medications of a patient
+#[ic_cdk::query]
+fn get_medications_for_patient(patient_id: PatientId) -> Result<Vec<Medication>, Message> {
+    let patient_id = resolve_patient(patient_id)?;
+    Ok(medication_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| MEDICATIONS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect())
+}
+
+// Function to report every foreign key that points at a missing patient or doctor
+#[ic_cdk::query]
+fn check_referential_integrity() -> Result<Vec<DanglingReference>, Message> {
+    let mut dangling = Vec::new();
+    let mut check = |entity, entity_id, field: &str, missing, missing_id, exists: bool| {
+        if !exists {
+            dangling.push(DanglingReference {
+                entity,
+                entity_id,
+                field: field.to_string(),
+                missing,
+                missing_id,
+            });
+        }
+    };
+
+    APPOINTMENTS_STORAGE.with(|storage| {
+        for (_, appointment) in storage.borrow().iter() {
+            check(
+                EntityKind::Appointment,
+                appointment.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                appointment.patient_id.0,
+                ensure_patient_exists(appointment.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::Appointment,
+                appointment.id.0,
+                "doctor_id",
+                EntityKind::Doctor,
+                appointment.doctor_id.0,
+                ensure_doctor_exists(appointment.doctor_id).is_ok(),
+            );
+        }
+    });
+
+    PATIENT_RECORDS_STORAGE.with(|storage| {
+        for (_, patient_record) in storage.borrow().iter() {
+            check(
+                EntityKind::PatientRecord,
+                patient_record.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                patient_record.patient_id.0,
+                ensure_patient_exists(patient_record.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::PatientRecord,
+                patient_record.id.0,
+                "doctor_id",
+                EntityKind::Doctor,
+                patient_record.doctor_id.0,
+                ensure_doctor_exists(patient_record.doctor_id).is_ok(),
+            );
+            for medication_id in &patient_record.medications {
+                check(
+                    EntityKind::PatientRecord,
+                    patient_record.id.0,
+                    "medications",
+                    EntityKind::Medication,
+                    medication_id.0,
+                    MEDICATIONS_STORAGE
+                        .with(|storage| storage.borrow().contains_key(medication_id)),
+                );
+            }
+        }
+    });
+
+    MEDICATIONS_STORAGE.with(|storage| {
+        for (_, medication) in storage.borrow().iter() {
+            check(
+                EntityKind::Medication,
+                medication.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                medication.patient_id.0,
+                ensure_patient_exists(medication.patient_id).is_ok(),
+            );
+            if let Some(doctor_id) = medication.doctor_id {
+                check(
+                    EntityKind::Medication,
+                    medication.id.0,
+                    "doctor_id",
+                    EntityKind::Doctor,
+                    doctor_id.0,
+                    ensure_doctor_exists(doctor_id).is_ok(),
+                );
+            }
+        }
+    });
+
+    INSURANCE_COVERAGES_STORAGE.with(|storage| {
+        for (_, insurance_coverage) in storage.borrow().iter() {
+            check(
+                EntityKind::InsuranceCoverage,
+                insurance_coverage.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                insurance_coverage.patient_id.0,
+                ensure_patient_exists(insurance_coverage.patient_id).is_ok(),
+            );
+        }
+    });
+
+    ALLERGIES_STORAGE.with(|storage| {
+        for (_, allergy) in storage.borrow().iter() {
+            check(
+                EntityKind::Allergy,
+                allergy.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                allergy.patient_id.0,
+                ensure_patient_exists(allergy.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::Allergy,
+                allergy.id.0,
+                "recorded_by",
+                EntityKind::Doctor,
+                allergy.recorded_by.0,
+                ensure_doctor_exists(allergy.recorded_by).is_ok(),
+            );
+        }
+    });
+
+    PROBLEMS_STORAGE.with(|storage| {
+        for (_, problem) in storage.borrow().iter() {
+            check(
+                EntityKind::Problem,
+                problem.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                problem.patient_id.0,
+                ensure_patient_exists(problem.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::Problem,
+                problem.id.0,
+                "recorded_by",
+                EntityKind::Doctor,
+                problem.recorded_by.0,
+                ensure_doctor_exists(problem.recorded_by).is_ok(),
+            );
+            for (field, record_id) in [
+                ("source_record_id", problem.source_record_id),
+                ("resolved_record_id", problem.resolved_record_id),
+            ] {
+                if let Some(record_id) = record_id {
+                    check(
+                        EntityKind::Problem,
+                        problem.id.0,
+                        field,
+                        EntityKind::PatientRecord,
+                        record_id.0,
+                        entity_exists(EntityKind::PatientRecord, record_id.0),
+                    );
+                }
+            }
+        }
+    });
+
+    FAMILY_HISTORY_STORAGE.with(|storage| {
+        for (_, entry) in storage.borrow().iter() {
+            check(
+                EntityKind::FamilyHistory,
+                entry.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                entry.patient_id.0,
+                ensure_patient_exists(entry.patient_id).is_ok(),
+            );
+        }
+    });
+
+    CARE_PLANS_STORAGE.with(|storage| {
+        for (_, plan) in storage.borrow().iter() {
+            check(
+                EntityKind::CarePlan,
+                plan.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                plan.patient_id.0,
+                ensure_patient_exists(plan.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::CarePlan,
+                plan.id.0,
+                "doctor_id",
+                EntityKind::Doctor,
+                plan.doctor_id.0,
+                ensure_doctor_exists(plan.doctor_id).is_ok(),
+            );
+        }
+    });
+
+    CARE_TASKS_STORAGE.with(|storage| {
+        for (_, task) in storage.borrow().iter() {
+            check(
+                EntityKind::CareTask,
+                task.id.0,
+                "care_plan_id",
+                EntityKind::CarePlan,
+                task.care_plan_id.0,
+                entity_exists(EntityKind::CarePlan, task.care_plan_id.0),
+            );
+            check(
+                EntityKind::CareTask,
+                task.id.0,
+                "assigned_to",
+                EntityKind::Doctor,
+                task.assigned_to.0,
+                ensure_doctor_exists(task.assigned_to).is_ok(),
+            );
+        }
+    });
+
+    OBSERVATIONS_STORAGE.with(|storage| {
+        for (_, observation) in storage.borrow().iter() {
+            check(
+                EntityKind::Observation,
+                observation.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                observation.patient_id.0,
+                ensure_patient_exists(observation.patient_id).is_ok(),
+            );
+            if let Some(appointment_id) = observation.appointment_id {
+                check(
+                    EntityKind::Observation,
+                    observation.id.0,
+                    "appointment_id",
+                    EntityKind::Appointment,
+                    appointment_id.0,
+                    APPOINTMENTS_STORAGE
+                        .with(|storage| storage.borrow().contains_key(&appointment_id)),
+                );
+            }
+        }
+    });
+
+    LAB_ORDERS_STORAGE.with(|storage| {
+        for (_, order) in storage.borrow().iter() {
+            check(
+                EntityKind::LabOrder,
+                order.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                order.patient_id.0,
+                ensure_patient_exists(order.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::LabOrder,
+                order.id.0,
+                "doctor_id",
+                EntityKind::Doctor,
+                order.doctor_id.0,
+                ensure_doctor_exists(order.doctor_id).is_ok(),
+            );
+        }
+    });
+
+    LAB_RESULTS_STORAGE.with(|storage| {
+        for (_, result) in storage.borrow().iter() {
+            check(
+                EntityKind::LabResult,
+                result.id.0,
+                "order_id",
+                EntityKind::LabOrder,
+                result.order_id.0,
+                LAB_ORDERS_STORAGE.with(|storage| storage.borrow().contains_key(&result.order_id)),
+            );
+        }
+    });
+
+    IMMUNIZATIONS_STORAGE.with(|storage| {
+        for (_, immunization) in storage.borrow().iter() {
+            check(
+                EntityKind::Immunization,
+                immunization.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                immunization.patient_id.0,
+                ensure_patient_exists(immunization.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::Immunization,
+                immunization.id.0,
+                "administered_by",
+                EntityKind::Doctor,
+                immunization.administered_by.0,
+                ensure_doctor_exists(immunization.administered_by).is_ok(),
+            );
+        }
+    });
+
+    CLINICAL_NOTES_STORAGE.with(|storage| {
+        for (_, note) in storage.borrow().iter() {
+            check(
+                EntityKind::ClinicalNote,
+                note.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                note.patient_id.0,
+                ensure_patient_exists(note.patient_id).is_ok(),
+            );
+            check(
+                EntityKind::ClinicalNote,
+                note.id.0,
+                "doctor_id",
+                EntityKind::Doctor,
+                note.doctor_id.0,
+                ensure_doctor_exists(note.doctor_id).is_ok(),
+            );
+            if let Some(appointment_id) = note.appointment_id {
+                check(
+                    EntityKind::ClinicalNote,
+                    note.id.0,
+                    "appointment_id",
+                    EntityKind::Appointment,
+                    appointment_id.0,
+                    APPOINTMENTS_STORAGE
+                        .with(|storage| storage.borrow().contains_key(&appointment_id)),
+                );
+            }
+        }
+    });
+
+    APPOINTMENTS_STORAGE.with(|storage| {
+        for (_, appointment) in storage.borrow().iter() {
+            if let Some(room_id) = appointment.room_id {
+                check(
+                    EntityKind::Appointment,
+                    appointment.id.0,
+                    "room_id",
+                    EntityKind::Room,
+                    room_id.0,
+                    ensure_room_exists(room_id).is_ok(),
+                );
+            }
+        }
+    });
+
+    DEPARTMENTS_STORAGE.with(|storage| {
+        for (_, department) in storage.borrow().iter() {
+            check(
+                EntityKind::Department,
+                department.id.0,
+                "facility_id",
+                EntityKind::Facility,
+                department.facility_id.0,
+                ensure_facility_exists(department.facility_id).is_ok(),
+            );
+        }
+    });
+
+    ROOMS_STORAGE.with(|storage| {
+        for (_, room) in storage.borrow().iter() {
+            check(
+                EntityKind::Room,
+                room.id.0,
+                "department_id",
+                EntityKind::Department,
+                room.department_id.0,
+                ensure_department_exists(room.department_id).is_ok(),
+            );
+        }
+    });
+
+    DOCUMENTS_STORAGE.with(|storage| {
+        for (_, document) in storage.borrow().iter() {
+            check(
+                EntityKind::Document,
+                document.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                document.patient_id.0,
+                ensure_patient_exists(document.patient_id).is_ok(),
+            );
+        }
+    });
+
+    EMERGENCY_CONTACTS_STORAGE.with(|storage| {
+        for (_, emergency_contact) in storage.borrow().iter() {
+            check(
+                EntityKind::EmergencyContact,
+                emergency_contact.id.0,
+                "patient_id",
+                EntityKind::Patient,
+                emergency_contact.patient_id.0,
+                ensure_patient_exists(emergency_contact.patient_id).is_ok(),
+            );
+        }
+    });
+
+    Ok(dangling)
+}
+
+// Function to resolve any id to the kind of entity it identifies
+#[ic_cdk::query]
+fn resolve_id(id: u64) -> Result<EntityKind, Message> {
+    let candidates = match EntityKind::from_id(id) {
+        Some(kind) => vec![kind],
+        None => vec![
+            EntityKind::Doctor,
+            EntityKind::Patient,
+            EntityKind::Appointment,
+            EntityKind::PatientRecord,
+            EntityKind::Medication,
+            EntityKind::EmergencyContact,
+            EntityKind::InsuranceCoverage,
+            EntityKind::Allergy,
+            EntityKind::Observation,
+            EntityKind::LabOrder,
+            EntityKind::LabResult,
+            EntityKind::Immunization,
+            EntityKind::ClinicalNote,
+            EntityKind::Document,
+            EntityKind::Facility,
+            EntityKind::Department,
+            EntityKind::Room,
+            EntityKind::Problem,
+            EntityKind::FamilyHistory,
+            EntityKind::CarePlan,
+            EntityKind::CareTask,
+        ],
+    };
+    candidates
+        .into_iter()
+        .find(|kind| entity_exists(*kind, id))
+        .ok_or(Message::NotFound(
+            "No entity found with this id".to_string(),
+        ))
+}
+
+#[cfg(not(test))]
+fn current_time() -> u64 {
+    ic_cdk::api::time()
+}
+
+// Unit tests run natively, without the system API, so their clock stands still
+#[cfg(test)]
+fn current_time() -> u64 {
+    tests::NOW
+}
+
+fn current_date() -> NaiveDate {
+    date_from_timestamp(current_time())
+}
+
+// Function to get the UTC date of a timestamp in nanoseconds
+fn date_from_timestamp(nanos: u64) -> NaiveDate {
+    DateTime::from_timestamp((nanos / 1_000_000_000) as i64, 0)
+        .expect("Timestamp is out of range")
+        .date_naive()
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+// Function to fill in the patient's age as of today
+fn with_current_age(mut patient: Patient) -> Patient {
+    if let Ok(date_of_birth) = NaiveDate::parse_from_str(&patient.date_of_birth, "%Y-%m-%d") {
+        patient.age = age_on(date_of_birth, current_date());
+    }
+    patient
+}
+
+fn age_on(date_of_birth: NaiveDate, date: NaiveDate) -> u32 {
+    let had_birthday = (date.month(), date.day()) >= (date_of_birth.month(), date_of_birth.day());
+    let years = date.year() - date_of_birth.year() - if had_birthday { 0 } else { 1 };
+    years.max(0) as u32
+}
+
+// Function to allocate the next id from the entity's own sequence, tagged with its kind
+fn next_id(kind: EntityKind) -> u64 {
+    let tag = kind.tag();
+    let sequence = ID_SEQUENCES.with(|sequences| {
+        let mut sequences = sequences.borrow_mut();
+        let current_value = sequences.get(&tag).unwrap_or(0);
+        sequences.insert(tag, current_value + 1);
+        current_value
+    });
+    (u64::from(tag) << ID_TAG_SHIFT) | sequence
+}
+
+// Function to reject an id that was allocated for a different kind of entity
+fn ensure_id_kind(id: u64, expected: EntityKind) -> Result<(), Message> {
+    match EntityKind::from_id(id) {
+        Some(kind) if kind != expected => Err(Message::InvalidPayload(format!(
+            "Id {} belongs to a {:?}, not a {:?}.",
+            id, kind, expected
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn entity_exists(kind: EntityKind, id: u64) -> bool {
+    match kind {
+        EntityKind::Doctor => {
+            DOCTORS_STORAGE.with(|storage| storage.borrow().contains_key(&DoctorId(id)))
+        }
+        EntityKind::Patient => {
+            PATIENTS_STORAGE.with(|storage| storage.borrow().contains_key(&PatientId(id)))
+        }
+        EntityKind::Appointment => {
+            APPOINTMENTS_STORAGE.with(|storage| storage.borrow().contains_key(&AppointmentId(id)))
+        }
+        EntityKind::PatientRecord => PATIENT_RECORDS_STORAGE
+            .with(|storage| storage.borrow().contains_key(&PatientRecordId(id))),
+        EntityKind::Medication => {
+            MEDICATIONS_STORAGE.with(|storage| storage.borrow().contains_key(&MedicationId(id)))
+        }
+        EntityKind::EmergencyContact => EMERGENCY_CONTACTS_STORAGE
+            .with(|storage| storage.borrow().contains_key(&EmergencyContactId(id))),
+        EntityKind::InsuranceCoverage => INSURANCE_COVERAGES_STORAGE
+            .with(|storage| storage.borrow().contains_key(&InsuranceCoverageId(id))),
+        EntityKind::Allergy => {
+            ALLERGIES_STORAGE.with(|storage| storage.borrow().contains_key(&AllergyId(id)))
+        }
+        EntityKind::Observation => {
+            OBSERVATIONS_STORAGE.with(|storage| storage.borrow().contains_key(&ObservationId(id)))
+        }
+        EntityKind::LabOrder => {
+            LAB_ORDERS_STORAGE.with(|storage| storage.borrow().contains_key(&LabOrderId(id)))
+        }
+        EntityKind::LabResult => {
+            LAB_RESULTS_STORAGE.with(|storage| storage.borrow().contains_key(&LabResultId(id)))
+        }
+        EntityKind::Immunization => {
+            IMMUNIZATIONS_STORAGE.with(|storage| storage.borrow().contains_key(&ImmunizationId(id)))
+        }
+        EntityKind::ClinicalNote => CLINICAL_NOTES_STORAGE
+            .with(|storage| storage.borrow().contains_key(&ClinicalNoteId(id))),
+        EntityKind::Document => {
+            DOCUMENTS_STORAGE.with(|storage| storage.borrow().contains_key(&DocumentId(id)))
+        }
+        EntityKind::Facility => {
+            FACILITIES_STORAGE.with(|storage| storage.borrow().contains_key(&FacilityId(id)))
+        }
+        EntityKind::Department => {
+            DEPARTMENTS_STORAGE.with(|storage| storage.borrow().contains_key(&DepartmentId(id)))
+        }
+        EntityKind::Room => {
+            ROOMS_STORAGE.with(|storage| storage.borrow().contains_key(&RoomId(id)))
+        }
+        EntityKind::Problem => {
+            PROBLEMS_STORAGE.with(|storage| storage.borrow().contains_key(&ProblemId(id)))
+        }
+        EntityKind::FamilyHistory => FAMILY_HISTORY_STORAGE
+            .with(|storage| storage.borrow().contains_key(&FamilyHistoryId(id))),
+        EntityKind::CarePlan => {
+            CARE_PLANS_STORAGE.with(|storage| storage.borrow().contains_key(&CarePlanId(id)))
+        }
+        EntityKind::CareTask => {
+            CARE_TASKS_STORAGE.with(|storage| storage.borrow().contains_key(&CareTaskId(id)))
+        }
+    }
+}
+
+// Entities that put dated events on their patient's timeline
+trait TimelineSource {
+    fn timeline_keys(&self) -> Vec<TimelineKey>;
+}
+
+fn timeline_key(
+    patient_id: PatientId,
+    at: u64,
+    event: TimelineEvent,
+    entity_id: u64,
+) -> TimelineKey {
+    (patient_id, (at, (event.code(), entity_id)))
+}
+
+// Events recorded by date fall back to when the row was created if the date is unusable
+fn timeline_date(date: &str, fallback: u64) -> u64 {
+    parse_date(date)
+        .and_then(timestamp_from_date)
+        .unwrap_or(fallback)
+}
+
+// Function to replace the timeline entries of an entity's previous version with its current ones
+fn update_timeline<T: TimelineSource>(previous: Option<&T>, current: Option<&T>) {
+    TIMELINE_BY_PATIENT.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in previous.map(T::timeline_keys).unwrap_or_default() {
+            index.remove(&key);
+        }
+        for key in current.map(T::timeline_keys).unwrap_or_default() {
+            index.insert(key, ());
+        }
+    });
+}
+
+impl TimelineSource for Appointment {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            self.date_time,
+            TimelineEvent::Appointment,
+            self.id.0,
+        )]
+    }
+}
+
+impl TimelineSource for PatientRecord {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            self.created_at,
+            TimelineEvent::PatientRecord,
+            self.id.0,
+        )]
+    }
+}
+
+// A medication's end is listed once the date is reached, which is checked when reading
+impl TimelineSource for Medication {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        let started = match &self.start_date {
+            Some(start_date) => timeline_date(start_date, self.created_at),
+            None => self.created_at,
+        };
+        let mut keys = vec![timeline_key(
+            self.patient_id,
+            started,
+            TimelineEvent::MedicationStarted,
+            self.id.0,
+        )];
+        if let Some(end_date) = self.end_date.as_deref().and_then(parse_date) {
+            keys.push(timeline_key(
+                self.patient_id,
+                timestamp_from_date(end_date).unwrap_or(self.created_at),
+                TimelineEvent::MedicationEnded,
+                self.id.0,
+            ));
+        }
+        keys
+    }
+}
+
+impl TimelineSource for Problem {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        let onset = match &self.onset_date {
+            Some(onset_date) => timeline_date(onset_date, self.created_at),
+            None => self.created_at,
+        };
+        let mut keys = vec![timeline_key(
+            self.patient_id,
+            onset,
+            TimelineEvent::ProblemOnset,
+            self.id.0,
+        )];
+        if let Some(resolved_date) = &self.resolved_date {
+            keys.push(timeline_key(
+                self.patient_id,
+                timeline_date(resolved_date, self.created_at),
+                TimelineEvent::ProblemResolved,
+                self.id.0,
+            ));
+        }
+        keys
+    }
+}
+
+impl TimelineSource for Allergy {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            self.created_at,
+            TimelineEvent::AllergyRecorded,
+            self.id.0,
+        )]
+    }
+}
+
+impl TimelineSource for Immunization {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            timeline_date(&self.administered_on, self.created_at),
+            TimelineEvent::Immunization,
+            self.id.0,
+        )]
+    }
+}
+
+impl TimelineSource for LabOrder {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            self.created_at,
+            TimelineEvent::LabOrdered,
+            self.id.0,
+        )]
+    }
+}
+
+// Drafts are not part of the history yet
+impl TimelineSource for ClinicalNote {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        self.signed_at
+            .map(|signed_at| {
+                timeline_key(
+                    self.patient_id,
+                    signed_at,
+                    TimelineEvent::ClinicalNoteSigned,
+                    self.id.0,
+                )
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+// Documents are listed once their upload is complete
+impl TimelineSource for Document {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        if self.status != DocumentStatus::Available {
+            return Vec::new();
+        }
+        vec![timeline_key(
+            self.patient_id,
+            self.created_at,
+            TimelineEvent::DocumentAdded,
+            self.id.0,
+        )]
+    }
+}
+
+impl TimelineSource for FamilyHistory {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            self.created_at,
+            TimelineEvent::FamilyHistoryRecorded,
+            self.id.0,
+        )]
+    }
+}
+
+// The social history is listed once, at its last update
+impl TimelineSource for SocialHistory {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            self.updated_at.unwrap_or(self.created_at),
+            TimelineEvent::SocialHistoryUpdated,
+            0,
+        )]
+    }
+}
+
+impl TimelineSource for CarePlan {
+    fn timeline_keys(&self) -> Vec<TimelineKey> {
+        vec![timeline_key(
+            self.patient_id,
+            timeline_date(&self.start_date, self.created_at),
+            TimelineEvent::CarePlanStarted,
+            self.id.0,
+        )]
+    }
+}
+
+// Writes of indexed entities go through these functions so the secondary indexes stay in step
+fn save_appointment(appointment: &Appointment) {
+    let previous = APPOINTMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(appointment.id, appointment.clone())
+    });
+    update_timeline(previous.as_ref(), Some(appointment));
+    if let Some(previous) = previous {
+        unindex_appointment(&previous);
+    }
+    APPOINTMENTS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((appointment.patient_id, appointment.id), ())
+    });
+    APPOINTMENTS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .insert((appointment.doctor_id, appointment.id), ())
+    });
+    APPOINTMENTS_BY_DATE.with(|index| {
+        index
+            .borrow_mut()
+            .insert((appointment.date_time, appointment.id), ())
+    });
+    if let Some(room_id) = appointment.room_id {
+        APPOINTMENTS_BY_ROOM.with(|index| {
+            index
+                .borrow_mut()
+                .insert((room_id, (appointment.date_time, appointment.id)), ())
+        });
+    }
+}
+
+fn remove_appointment(id: AppointmentId) -> Option<Appointment> {
+    let removed = APPOINTMENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(appointment) = &removed {
+        unindex_appointment(appointment);
+
+        // Observations outlive the appointment they were taken at
+        for observation_id in observation_ids_for_appointment(id) {
+            if let Some(mut observation) =
+                OBSERVATIONS_STORAGE.with(|storage| storage.borrow().get(&observation_id))
+            {
+                observation.appointment_id = None;
+                observation.updated_at = Some(current_time());
+                save_observation(&observation);
+            }
+        }
+        // and so do clinical notes, signed or not
+        for note_id in clinical_note_ids_for_appointment(id) {
+            if let Some(mut note) =
+                CLINICAL_NOTES_STORAGE.with(|storage| storage.borrow().get(&note_id))
+            {
+                note.appointment_id = None;
+                note.updated_at = Some(current_time());
+                save_clinical_note(&note);
+            }
+        }
+    }
+    removed
+}
+
+fn unindex_appointment(appointment: &Appointment) {
+    APPOINTMENTS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(appointment.patient_id, appointment.id))
+    });
+    APPOINTMENTS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(appointment.doctor_id, appointment.id))
+    });
+    APPOINTMENTS_BY_DATE.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(appointment.date_time, appointment.id))
+    });
+    if let Some(room_id) = appointment.room_id {
+        APPOINTMENTS_BY_ROOM.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(room_id, (appointment.date_time, appointment.id)))
+        });
+    }
+}
+
+fn save_patient_record(patient_record: &PatientRecord) {
+    let previous = PATIENT_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(patient_record.id, patient_record.clone())
+    });
+    update_timeline(previous.as_ref(), Some(patient_record));
+    if let Some(previous) = previous {
+        unindex_patient_record(&previous);
+    }
+    PATIENT_RECORDS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((patient_record.patient_id, patient_record.id), ())
+    });
+    PATIENT_RECORDS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .insert((patient_record.doctor_id, patient_record.id), ())
+    });
+    PATIENT_RECORDS_BY_MEDICATION.with(|index| {
+        let mut index = index.borrow_mut();
+        for medication_id in &patient_record.medications {
+            index.insert((*medication_id, patient_record.id), ());
+        }
+    });
+    PATIENT_RECORDS_BY_DIAGNOSIS.with(|index| {
+        let mut index = index.borrow_mut();
+        for code in &patient_record.coded_diagnoses {
+            index.insert((icd10_key(code), patient_record.id), ());
+        }
+    });
+}
+
+fn remove_patient_record(id: PatientRecordId) -> Option<PatientRecord> {
+    let removed = PATIENT_RECORDS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(patient_record) = &removed {
+        unindex_patient_record(patient_record);
+
+        // Problems outlive the records that added or resolved them
+        for problem_id in problem_ids_for_record(id) {
+            if let Some(mut problem) =
+                PROBLEMS_STORAGE.with(|storage| storage.borrow().get(&problem_id))
+            {
+                if problem.source_record_id == Some(id) {
+                    problem.source_record_id = None;
+                }
+                if problem.resolved_record_id == Some(id) {
+                    problem.resolved_record_id = None;
+                }
+                problem.updated_at = Some(current_time());
+                save_problem(&problem);
+            }
+        }
+    }
+    removed
+}
+
+fn unindex_patient_record(patient_record: &PatientRecord) {
+    PATIENT_RECORDS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(patient_record.patient_id, patient_record.id))
+    });
+    PATIENT_RECORDS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(patient_record.doctor_id, patient_record.id))
+    });
+    PATIENT_RECORDS_BY_MEDICATION.with(|index| {
+        let mut index = index.borrow_mut();
+        for medication_id in &patient_record.medications {
+            index.remove(&(*medication_id, patient_record.id));
+        }
+    });
+    PATIENT_RECORDS_BY_DIAGNOSIS.with(|index| {
+        let mut index = index.borrow_mut();
+        for code in &patient_record.coded_diagnoses {
+            index.remove(&(icd10_key(code), patient_record.id));
+        }
+    });
+}
+
+fn save_medication(medication: &Medication) {
+    let previous = MEDICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(medication.id, medication.clone())
+    });
+    update_timeline(previous.as_ref(), Some(medication));
+    if let Some(previous) = previous {
+        unindex_medication(&previous);
+    }
+    MEDICATIONS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((medication.patient_id, medication.id), ())
+    });
+    if let Some(doctor_id) = medication.doctor_id {
+        MEDICATIONS_BY_DOCTOR
+            .with(|index| index.borrow_mut().insert((doctor_id, medication.id), ()));
+    }
+}
+
+fn remove_medication(id: MedicationId) -> Option<Medication> {
+    let removed = MEDICATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(medication) = &removed {
+        unindex_medication(medication);
+    }
+    removed
+}
+
+fn unindex_medication(medication: &Medication) {
+    MEDICATIONS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(medication.patient_id, medication.id))
+    });
+    if let Some(doctor_id) = medication.doctor_id {
+        MEDICATIONS_BY_DOCTOR.with(|index| index.borrow_mut().remove(&(doctor_id, medication.id)));
+    }
+}
+
+fn save_doctor(doctor: &Doctor) {
+    let previous =
+        DOCTORS_STORAGE.with(|storage| storage.borrow_mut().insert(doctor.id, doctor.clone()));
+    if let Some(previous) = previous {
+        unindex_doctor(&previous);
+    }
+    DOCTORS_BY_SPECIALITY.with(|index| {
+        let mut index = index.borrow_mut();
+        for speciality in &doctor.specialities {
+            index.insert((speciality_key(speciality), doctor.id), ());
+        }
+    });
+    DOCTORS_BY_LANGUAGE.with(|index| {
+        let mut index = index.borrow_mut();
+        for language in &doctor.languages {
+            index.insert((language_key(language), doctor.id), ());
+        }
+    });
+}
+
+fn remove_doctor(id: DoctorId) -> Option<Doctor> {
+    let removed = DOCTORS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(doctor) = &removed {
+        unindex_doctor(doctor);
+        for department_id in department_ids_for_doctor(id) {
+            remove_department_member(department_id, id);
+        }
+    }
+    removed
+}
+
+fn add_department_member(department_id: DepartmentId, doctor_id: DoctorId) {
+    DEPARTMENT_DOCTORS.with(|index| index.borrow_mut().insert((department_id, doctor_id), ()));
+    DOCTOR_DEPARTMENTS.with(|index| index.borrow_mut().insert((doctor_id, department_id), ()));
+}
+
+fn remove_department_member(department_id: DepartmentId, doctor_id: DoctorId) -> bool {
+    DOCTOR_DEPARTMENTS.with(|index| index.borrow_mut().remove(&(doctor_id, department_id)));
+    DEPARTMENT_DOCTORS
+        .with(|index| index.borrow_mut().remove(&(department_id, doctor_id)))
+        .is_some()
+}
+
+fn save_department(department: &Department) {
+    let previous = DEPARTMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(department.id, department.clone())
+    });
+    if let Some(previous) = previous {
+        DEPARTMENTS_BY_FACILITY.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.facility_id, previous.id))
+        });
+    }
+    DEPARTMENTS_BY_FACILITY.with(|index| {
+        index
+            .borrow_mut()
+            .insert((department.facility_id, department.id), ())
+    });
+}
+
+fn remove_department(id: DepartmentId) -> Option<Department> {
+    let removed = DEPARTMENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(department) = &removed {
+        DEPARTMENTS_BY_FACILITY.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(department.facility_id, department.id))
+        });
+        for doctor_id in doctor_ids_for_department(id) {
+            remove_department_member(id, doctor_id);
+        }
+    }
+    removed
+}
+
+fn save_room(room: &Room) {
+    let previous = ROOMS_STORAGE.with(|storage| storage.borrow_mut().insert(room.id, room.clone()));
+    if let Some(previous) = previous {
+        ROOMS_BY_DEPARTMENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.department_id, previous.id))
+        });
+    }
+    ROOMS_BY_DEPARTMENT.with(|index| index.borrow_mut().insert((room.department_id, room.id), ()));
+}
+
+fn remove_room(id: RoomId) -> Option<Room> {
+    let removed = ROOMS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(room) = &removed {
+        ROOMS_BY_DEPARTMENT.with(|index| index.borrow_mut().remove(&(room.department_id, room.id)));
+    }
+    removed
+}
+
+fn unindex_doctor(doctor: &Doctor) {
+    DOCTORS_BY_SPECIALITY.with(|index| {
+        let mut index = index.borrow_mut();
+        for speciality in &doctor.specialities {
+            index.remove(&(speciality_key(speciality), doctor.id));
+        }
+    });
+    DOCTORS_BY_LANGUAGE.with(|index| {
+        let mut index = index.borrow_mut();
+        for language in &doctor.languages {
+            index.remove(&(language_key(language), doctor.id));
+        }
+    });
+}
+
+fn save_problem(problem: &Problem) {
+    let previous =
+        PROBLEMS_STORAGE.with(|storage| storage.borrow_mut().insert(problem.id, problem.clone()));
+    update_timeline(previous.as_ref(), Some(problem));
+    if let Some(previous) = previous {
+        unindex_problem(&previous);
+    }
+    PROBLEMS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((problem.patient_id, problem.id), ())
+    });
+    PROBLEMS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .insert((problem.recorded_by, problem.id), ())
+    });
+    PROBLEMS_BY_RECORD.with(|index| {
+        let mut index = index.borrow_mut();
+        for record_id in problem
+            .source_record_id
+            .iter()
+            .chain(problem.resolved_record_id.iter())
+        {
+            index.insert((*record_id, problem.id), ());
+        }
+    });
+}
+
+fn save_family_history(entry: &FamilyHistory) {
+    let previous =
+        FAMILY_HISTORY_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry.clone()));
+    update_timeline(previous.as_ref(), Some(entry));
+    if let Some(previous) = previous {
+        FAMILY_HISTORY_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    FAMILY_HISTORY_BY_PATIENT
+        .with(|index| index.borrow_mut().insert((entry.patient_id, entry.id), ()));
+}
+
+fn remove_family_history(id: FamilyHistoryId) -> Option<FamilyHistory> {
+    let removed = FAMILY_HISTORY_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(entry) = &removed {
+        FAMILY_HISTORY_BY_PATIENT
+            .with(|index| index.borrow_mut().remove(&(entry.patient_id, entry.id)));
+    }
+    removed
+}
+
+fn save_care_plan(plan: &CarePlan) {
+    let previous =
+        CARE_PLANS_STORAGE.with(|storage| storage.borrow_mut().insert(plan.id, plan.clone()));
+    update_timeline(previous.as_ref(), Some(plan));
+    if let Some(previous) = previous {
+        CARE_PLANS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+        CARE_PLANS_BY_DOCTOR.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.doctor_id, previous.id))
+        });
+    }
+    CARE_PLANS_BY_PATIENT.with(|index| index.borrow_mut().insert((plan.patient_id, plan.id), ()));
+    CARE_PLANS_BY_DOCTOR.with(|index| index.borrow_mut().insert((plan.doctor_id, plan.id), ()));
+}
+
+fn remove_care_plan(id: CarePlanId) -> Option<CarePlan> {
+    let removed = CARE_PLANS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(plan) = &removed {
+        CARE_PLANS_BY_PATIENT.with(|index| index.borrow_mut().remove(&(plan.patient_id, plan.id)));
+        CARE_PLANS_BY_DOCTOR.with(|index| index.borrow_mut().remove(&(plan.doctor_id, plan.id)));
+        for task_id in care_task_ids_for_plan(id) {
+            remove_care_task(task_id);
+        }
+    }
+    removed
+}
+
+fn save_care_task(task: &CareTask) {
+    let previous =
+        CARE_TASKS_STORAGE.with(|storage| storage.borrow_mut().insert(task.id, task.clone()));
+    if let Some(previous) = previous {
+        CARE_TASKS_BY_DOCTOR.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.assigned_to, previous.id))
+        });
+    }
+    CARE_TASKS_BY_PLAN.with(|index| index.borrow_mut().insert((task.care_plan_id, task.id), ()));
+    CARE_TASKS_BY_DOCTOR.with(|index| index.borrow_mut().insert((task.assigned_to, task.id), ()));
+}
+
+fn remove_care_task(id: CareTaskId) -> Option<CareTask> {
+    let removed = CARE_TASKS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(task) = &removed {
+        CARE_TASKS_BY_PLAN.with(|index| index.borrow_mut().remove(&(task.care_plan_id, task.id)));
+        CARE_TASKS_BY_DOCTOR.with(|index| index.borrow_mut().remove(&(task.assigned_to, task.id)));
+    }
+    removed
+}
+
+fn remove_problem(id: ProblemId) -> Option<Problem> {
+    let removed = PROBLEMS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(problem) = &removed {
+        unindex_problem(problem);
+    }
+    removed
+}
+
+fn unindex_problem(problem: &Problem) {
+    PROBLEMS_BY_PATIENT.with(|index| index.borrow_mut().remove(&(problem.patient_id, problem.id)));
+    PROBLEMS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(problem.recorded_by, problem.id))
+    });
+    PROBLEMS_BY_RECORD.with(|index| {
+        let mut index = index.borrow_mut();
+        for record_id in problem
+            .source_record_id
+            .iter()
+            .chain(problem.resolved_record_id.iter())
+        {
+            index.remove(&(*record_id, problem.id));
+        }
+    });
+}
+
+fn save_allergy(allergy: &Allergy) {
+    let previous =
+        ALLERGIES_STORAGE.with(|storage| storage.borrow_mut().insert(allergy.id, allergy.clone()));
+    update_timeline(previous.as_ref(), Some(allergy));
+    if let Some(previous) = previous {
+        ALLERGIES_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    ALLERGIES_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((allergy.patient_id, allergy.id), ())
+    });
+    ALLERGIES_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .insert((allergy.recorded_by, allergy.id), ())
+    });
+}
+
+fn remove_allergy(id: AllergyId) -> Option<Allergy> {
+    let removed = ALLERGIES_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(allergy) = &removed {
+        ALLERGIES_BY_PATIENT
+            .with(|index| index.borrow_mut().remove(&(allergy.patient_id, allergy.id)));
+        ALLERGIES_BY_DOCTOR.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(allergy.recorded_by, allergy.id))
+        });
+    }
+    removed
+}
+
+fn save_lab_order(order: &LabOrder) {
+    let previous =
+        LAB_ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, order.clone()));
+    update_timeline(previous.as_ref(), Some(order));
+    if let Some(previous) = previous {
+        LAB_ORDERS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    LAB_ORDERS_BY_PATIENT.with(|index| index.borrow_mut().insert((order.patient_id, order.id), ()));
+    LAB_ORDERS_BY_DOCTOR.with(|index| index.borrow_mut().insert((order.doctor_id, order.id), ()));
+    LAB_ORDERS_AWAITING_REVIEW.with(|index| {
+        let mut index = index.borrow_mut();
+        if order.status == LabOrderStatus::Resulted {
+            index.insert((order.doctor_id, order.id), ());
+        } else {
+            index.remove(&(order.doctor_id, order.id));
+        }
+    });
+}
+
+fn remove_lab_order(id: LabOrderId) -> Option<LabOrder> {
+    let removed = LAB_ORDERS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(order) = &removed {
+        LAB_ORDERS_BY_PATIENT
+            .with(|index| index.borrow_mut().remove(&(order.patient_id, order.id)));
+        LAB_ORDERS_BY_DOCTOR.with(|index| index.borrow_mut().remove(&(order.doctor_id, order.id)));
+        LAB_ORDERS_AWAITING_REVIEW
+            .with(|index| index.borrow_mut().remove(&(order.doctor_id, order.id)));
+        for result_id in lab_result_ids_for_order(id) {
+            remove_lab_result(result_id);
+        }
+    }
+    removed
+}
+
+fn save_lab_result(result: &LabResult) {
+    LAB_RESULTS_STORAGE.with(|storage| storage.borrow_mut().insert(result.id, result.clone()));
+    LAB_RESULTS_BY_ORDER.with(|index| index.borrow_mut().insert((result.order_id, result.id), ()));
+}
+
+fn remove_lab_result(id: LabResultId) -> Option<LabResult> {
+    let removed = LAB_RESULTS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(result) = &removed {
+        LAB_RESULTS_BY_ORDER.with(|index| index.borrow_mut().remove(&(result.order_id, result.id)));
+    }
+    removed
+}
+
+// Function to move an order to resulted after one of its results was added or changed
+fn mark_lab_order_resulted(order: &mut LabOrder) {
+    let now = current_time();
+    order.status = LabOrderStatus::Resulted;
+    order.resulted_at = Some(now);
+    order.reviewed_at = None;
+    order.updated_at = Some(now);
+    save_lab_order(order);
+}
+
+fn save_immunization(immunization: &Immunization) {
+    let previous = IMMUNIZATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(immunization.id, immunization.clone())
+    });
+    update_timeline(previous.as_ref(), Some(immunization));
+    if let Some(previous) = previous {
+        IMMUNIZATIONS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    IMMUNIZATIONS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((immunization.patient_id, immunization.id), ())
+    });
+    IMMUNIZATIONS_BY_DOCTOR.with(|index| {
+        index
+            .borrow_mut()
+            .insert((immunization.administered_by, immunization.id), ())
+    });
+}
+
+fn remove_immunization(id: ImmunizationId) -> Option<Immunization> {
+    let removed = IMMUNIZATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(immunization) = &removed {
+        IMMUNIZATIONS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(immunization.patient_id, immunization.id))
+        });
+        IMMUNIZATIONS_BY_DOCTOR.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(immunization.administered_by, immunization.id))
+        });
+    }
+    removed
+}
+
+fn save_clinical_note(note: &ClinicalNote) {
+    let previous =
+        CLINICAL_NOTES_STORAGE.with(|storage| storage.borrow_mut().insert(note.id, note.clone()));
+    update_timeline(previous.as_ref(), Some(note));
+    if let Some(previous) = previous {
+        CLINICAL_NOTES_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+        if let Some(appointment_id) = previous.appointment_id {
+            CLINICAL_NOTES_BY_APPOINTMENT
+                .with(|index| index.borrow_mut().remove(&(appointment_id, note.id)));
+        }
+    }
+    CLINICAL_NOTES_BY_PATIENT
+        .with(|index| index.borrow_mut().insert((note.patient_id, note.id), ()));
+    CLINICAL_NOTES_BY_DOCTOR.with(|index| index.borrow_mut().insert((note.doctor_id, note.id), ()));
+    if let Some(appointment_id) = note.appointment_id {
+        CLINICAL_NOTES_BY_APPOINTMENT
+            .with(|index| index.borrow_mut().insert((appointment_id, note.id), ()));
+    }
+}
+
+fn remove_clinical_note(id: ClinicalNoteId) -> Option<ClinicalNote> {
+    let removed = CLINICAL_NOTES_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(note) = &removed {
+        CLINICAL_NOTES_BY_PATIENT
+            .with(|index| index.borrow_mut().remove(&(note.patient_id, note.id)));
+        CLINICAL_NOTES_BY_DOCTOR
+            .with(|index| index.borrow_mut().remove(&(note.doctor_id, note.id)));
+        if let Some(appointment_id) = note.appointment_id {
+            CLINICAL_NOTES_BY_APPOINTMENT
+                .with(|index| index.borrow_mut().remove(&(appointment_id, note.id)));
+        }
+    }
+    removed
+}
+
+fn save_document(document: &Document) {
+    let previous = DOCUMENTS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(document.id, document.clone()));
+    update_timeline(previous.as_ref(), Some(document));
+    if let Some(previous) = previous {
+        DOCUMENTS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    DOCUMENTS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((document.patient_id, document.id), ())
+    });
+}
+
+// Removes the document's content too
+fn remove_document(id: DocumentId) -> Option<Document> {
+    let removed = DOCUMENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    update_timeline(removed.as_ref(), None);
+    if let Some(document) = &removed {
+        DOCUMENTS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(document.patient_id, document.id))
+        });
+        DOCUMENT_CONTENT.with(|content| {
+            let mut content = content.borrow_mut();
+            for index in 0..chunk_count(document.size) {
+                content.remove(&(id, index));
+            }
+        });
+    }
+    removed
+}
+
+fn save_observation(observation: &Observation) {
+    let previous = OBSERVATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(observation.id, observation.clone())
+    });
+    if let Some(previous) = previous {
+        unindex_observation(&previous);
+    }
+    OBSERVATIONS_BY_PATIENT_TYPE_TIME
+        .with(|index| index.borrow_mut().insert(observation_key(observation), ()));
+    if let Some(appointment_id) = observation.appointment_id {
+        OBSERVATIONS_BY_APPOINTMENT.with(|index| {
+            index
+                .borrow_mut()
+                .insert((appointment_id, observation.id), ())
+        });
+    }
+}
+
+fn remove_observation(id: ObservationId) -> Option<Observation> {
+    let removed = OBSERVATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(observation) = &removed {
+        unindex_observation(observation);
+    }
+    removed
+}
+
+fn unindex_observation(observation: &Observation) {
+    OBSERVATIONS_BY_PATIENT_TYPE_TIME
+        .with(|index| index.borrow_mut().remove(&observation_key(observation)));
+    if let Some(appointment_id) = observation.appointment_id {
+        OBSERVATIONS_BY_APPOINTMENT
+            .with(|index| index.borrow_mut().remove(&(appointment_id, observation.id)));
+    }
+}
+
+fn observation_key(observation: &Observation) -> ObservationKey {
+    (
+        (observation.patient_id, observation.observation_type.code()),
+        (observation.observed_at, observation.id),
+    )
+}
+
+fn save_icd10_code(code: Icd10Code) {
+    let key = icd10_key(&code.code);
+    let previous = ICD10_CODES.with(|codes| codes.borrow_mut().insert(key, code.clone()));
+    ICD10_CODES_BY_KEYWORD.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            for word in description_words(&previous.description) {
+                index.remove(&(keyword_key(&word), key));
+            }
+        }
+        for word in description_words(&code.description) {
+            index.insert((keyword_key(&word), key), ());
+        }
+    });
+}
+
+fn save_emergency_contact(emergency_contact: &EmergencyContact) {
+    let previous = EMERGENCY_CONTACTS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(emergency_contact.id, emergency_contact.clone())
+    });
+    if let Some(previous) = previous {
+        EMERGENCY_CONTACTS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    EMERGENCY_CONTACTS_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((emergency_contact.patient_id, emergency_contact.id), ())
+    });
+}
+
+fn remove_emergency_contact(id: EmergencyContactId) -> Option<EmergencyContact> {
+    let removed = EMERGENCY_CONTACTS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(emergency_contact) = &removed {
+        EMERGENCY_CONTACTS_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(emergency_contact.patient_id, emergency_contact.id))
+        });
+    }
+    removed
+}
+
+fn save_insurance_coverage(insurance_coverage: &InsuranceCoverage) {
+    let previous = INSURANCE_COVERAGES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(insurance_coverage.id, insurance_coverage.clone())
+    });
+    if let Some(previous) = previous {
+        INSURANCE_COVERAGES_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(previous.patient_id, previous.id))
+        });
+    }
+    INSURANCE_COVERAGES_BY_PATIENT.with(|index| {
+        index
+            .borrow_mut()
+            .insert((insurance_coverage.patient_id, insurance_coverage.id), ())
+    });
+}
+
+fn remove_insurance_coverage(id: InsuranceCoverageId) -> Option<InsuranceCoverage> {
+    let removed = INSURANCE_COVERAGES_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(insurance_coverage) = &removed {
+        INSURANCE_COVERAGES_BY_PATIENT.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(insurance_coverage.patient_id, insurance_coverage.id))
+        });
+    }
+    removed
+}
+
+fn save_patient(patient: &Patient) {
+    let previous =
+        PATIENTS_STORAGE.with(|storage| storage.borrow_mut().insert(patient.id, patient.clone()));
+    DUPLICATE_BLOCKS.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous {
+            for block in duplicate_blocking_keys(&previous) {
+                index.remove(&(block, previous.id));
+            }
+        }
+        for block in duplicate_blocking_keys(patient) {
+            index.insert((block, patient.id), ());
+        }
+    });
+}
+
+fn remove_patient(id: PatientId) -> Option<Patient> {
+    let removed = PATIENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(patient) = &removed {
+        DUPLICATE_BLOCKS.with(|index| {
+            let mut index = index.borrow_mut();
+            for block in duplicate_blocking_keys(patient) {
+                index.remove(&(block, id));
+            }
+        });
+    }
+    removed
+}
+
+fn save_social_history(social_history: &SocialHistory) {
+    let previous = SOCIAL_HISTORY_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(social_history.patient_id, social_history.clone())
+    });
+    update_timeline(previous.as_ref(), Some(social_history));
+}
+
+fn remove_social_history(patient_id: PatientId) -> Option<SocialHistory> {
+    let removed = SOCIAL_HISTORY_STORAGE.with(|storage| storage.borrow_mut().remove(&patient_id));
+    update_timeline(removed.as_ref(), None);
+    removed
+}
+
+fn save_patient_redirect(id: PatientId, redirect: PatientRedirect) {
+    let target = redirect.merged_into;
+    let previous = PATIENT_REDIRECTS.with(|redirects| redirects.borrow_mut().insert(id, redirect));
+    if let Some(previous) = previous {
+        PATIENT_REDIRECTS_BY_TARGET
+            .with(|index| index.borrow_mut().remove(&(previous.merged_into, id)));
+    }
+    PATIENT_REDIRECTS_BY_TARGET.with(|index| index.borrow_mut().insert((target, id), ()));
+}
+
+fn remove_patient_redirect(id: PatientId) -> Option<PatientRedirect> {
+    let removed = PATIENT_REDIRECTS.with(|redirects| redirects.borrow_mut().remove(&id));
+    if let Some(redirect) = &removed {
+        PATIENT_REDIRECTS_BY_TARGET
+            .with(|index| index.borrow_mut().remove(&(redirect.merged_into, id)));
+    }
+    removed
+}
+
+// Steps of the migration from the original layout, in the order they run. Medications come
+// before patient records, whose medication names are matched to them
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default,
+)]
+enum MigrationStep {
+    #[default]
+    Doctors,
+    Patients,
+    Medications,
+    PatientRecords,
+    Appointments,
+    Done,
+}
+
+impl MigrationStep {
+    fn next(self) -> Self {
+        match self {
+            MigrationStep::Doctors => MigrationStep::Patients,
+            MigrationStep::Patients => MigrationStep::Medications,
+            MigrationStep::Medications => MigrationStep::PatientRecords,
+            MigrationStep::PatientRecords => MigrationStep::Appointments,
+            MigrationStep::Appointments | MigrationStep::Done => MigrationStep::Done,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MigrationProgress {
+    step: MigrationStep,
+    // Appointments stay in memory 3 and are re-saved in id order after this one
+    last_appointment: Option<AppointmentId>,
+    migrated: u64,
+    rejected: u64,
+}
+
+impl Storable for MigrationProgress {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RejectedRow {
+    // Memory the row was read from: 1 doctors, 2 patients, 3 appointments, 4 patient records
+    // and 5 medications
+    memory_id: u8,
+    id: u64,
+    // Candid bytes as stored
+    bytes: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MigrationStatus {
+    schema_version: u32,
+    step: MigrationStep,
+    migrated: u64,
+    rejected: u64,
+    // The first rejected rows, by memory and id
+    rejected_rows: Vec<RejectedRow>,
+}
+
+// Function to run the next migration batch, then schedule the one after it in its own
+// message, so no single message has to move every row
+fn continue_migration() {
+    if run_migration_batch() {
+        STORED_SCHEMA_VERSION
+            .with(|version| version.borrow_mut().set(SCHEMA_VERSION))
+            .expect("Cannot set the schema version");
+    } else {
+        ic_cdk_timers::set_timer(Duration::ZERO, continue_migration);
+    }
+}
+
+fn migration_finished() -> bool {
+    STORED_SCHEMA_VERSION.with(|version| *version.borrow().get()) >= SCHEMA_VERSION
+}
+
+// Function to migrate up to MIGRATION_BATCH_SIZE rows from the original layout, returning
+// true once every row has been migrated or rejected
+// The bounded maps in memories 1, 2, 4 and 5 are drained as their rows move, so a batch
+// resumes from whatever rows are left; appointments resume after the last one re-saved
+fn run_migration_batch() -> bool {
+    let mut progress = MIGRATION_PROGRESS.with(|progress| progress.borrow().get().clone());
+    let mut remaining = MIGRATION_BATCH_SIZE;
+    while remaining > 0 && progress.step != MigrationStep::Done {
+        let taken = match progress.step {
+            MigrationStep::Doctors => {
+                drain_legacy_rows(1, remaining, &mut progress, migrate_legacy_doctor)
+            }
+            MigrationStep::Patients => {
+                drain_legacy_rows(2, remaining, &mut progress, migrate_legacy_patient)
+            }
+            MigrationStep::Medications => {
+                drain_legacy_rows(5, remaining, &mut progress, migrate_legacy_medication)
+            }
+            MigrationStep::PatientRecords => {
+                drain_legacy_rows(4, remaining, &mut progress, migrate_legacy_patient_record)
+            }
+            MigrationStep::Appointments => resave_appointments(remaining, &mut progress),
+            MigrationStep::Done => 0,
+        };
+        if taken < remaining {
+            progress.step = progress.step.next();
+        }
+        remaining -= taken;
+    }
+    let done = progress.step == MigrationStep::Done;
+    MIGRATION_PROGRESS
+        .with(|cell| cell.borrow_mut().set(progress))
+        .expect("Cannot save the migration progress");
+    done
+}
+
+// Raw view of a bounded map in the original layout, keyed by the untagged u64 ids of that time
+fn legacy_map(memory_id: u8) -> StableBTreeMap<u64, BoundedEntityBytes, Memory> {
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))))
+}
+
+// Function to move up to `limit` rows out of a bounded map in the original layout, passing
+// each decoded row to `migrate` and setting aside rows that do not decode
+// Returns the number of rows taken
+fn drain_legacy_rows<T>(
+    memory_id: u8,
+    limit: usize,
+    progress: &mut MigrationProgress,
+    migrate: fn(T),
+) -> usize
+where
+    T: candid::CandidType + serde::de::DeserializeOwned,
+{
+    let mut legacy = legacy_map(memory_id);
+    let rows: Vec<(u64, BoundedEntityBytes)> = legacy.iter().take(limit).collect();
+    for (id, bytes) in &rows {
+        legacy.remove(id);
+        match Decode!(bytes.as_slice(), T) {
+            Ok(row) => {
+                migrate(row);
+                progress.migrated += 1;
+            }
+            Err(_) => reject_migration_row(memory_id, *id, bytes, progress),
+        }
+    }
+    rows.len()
+}
+
+// Function to re-save up to `limit` appointments in place, filling in the indexes and the
+// timeline; appointments only gained optional fields, so the rows decode as they are
+fn resave_appointments(limit: usize, progress: &mut MigrationProgress) -> usize {
+    // Read the raw bytes, so a row that does not decode is set aside instead of trapping
+    let mut raw = legacy_map(3);
+    let start = progress
+        .last_appointment
+        .map_or(Bound::Unbounded, |id| Bound::Excluded(id.0));
+    let rows: Vec<(u64, BoundedEntityBytes)> =
+        raw.range((start, Bound::Unbounded)).take(limit).collect();
+    let mut appointments = Vec::with_capacity(rows.len());
+    let mut rejected = false;
+    for (id, bytes) in &rows {
+        match Decode!(bytes.as_slice(), Appointment) {
+            Ok(appointment) => appointments.push(appointment),
+            Err(_) => {
+                raw.remove(id);
+                reject_migration_row(3, *id, bytes, progress);
+                rejected = true;
+            }
+        }
+    }
+    if rejected {
+        // The typed map caches the tree's root and length, so it has to see the removals
+        APPOINTMENTS_STORAGE.with(|storage| {
+            *storage.borrow_mut() =
+                StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+        });
+    }
+    for appointment in &appointments {
+        save_appointment(appointment);
+        progress.migrated += 1;
+    }
+    if let Some((id, _)) = rows.last() {
+        progress.last_appointment = Some(AppointmentId(*id));
+    }
+    rows.len()
+}
+
+fn reject_migration_row(
+    memory_id: u8,
+    id: u64,
+    bytes: &BoundedEntityBytes,
+    progress: &mut MigrationProgress,
+) {
+    MIGRATION_REJECTS.with(|rejects| rejects.borrow_mut().insert((memory_id, id), *bytes));
+    progress.rejected += 1;
+}
+
+// Function to report how far the migration from the original layout has got, and the rows
+// it could not decode
+#[ic_cdk::query]
+fn get_migration_status() -> Result<MigrationStatus, Message> {
+    ensure_controller()?;
+    let progress = MIGRATION_PROGRESS.with(|progress| progress.borrow().get().clone());
+    let rejected_rows = MIGRATION_REJECTS.with(|rejects| {
+        rejects
+            .borrow()
+            .iter()
+            .take(MAX_SEARCH_RESULTS)
+            .map(|((memory_id, id), bytes)| RejectedRow {
+                memory_id,
+                id,
+                bytes: bytes.as_slice().to_vec(),
+            })
+            .collect()
+    });
+    Ok(MigrationStatus {
+        schema_version: STORED_SCHEMA_VERSION.with(|version| *version.borrow().get()),
+        step: progress.step,
+        migrated: progress.migrated,
+        rejected: progress.rejected,
+        rejected_rows,
+    })
+}
+
+// Doctor layout in memory 1
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyDoctor {
+    id: DoctorId,
+    name: String,
+    speciality: String,
+    created_at: u64,
+}
+
+// Function to convert a legacy doctor, adding its free-text speciality to the catalogue
+fn migrate_legacy_doctor(old: LegacyDoctor) {
+    let doctor = Doctor {
+        id: old.id,
+        name: old.name,
+        specialities: add_legacy_speciality(&old.speciality).into_iter().collect(),
+        created_at: old.created_at,
+        ..Default::default()
+    };
+    save_doctor(&doctor);
+}
+
+// Returns the catalogue name of a legacy speciality, adding it to the catalogue if missing
+fn add_legacy_speciality(name: &str) -> Option<String> {
+    let name = name.trim();
+    let key = normalize_words(name);
+    if key.is_empty() || key.len() > MAX_LABEL_LENGTH {
+        return None;
+    }
+    if let Some(speciality) = find_speciality(name) {
+        return Some(speciality.name);
+    }
+    let speciality = Speciality {
+        name: name.to_string(),
+        description: String::new(),
+    };
+    SPECIALITIES.with(|catalogue| {
+        catalogue
+            .borrow_mut()
+            .insert(speciality_key(name), speciality)
+    });
+    Some(name.to_string())
+}
+
+// Patient layout in memory 2
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyPatient {
+    id: PatientId,
+    name: String,
+    age: u32,
+    gender: String,
+    created_at: u64,
+}
+
+// Function to convert a legacy patient, estimating the birth year from the recorded age
+fn migrate_legacy_patient(old: LegacyPatient) {
+    let current_year = current_date().year();
+    let (sex, gender_identity) = match old.gender.trim().to_lowercase().as_str() {
+        "female" | "f" => (Sex::Female, None),
+        "male" | "m" => (Sex::Male, None),
+        "" => (Sex::Unknown, None),
+        _ => (
+            Sex::Unknown,
+            Some(GenderIdentity::Other(old.gender.clone())),
+        ),
+    };
+    // Ages beyond what a valid date of birth allows are capped, giving 1900-01-01
+    let age = old.age.min((current_year - MIN_BIRTH_YEAR) as u32);
     let patient = Patient {
-        id,
-        name: payload.name,
-        age: payload.age,
-        gender: payload.gender,
-        created_at: current_time(),
+        id: old.id,
+        name: old.name,
+        date_of_birth: format!("{:04}-01-01", current_year - age as i32),
+        date_of_birth_estimated: true,
+        age,
+        sex,
+        gender_identity,
+        created_at: old.created_at,
+        ..Default::default()
+    };
+    save_patient(&patient);
+}
+
+// Patient record layout in memory 4
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyPatientRecord {
+    id: PatientRecordId,
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    diagnosis: String,
+    treatment: String,
+    medications: Vec<String>,
+    created_at: u64,
+}
+
+// Function to convert a legacy patient record, replacing its medication names with
+// Medication ids: each name is matched to the patient's medications, and a medication is
+// created for a name with no match
+fn migrate_legacy_patient_record(old: LegacyPatientRecord) {
+    let mut medications: Vec<MedicationId> = Vec::new();
+    for name in old.medications {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            continue;
+        }
+        let existing =
+            medication_ids_for_patient(old.patient_id)
+                .into_iter()
+                .find(|medication_id| {
+                    matches!(
+                        MEDICATIONS_STORAGE.with(|storage| storage.borrow().get(medication_id)),
+                        Some(medication) if medication.name.eq_ignore_ascii_case(&name)
+                    )
+                });
+        let medication_id = existing.unwrap_or_else(|| {
+            let medication = Medication {
+                id: MedicationId(next_id(EntityKind::Medication)),
+                name,
+                patient_id: old.patient_id,
+                doctor_id: Some(old.doctor_id),
+                created_at: old.created_at,
+                ..Default::default()
+            };
+            save_medication(&medication);
+            medication.id
+        });
+        if !medications.contains(&medication_id) {
+            medications.push(medication_id);
+        }
+    }
+    save_patient_record(&PatientRecord {
+        id: old.id,
+        patient_id: old.patient_id,
+        doctor_id: old.doctor_id,
+        diagnosis: old.diagnosis,
+        coded_diagnoses: Vec::new(),
+        treatment: old.treatment,
+        medications,
+        created_at: old.created_at,
+    });
+}
+
+// Medication layout in memory 5
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyMedication {
+    id: MedicationId,
+    name: String,
+    dosage: String,
+    frequency: String,
+    patient_id: PatientId,
+    created_at: u64,
+}
+
+// Function to convert a legacy medication, parsing its free-text dosing into structured
+// fields and keeping the original text
+fn migrate_legacy_medication(old: LegacyMedication) {
+    let medication = Medication {
+        id: old.id,
+        name: old.name,
+        dose: parse_legacy_dose(&old.dosage),
+        route: Route::Unknown,
+        frequency: parse_legacy_frequency(&old.frequency),
+        start_date: None,
+        end_date: None,
+        patient_id: old.patient_id,
+        doctor_id: None,
+        indication: None,
+        legacy_dosage: Some(old.dosage),
+        legacy_frequency: Some(old.frequency),
+        allergy_override: None,
+        created_at: old.created_at,
+        updated_at: None,
+    };
+    save_medication(&medication);
+}
+
+// Function to parse a frequency code: QD, BID, TID, QID, PRN or qNh with N from 1 to 72
+fn parse_frequency_code(code: &str) -> Result<Frequency, Message> {
+    let code = code.trim().to_uppercase();
+    let frequency = match code.as_str() {
+        "QD" => Some(Frequency::OnceDaily),
+        "BID" => Some(Frequency::TwiceDaily),
+        "TID" => Some(Frequency::ThreeTimesDaily),
+        "QID" => Some(Frequency::FourTimesDaily),
+        "PRN" => Some(Frequency::AsNeeded),
+        _ => code
+            .strip_prefix('Q')
+            .and_then(|rest| rest.strip_suffix('H'))
+            .filter(|hours| !hours.is_empty() && hours.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|hours| hours.parse::<u32>().ok())
+            .filter(|hours| (1..=72).contains(hours))
+            .map(Frequency::EveryHours),
+    };
+    frequency.ok_or(Message::InvalidPayload(
+        "'frequency' must be one of QD, BID, TID, QID, PRN or qNh (for example q8h).".to_string(),
+    ))
+}
+
+// Lenient parser for legacy text such as "twice a day"; None when the text is ambiguous
+fn parse_legacy_frequency(text: &str) -> Option<Frequency> {
+    if let Ok(frequency) = parse_frequency_code(text) {
+        return Some(frequency);
+    }
+    let text = text.trim().to_lowercase().replace(['-', '.'], " ");
+    let words: Vec<&str> = text.split_whitespace().collect();
+    // Dotted abbreviations such as "b.i.d." are written without the dots
+    let text = if words.iter().all(|word| word.len() == 1) {
+        words.concat()
+    } else {
+        words.join(" ")
+    };
+    if let Ok(frequency) = parse_frequency_code(&text) {
+        return Some(frequency);
+    }
+    match text.as_str() {
+        "od" | "daily" | "once daily" | "once a day" | "once per day" | "every day"
+        | "1x daily" | "1 time a day" | "one time a day" => Some(Frequency::OnceDaily),
+        "bd" | "twice daily" | "twice a day" | "twice per day" | "2x daily" | "2 times a day"
+        | "two times a day" => Some(Frequency::TwiceDaily),
+        "tds" | "thrice daily" | "three times daily" | "three times a day" | "3x daily"
+        | "3 times a day" => Some(Frequency::ThreeTimesDaily),
+        "qds" | "four times daily" | "four times a day" | "4x daily" | "4 times a day" => {
+            Some(Frequency::FourTimesDaily)
+        }
+        "as needed" | "when needed" | "as required" | "if needed" => Some(Frequency::AsNeeded),
+        "every hour" | "hourly" => Some(Frequency::EveryHours(1)),
+        _ => match words.as_slice() {
+            ["every", hours, "hours" | "hrs" | "hr" | "h"] => hours
+                .parse::<u32>()
+                .ok()
+                .filter(|hours| (1..=72).contains(hours))
+                .map(Frequency::EveryHours),
+            _ => None,
+        },
+    }
+}
+
+// Lenient parser for legacy dosages such as "500mg" or "2 tablets"
+fn parse_legacy_dose(text: &str) -> Option<Dose> {
+    let text = text.trim().to_lowercase();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let quantity = text[..split].parse::<f64>().ok().filter(|q| *q > 0.0)?;
+    let unit = match text[split..].trim() {
+        "mg" | "milligram" | "milligrams" => DoseUnit::Mg,
+        "g" | "gram" | "grams" => DoseUnit::G,
+        "mcg" | "ug" | "µg" | "microgram" | "micrograms" => DoseUnit::Mcg,
+        "ml" | "milliliter" | "milliliters" | "millilitre" | "millilitres" => DoseUnit::Ml,
+        "iu" | "u" | "unit" | "units" => DoseUnit::Unit,
+        "tab" | "tabs" | "tablet" | "tablets" => DoseUnit::Tablet,
+        "cap" | "caps" | "capsule" | "capsules" => DoseUnit::Capsule,
+        "drop" | "drops" => DoseUnit::Drop,
+        "puff" | "puffs" => DoseUnit::Puff,
+        "patch" | "patches" => DoseUnit::Patch,
+        _ => return None,
+    };
+    Some(Dose { quantity, unit })
+}
+
+// Index lookups return ids in key order
+fn redirect_ids_to(patient_id: PatientId) -> Vec<PatientId> {
+    PATIENT_REDIRECTS_BY_TARGET.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, PatientId(0))..=(patient_id, PatientId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn appointment_ids_for_patient(patient_id: PatientId) -> Vec<AppointmentId> {
+    APPOINTMENTS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, AppointmentId(0))..=(patient_id, AppointmentId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn appointment_ids_for_doctor(doctor_id: DoctorId) -> Vec<AppointmentId> {
+    APPOINTMENTS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, AppointmentId(0))..=(doctor_id, AppointmentId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn patient_record_ids_for_patient(patient_id: PatientId) -> Vec<PatientRecordId> {
+    PATIENT_RECORDS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, PatientRecordId(0))..=(patient_id, PatientRecordId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn patient_record_ids_for_doctor(doctor_id: DoctorId) -> Vec<PatientRecordId> {
+    PATIENT_RECORDS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, PatientRecordId(0))..=(doctor_id, PatientRecordId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn patient_record_ids_for_medication(medication_id: MedicationId) -> Vec<PatientRecordId> {
+    PATIENT_RECORDS_BY_MEDICATION.with(|index| {
+        index
+            .borrow()
+            .range((medication_id, PatientRecordId(0))..=(medication_id, PatientRecordId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn medication_ids_for_patient(patient_id: PatientId) -> Vec<MedicationId> {
+    MEDICATIONS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, MedicationId(0))..=(patient_id, MedicationId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn medication_ids_for_doctor(doctor_id: DoctorId) -> Vec<MedicationId> {
+    MEDICATIONS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, MedicationId(0))..=(doctor_id, MedicationId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn emergency_contact_ids_for_patient(patient_id: PatientId) -> Vec<EmergencyContactId> {
+    EMERGENCY_CONTACTS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, EmergencyContactId(0))..=(patient_id, EmergencyContactId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn insurance_coverage_ids_for_patient(patient_id: PatientId) -> Vec<InsuranceCoverageId> {
+    INSURANCE_COVERAGES_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range(
+                (patient_id, InsuranceCoverageId(0))..=(patient_id, InsuranceCoverageId(u64::MAX)),
+            )
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn doctor_ids_for_speciality(speciality: SpecialityKey) -> Vec<DoctorId> {
+    DOCTORS_BY_SPECIALITY.with(|index| {
+        index
+            .borrow()
+            .range((speciality, DoctorId(0))..=(speciality, DoctorId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn doctor_ids_for_language(language: LanguageKey) -> Vec<DoctorId> {
+    DOCTORS_BY_LANGUAGE.with(|index| {
+        index
+            .borrow()
+            .range((language, DoctorId(0))..=(language, DoctorId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn department_ids_for_facility(facility_id: FacilityId) -> Vec<DepartmentId> {
+    DEPARTMENTS_BY_FACILITY.with(|index| {
+        index
+            .borrow()
+            .range((facility_id, DepartmentId(0))..=(facility_id, DepartmentId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn room_ids_for_department(department_id: DepartmentId) -> Vec<RoomId> {
+    ROOMS_BY_DEPARTMENT.with(|index| {
+        index
+            .borrow()
+            .range((department_id, RoomId(0))..=(department_id, RoomId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn doctor_ids_for_department(department_id: DepartmentId) -> Vec<DoctorId> {
+    DEPARTMENT_DOCTORS.with(|index| {
+        index
+            .borrow()
+            .range((department_id, DoctorId(0))..=(department_id, DoctorId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn department_ids_for_doctor(doctor_id: DoctorId) -> Vec<DepartmentId> {
+    DOCTOR_DEPARTMENTS.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, DepartmentId(0))..=(doctor_id, DepartmentId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+// Appointments in a room starting in [from, to), ordered by start time
+fn appointment_ids_for_room(room_id: RoomId, from: u64, to: u64) -> Vec<AppointmentId> {
+    APPOINTMENTS_BY_ROOM.with(|index| {
+        index
+            .borrow()
+            .range((room_id, (from, AppointmentId(0)))..(room_id, (to, AppointmentId(0))))
+            .map(|((_, (_, id)), _)| id)
+            .collect()
+    })
+}
+
+fn problem_ids_for_patient(patient_id: PatientId) -> Vec<ProblemId> {
+    PROBLEMS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, ProblemId(0))..=(patient_id, ProblemId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn problem_ids_for_doctor(doctor_id: DoctorId) -> Vec<ProblemId> {
+    PROBLEMS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, ProblemId(0))..=(doctor_id, ProblemId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn problem_ids_for_record(record_id: PatientRecordId) -> Vec<ProblemId> {
+    PROBLEMS_BY_RECORD.with(|index| {
+        index
+            .borrow()
+            .range((record_id, ProblemId(0))..=(record_id, ProblemId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_problems(patient_id: PatientId) -> Vec<Problem> {
+    problem_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| PROBLEMS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+fn family_history_ids_for_patient(patient_id: PatientId) -> Vec<FamilyHistoryId> {
+    FAMILY_HISTORY_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, FamilyHistoryId(0))..=(patient_id, FamilyHistoryId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_family_history(patient_id: PatientId) -> Vec<FamilyHistory> {
+    family_history_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| FAMILY_HISTORY_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+fn care_plan_ids_for_patient(patient_id: PatientId) -> Vec<CarePlanId> {
+    CARE_PLANS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, CarePlanId(0))..=(patient_id, CarePlanId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn care_plan_ids_for_doctor(doctor_id: DoctorId) -> Vec<CarePlanId> {
+    CARE_PLANS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, CarePlanId(0))..=(doctor_id, CarePlanId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn care_task_ids_for_plan(care_plan_id: CarePlanId) -> Vec<CareTaskId> {
+    CARE_TASKS_BY_PLAN.with(|index| {
+        index
+            .borrow()
+            .range((care_plan_id, CareTaskId(0))..=(care_plan_id, CareTaskId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn care_task_ids_for_doctor(doctor_id: DoctorId) -> Vec<CareTaskId> {
+    CARE_TASKS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, CareTaskId(0))..=(doctor_id, CareTaskId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_care_plans(patient_id: PatientId) -> Vec<CarePlan> {
+    care_plan_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| CARE_PLANS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+// Function to load care tasks, earliest due first
+fn load_care_tasks(ids: Vec<CareTaskId>) -> Vec<CareTask> {
+    let mut tasks: Vec<CareTask> = ids
+        .into_iter()
+        .filter_map(|id| CARE_TASKS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect();
+    tasks.sort_by(|a, b| a.due_date.cmp(&b.due_date).then(a.id.cmp(&b.id)));
+    tasks
+}
+
+// Function to load a patient's family history with each relative's entries together
+fn load_family_history_by_relative(patient_id: PatientId) -> Vec<FamilyHistory> {
+    let mut entries = load_family_history(patient_id);
+    entries.sort_by_key(|entry| relative_order(&entry.relative));
+    entries
+}
+
+// Parents first, then siblings, children, grandparents and the wider family
+fn relative_order(relative: &Relative) -> u8 {
+    match relative {
+        Relative::Mother => 0,
+        Relative::Father => 1,
+        Relative::Sister => 2,
+        Relative::Brother => 3,
+        Relative::Daughter => 4,
+        Relative::Son => 5,
+        Relative::MaternalGrandmother => 6,
+        Relative::MaternalGrandfather => 7,
+        Relative::PaternalGrandmother => 8,
+        Relative::PaternalGrandfather => 9,
+        Relative::Aunt => 10,
+        Relative::Uncle => 11,
+        Relative::Cousin => 12,
+        Relative::Other(_) => 13,
+    }
+}
+
+fn allergy_ids_for_patient(patient_id: PatientId) -> Vec<AllergyId> {
+    ALLERGIES_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, AllergyId(0))..=(patient_id, AllergyId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn allergy_ids_for_doctor(doctor_id: DoctorId) -> Vec<AllergyId> {
+    ALLERGIES_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, AllergyId(0))..=(doctor_id, AllergyId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn lab_order_ids_for_patient(patient_id: PatientId) -> Vec<LabOrderId> {
+    LAB_ORDERS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, LabOrderId(0))..=(patient_id, LabOrderId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn lab_order_ids_for_doctor(doctor_id: DoctorId) -> Vec<LabOrderId> {
+    LAB_ORDERS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, LabOrderId(0))..=(doctor_id, LabOrderId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn lab_result_ids_for_order(order_id: LabOrderId) -> Vec<LabResultId> {
+    LAB_RESULTS_BY_ORDER.with(|index| {
+        index
+            .borrow()
+            .range((order_id, LabResultId(0))..=(order_id, LabResultId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_lab_order(id: LabOrderId) -> Result<LabOrder, Message> {
+    ensure_id_kind(id.0, EntityKind::LabOrder)?;
+    LAB_ORDERS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Lab order not found".to_string()))
+}
+
+fn load_lab_results(order_id: LabOrderId) -> Vec<LabResult> {
+    lab_result_ids_for_order(order_id)
+        .into_iter()
+        .filter_map(|id| LAB_RESULTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+fn immunization_ids_for_patient(patient_id: PatientId) -> Vec<ImmunizationId> {
+    IMMUNIZATIONS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, ImmunizationId(0))..=(patient_id, ImmunizationId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn immunization_ids_for_doctor(doctor_id: DoctorId) -> Vec<ImmunizationId> {
+    IMMUNIZATIONS_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, ImmunizationId(0))..=(doctor_id, ImmunizationId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_immunizations(patient_id: PatientId) -> Vec<Immunization> {
+    immunization_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| IMMUNIZATIONS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+fn clinical_note_ids_for_patient(patient_id: PatientId) -> Vec<ClinicalNoteId> {
+    CLINICAL_NOTES_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, ClinicalNoteId(0))..=(patient_id, ClinicalNoteId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn clinical_note_ids_for_doctor(doctor_id: DoctorId) -> Vec<ClinicalNoteId> {
+    CLINICAL_NOTES_BY_DOCTOR.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, ClinicalNoteId(0))..=(doctor_id, ClinicalNoteId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn clinical_note_ids_for_appointment(appointment_id: AppointmentId) -> Vec<ClinicalNoteId> {
+    CLINICAL_NOTES_BY_APPOINTMENT.with(|index| {
+        index
+            .borrow()
+            .range((appointment_id, ClinicalNoteId(0))..=(appointment_id, ClinicalNoteId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_draft_note(id: ClinicalNoteId) -> Result<ClinicalNote, Message> {
+    let note = get_clinical_note_by_id(id)?;
+    if note.status != NoteStatus::Draft {
+        return Err(Message::Error(
+            "A signed clinical note cannot be changed.".to_string(),
+        ));
+    }
+    Ok(note)
+}
+
+fn document_ids_for_patient(patient_id: PatientId) -> Vec<DocumentId> {
+    DOCUMENTS_BY_PATIENT.with(|index| {
+        index
+            .borrow()
+            .range((patient_id, DocumentId(0))..=(patient_id, DocumentId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn load_document(id: DocumentId) -> Result<Document, Message> {
+    ensure_id_kind(id.0, EntityKind::Document)?;
+    DOCUMENTS_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Message::NotFound("Document not found".to_string()))
+}
+
+fn observation_ids_for_patient(patient_id: PatientId) -> Vec<ObservationId> {
+    OBSERVATIONS_BY_PATIENT_TYPE_TIME.with(|index| {
+        index
+            .borrow()
+            .range(
+                ((patient_id, 0), (0, ObservationId(0)))
+                    ..=((patient_id, u8::MAX), (u64::MAX, ObservationId(u64::MAX))),
+            )
+            .map(|((_, (_, id)), _)| id)
+            .collect()
+    })
+}
+
+fn observation_ids_between(
+    patient_id: PatientId,
+    observation_type: ObservationType,
+    from: u64,
+    to: u64,
+) -> Vec<ObservationId> {
+    let series = (patient_id, observation_type.code());
+    OBSERVATIONS_BY_PATIENT_TYPE_TIME.with(|index| {
+        index
+            .borrow()
+            .range((series, (from, ObservationId(0)))..=(series, (to, ObservationId(u64::MAX))))
+            .map(|((_, (_, id)), _)| id)
+            .collect()
+    })
+}
+
+fn observation_ids_for_appointment(appointment_id: AppointmentId) -> Vec<ObservationId> {
+    OBSERVATIONS_BY_APPOINTMENT.with(|index| {
+        index
+            .borrow()
+            .range((appointment_id, ObservationId(0))..=(appointment_id, ObservationId(u64::MAX)))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+// The greatest key below the end of the series is the latest observation in it
+fn latest_observation(
+    patient_id: PatientId,
+    observation_type: ObservationType,
+) -> Option<Observation> {
+    let series = (patient_id, observation_type.code());
+    let bound = (series, (u64::MAX, ObservationId(u64::MAX)));
+    let latest = OBSERVATIONS_BY_PATIENT_TYPE_TIME.with(|index| {
+        index
+            .borrow()
+            .iter_upper_bound(&bound)
+            .next()
+            .filter(|((key_series, _), _)| *key_series == series)
+            .map(|((_, (_, id)), _)| id)
+    })?;
+    OBSERVATIONS_STORAGE.with(|storage| storage.borrow().get(&latest))
+}
+
+fn first_observation_since(
+    patient_id: PatientId,
+    observation_type: ObservationType,
+    since: u64,
+) -> Option<Observation> {
+    let series = (patient_id, observation_type.code());
+    let first = OBSERVATIONS_BY_PATIENT_TYPE_TIME.with(|index| {
+        index
+            .borrow()
+            .range(
+                (series, (since, ObservationId(0)))..=(series, (u64::MAX, ObservationId(u64::MAX))),
+            )
+            .next()
+            .map(|((_, (_, id)), _)| id)
+    })?;
+    OBSERVATIONS_STORAGE.with(|storage| storage.borrow().get(&first))
+}
+
+fn load_appointments(ids: Vec<AppointmentId>) -> Vec<Appointment> {
+    ids.into_iter()
+        .filter_map(|id| APPOINTMENTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+fn load_allergies(patient_id: PatientId) -> Vec<Allergy> {
+    allergy_ids_for_patient(patient_id)
+        .into_iter()
+        .filter_map(|id| ALLERGIES_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .collect()
+}
+
+fn load_emergency_contacts(patient_id: PatientId) -> Vec<EmergencyContact> {
+    let mut emergency_contacts: Vec<EmergencyContact> =
+        emergency_contact_ids_for_patient(patient_id)
+            .into_iter()
+            .filter_map(|id| EMERGENCY_CONTACTS_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .collect();
+    emergency_contacts.sort_by_key(|emergency_contact| emergency_contact.priority);
+    emergency_contacts
+}
+
+fn load_insurance_coverages(patient_id: PatientId) -> Vec<InsuranceCoverage> {
+    let mut insurance_coverages: Vec<InsuranceCoverage> =
+        insurance_coverage_ids_for_patient(patient_id)
+            .into_iter()
+            .filter_map(|id| INSURANCE_COVERAGES_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .collect();
+    insurance_coverages.sort_by_key(|insurance_coverage| insurance_coverage.order);
+    insurance_coverages
+}
+
+// Function to work out a patient's coverage on a given date
+fn check_eligibility_on(patient_id: PatientId, date: NaiveDate) -> Eligibility {
+    let mut covered = None;
+    let mut expired: Option<(NaiveDate, InsuranceCoverageId)> = None;
+    let mut upcoming: Option<(NaiveDate, InsuranceCoverageId)> = None;
+    // Coverages are sorted by order, so the first one in effect wins
+    for insurance_coverage in load_insurance_coverages(patient_id) {
+        // Dates are validated on write
+        let effective_date = match parse_date(&insurance_coverage.effective_date) {
+            Some(effective_date) => effective_date,
+            None => continue,
+        };
+        let expiry_date = insurance_coverage
+            .expiry_date
+            .as_deref()
+            .and_then(parse_date);
+        if effective_date > date {
+            if !matches!(upcoming, Some((start, _)) if start <= effective_date) {
+                upcoming = Some((effective_date, insurance_coverage.id));
+            }
+        } else if let Some(expiry_date) = expiry_date.filter(|expiry_date| *expiry_date < date) {
+            if !matches!(expired, Some((end, _)) if end >= expiry_date) {
+                expired = Some((expiry_date, insurance_coverage.id));
+            }
+        } else if covered.is_none() {
+            covered = Some(insurance_coverage.id);
+        }
+    }
+
+    match (covered, expired, upcoming) {
+        (Some(id), _, _) => Eligibility::Covered(id),
+        (None, Some((_, id)), _) => Eligibility::Expired(id),
+        (None, None, Some((_, id))) => Eligibility::NotYetEffective(id),
+        (None, None, None) => Eligibility::NoCoverage,
+    }
+}
+
+// Function to work out which dependents a patient deletion would remove
+fn plan_patient_delete(
+    id: PatientId,
+    options: &PatientDeleteOptions,
+) -> Result<DeleteImpact, Message> {
+    ensure_patient_exists(id)?;
+
+    let appointments = appointment_ids_for_patient(id);
+    let patient_records = patient_record_ids_for_patient(id);
+    let medications = medication_ids_for_patient(id);
+    let clinical_notes = clinical_note_ids_for_patient(id);
+
+    Ok(DeleteImpact {
+        removed_emergency_contacts: resolve_dependents(
+            "emergency contacts",
+            emergency_contact_ids_for_patient(id),
+            options.emergency_contacts,
+        )?,
+        removed_insurance_coverages: resolve_dependents(
+            "insurance coverages",
+            insurance_coverage_ids_for_patient(id),
+            options.insurance_coverages,
+        )?,
+        removed_allergies: resolve_dependents(
+            "allergies",
+            allergy_ids_for_patient(id),
+            options.allergies,
+        )?,
+        removed_observations: resolve_dependents(
+            "observations",
+            observation_ids_for_patient(id),
+            options.observations,
+        )?,
+        removed_lab_orders: resolve_dependents(
+            "lab orders",
+            lab_order_ids_for_patient(id),
+            options.lab_orders,
+        )?,
+        removed_immunizations: resolve_dependents(
+            "immunizations",
+            immunization_ids_for_patient(id),
+            options.immunizations,
+        )?,
+        removed_appointments: resolve_dependents(
+            "appointments",
+            appointments,
+            options.appointments,
+        )?,
+        removed_patient_records: resolve_dependents(
+            "patient records",
+            patient_records,
+            options.patient_records,
+        )?,
+        removed_medications: resolve_dependents("medications", medications, options.medications)?,
+        removed_clinical_notes: resolve_dependents(
+            "clinical notes",
+            clinical_notes,
+            options.clinical_notes,
+        )?,
+        removed_documents: resolve_dependents(
+            "documents",
+            document_ids_for_patient(id),
+            options.documents,
+        )?,
+        removed_problems: resolve_dependents(
+            "problems",
+            problem_ids_for_patient(id),
+            options.problems,
+        )?,
+        removed_family_history: resolve_dependents(
+            "family history entries",
+            family_history_ids_for_patient(id),
+            options.family_history,
+        )?,
+        removed_care_plans: resolve_dependents(
+            "care plans",
+            care_plan_ids_for_patient(id),
+            options.care_plans,
+        )?,
+        ..Default::default()
+    })
+}
+
+// Function to work out which dependents a doctor deletion would remove or reassign
+fn plan_doctor_delete(
+    id: DoctorId,
+    options: &DoctorDeleteOptions,
+) -> Result<DeleteImpact, Message> {
+    ensure_doctor_exists(id)?;
+
+    let now = current_time();
+    let (future_appointments, past_appointments): (Vec<AppointmentId>, Vec<AppointmentId>) =
+        appointment_ids_for_doctor(id)
+            .into_iter()
+            .partition(|appointment_id| {
+                matches!(
+                    APPOINTMENTS_STORAGE.with(|storage| storage.borrow().get(appointment_id)),
+                    Some(appointment) if appointment.date_time >= now
+                )
+            });
+    let patient_records = patient_record_ids_for_doctor(id);
+    let medications = medication_ids_for_doctor(id);
+    let allergies = allergy_ids_for_doctor(id);
+    let lab_orders = lab_order_ids_for_doctor(id);
+    let immunizations = immunization_ids_for_doctor(id);
+    let clinical_notes = clinical_note_ids_for_doctor(id);
+    let problems = problem_ids_for_doctor(id);
+    let care_plans = care_plan_ids_for_doctor(id);
+    let care_tasks = care_task_ids_for_doctor(id);
+
+    let mut impact = DeleteImpact {
+        removed_appointments: resolve_dependents(
+            "past appointments",
+            past_appointments,
+            options.past_appointments,
+        )?,
+        removed_patient_records: resolve_dependents(
+            "patient records",
+            patient_records,
+            options.patient_records,
+        )?,
+        removed_medications: resolve_dependents("medications", medications, options.medications)?,
+        removed_allergies: resolve_dependents("allergies", allergies, options.allergies)?,
+        removed_lab_orders: resolve_dependents("lab orders", lab_orders, options.lab_orders)?,
+        removed_immunizations: resolve_dependents(
+            "immunizations",
+            immunizations,
+            options.immunizations,
+        )?,
+        removed_clinical_notes: resolve_dependents(
+            "clinical notes",
+            clinical_notes,
+            options.clinical_notes,
+        )?,
+        removed_problems: resolve_dependents("problems", problems, options.problems)?,
+        removed_care_plans: resolve_dependents("care plans", care_plans, options.care_plans)?,
+        removed_care_tasks: resolve_dependents("care plan tasks", care_tasks, options.care_tasks)?,
+        ..Default::default()
+    };
+
+    match options.future_appointments {
+        FutureAppointmentAction::Restrict => {
+            resolve_dependents(
+                "future appointments",
+                future_appointments,
+                DependentAction::Restrict,
+            )?;
+        }
+        FutureAppointmentAction::Cascade => {
+            impact.removed_appointments.extend(future_appointments);
+        }
+        FutureAppointmentAction::Reassign(doctor_id) => {
+            if doctor_id == id {
+                return Err(Message::InvalidPayload(
+                    "Appointments cannot be reassigned to the doctor being deleted.".to_string(),
+                ));
+            }
+            ensure_doctor_exists(doctor_id)?;
+            impact.reassigned_appointments = future_appointments;
+            impact.reassigned_to = Some(doctor_id);
+        }
+    }
+
+    // Other doctors' records may list the medications this doctor prescribed
+    for medication_id in &impact.removed_medications {
+        ensure_medication_unreferenced(*medication_id, &impact.removed_patient_records)?;
+    }
+
+    Ok(impact)
+}
+
+// Function to apply a dependent action to the ids of one relationship
+fn resolve_dependents<T>(
+    relationship: &str,
+    ids: Vec<T>,
+    action: DependentAction,
+) -> Result<Vec<T>, Message> {
+    match action {
+        DependentAction::Restrict if !ids.is_empty() => Err(Message::Error(format!(
+            "Cannot delete while {} {} still reference it.",
+            ids.len(),
+            relationship
+        ))),
+        DependentAction::Restrict => Ok(Vec::new()),
+        DependentAction::Cascade => Ok(ids),
+    }
+}
+
+// Function to remove and reassign the dependents listed in a delete impact
+fn apply_delete_impact(impact: &DeleteImpact) {
+    for id in &impact.removed_appointments {
+        remove_appointment(*id);
+    }
+    if let Some(doctor_id) = impact.reassigned_to {
+        for id in &impact.reassigned_appointments {
+            if let Some(mut appointment) =
+                APPOINTMENTS_STORAGE.with(|storage| storage.borrow().get(id))
+            {
+                appointment.doctor_id = doctor_id;
+                appointment.updated_at = Some(current_time());
+                save_appointment(&appointment);
+            }
+        }
+    }
+    for id in &impact.removed_patient_records {
+        remove_patient_record(*id);
+    }
+    for id in &impact.removed_medications {
+        remove_medication(*id);
+    }
+    for id in &impact.removed_emergency_contacts {
+        remove_emergency_contact(*id);
+    }
+    for id in &impact.removed_insurance_coverages {
+        remove_insurance_coverage(*id);
+    }
+    for id in &impact.removed_allergies {
+        remove_allergy(*id);
+    }
+    for id in &impact.removed_observations {
+        remove_observation(*id);
+    }
+    for id in &impact.removed_lab_orders {
+        remove_lab_order(*id);
+    }
+    for id in &impact.removed_immunizations {
+        remove_immunization(*id);
+    }
+    for id in &impact.removed_clinical_notes {
+        remove_clinical_note(*id);
+    }
+    for id in &impact.removed_documents {
+        remove_document(*id);
+    }
+    for id in &impact.removed_problems {
+        remove_problem(*id);
+    }
+    for id in &impact.removed_family_history {
+        remove_family_history(*id);
+    }
+    for id in &impact.removed_care_plans {
+        remove_care_plan(*id);
+    }
+    for id in &impact.removed_care_tasks {
+        remove_care_task(*id);
+    }
+}
+
+// Function to check a record's problem list changes and build the problems they add or
+// resolve, without saving anything
+fn prepare_record_problems(
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    changes: Vec<RecordProblem>,
+) -> Result<Vec<Problem>, Message> {
+    if changes.len() > MAX_RECORD_PROBLEMS {
+        return Err(Message::InvalidPayload(format!(
+            "'problems' must have at most {} entries.",
+            MAX_RECORD_PROBLEMS
+        )));
+    }
+
+    let mut problems: Vec<Problem> = Vec::with_capacity(changes.len());
+    for change in changes {
+        let problem = match change {
+            RecordProblem::Add(payload) => Problem {
+                patient_id,
+                condition: payload.condition,
+                code: payload
+                    .code
+                    .map(|code| known_icd10_code(&code))
+                    .transpose()?,
+                onset_date: payload.onset_date,
+                severity: payload.severity,
+                recorded_by: doctor_id,
+                created_at: current_time(),
+                ..Default::default()
+            },
+            RecordProblem::Resolve(id) => {
+                ensure_id_kind(id.0, EntityKind::Problem)?;
+                let mut problem = PROBLEMS_STORAGE
+                    .with(|storage| storage.borrow().get(&id))
+                    .ok_or(Message::NotFound(format!("Problem {} not found", id.0)))?;
+                if problem.patient_id != patient_id {
+                    return Err(Message::InvalidPayload(format!(
+                        "Problem {} belongs to a different patient.",
+                        id.0
+                    )));
+                }
+                if problem.status == ProblemStatus::Resolved
+                    || problems.iter().any(|other| other.id == id)
+                {
+                    return Err(Message::InvalidPayload(format!(
+                        "Problem {} is already resolved.",
+                        id.0
+                    )));
+                }
+                problem.status = ProblemStatus::Resolved;
+                problem.resolved_date = Some(current_date().to_string());
+                problem.updated_at = Some(current_time());
+                problem
+            }
+        };
+        validate_problem(&problem)?;
+        ensure_not_on_problem_list(&problem, &problems)?;
+        problems.push(problem);
+    }
+    Ok(problems)
+}
+
+// Function to save the problems prepared for a record, linking them to it
+fn save_record_problems(problems: Vec<Problem>, record_id: PatientRecordId) {
+    for mut problem in problems {
+        if problem.id == ProblemId::default() {
+            problem.id = ProblemId(next_id(EntityKind::Problem));
+            problem.source_record_id = Some(record_id);
+        } else {
+            problem.resolved_record_id = Some(record_id);
+        }
+        save_problem(&problem);
+    }
+}
+
+// Function to refuse a second unresolved problem for the same condition. Pending problems are
+// about to be saved along with this one and take the place of their stored versions.
+fn ensure_not_on_problem_list(problem: &Problem, pending: &[Problem]) -> Result<(), Message> {
+    if problem.status == ProblemStatus::Resolved {
+        return Ok(());
+    }
+    let stored = load_problems(problem.patient_id);
+    let duplicate = stored
+        .iter()
+        .filter(|other| other.id != problem.id && !pending.iter().any(|p| p.id == other.id))
+        .chain(pending.iter())
+        .any(|other| other.status != ProblemStatus::Resolved && same_condition(other, problem));
+    if duplicate {
+        return Err(Message::InvalidPayload(format!(
+            "'{}' is already on the patient's problem list.",
+            problem.condition
+        )));
+    }
+    Ok(())
+}
+
+// Problems match on their ICD-10 code when both are coded, and on the condition otherwise
+fn same_condition(a: &Problem, b: &Problem) -> bool {
+    match (&a.code, &b.code) {
+        (Some(a), Some(b)) => a == b,
+        _ => normalize_words(&a.condition) == normalize_words(&b.condition),
+    }
+}
+
+// Function to check a record's medications and build the new ones, without saving anything
+fn prepare_record_medications(
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+    medications: Vec<RecordMedication>,
+) -> Result<PreparedMedications, Message> {
+    if medications.len() > MAX_RECORD_MEDICATIONS {
+        return Err(Message::InvalidPayload(format!(
+            "'medications' must have at most {} entries.",
+            MAX_RECORD_MEDICATIONS
+        )));
+    }
+    let mut prepared: Vec<Medication> = Vec::new();
+    let mut interaction_warnings = Vec::new();
+    for medication in medications {
+        match medication {
+            RecordMedication::Existing(id) => {
+                ensure_id_kind(id.0, EntityKind::Medication)?;
+                let medication = MEDICATIONS_STORAGE
+                    .with(|storage| storage.borrow().get(&id))
+                    .ok_or(Message::NotFound(format!("Medication {} not found", id.0)))?;
+                if medication.patient_id != patient_id {
+                    return Err(Message::InvalidPayload(format!(
+                        "Medication {} belongs to a different patient.",
+                        id.0
+                    )));
+                }
+                if prepared.iter().any(|other| other.id == id) {
+                    return Err(Message::InvalidPayload(format!(
+                        "Medication {} is listed more than once.",
+                        id.0
+                    )));
+                }
+                prepared.push(medication);
+            }
+            RecordMedication::New(payload) => {
+                let frequency = parse_frequency_code(&payload.frequency)?;
+                validate_route(payload.route)?;
+                let medication = Medication {
+                    id: MedicationId::default(),
+                    name: payload.name,
+                    dose: Some(payload.dose),
+                    route: payload.route,
+                    frequency: Some(frequency),
+                    start_date: Some(payload.start_date),
+                    end_date: payload.end_date,
+                    patient_id,
+                    doctor_id: Some(doctor_id),
+                    indication: payload.indication,
+                    legacy_dosage: None,
+                    legacy_frequency: None,
+                    allergy_override: None,
+                    created_at: current_time(),
+                    updated_at: None,
+                };
+                validate_medication(&medication)?;
+                let allergy_override = check_allergies(
+                    patient_id,
+                    &medication.name,
+                    payload.allergy_override_reason,
+                )?;
+                // New medications of the same record are not stored yet, so they are checked
+                // against each other here
+                let mut warnings: Vec<(Option<usize>, InteractionWarning)> =
+                    find_interactions(patient_id, &medication)
+                        .into_iter()
+                        .map(|warning| (None, warning))
+                        .collect();
+                let known = known_interactions(&medication.name);
+                let today = current_date();
+                for (index, earlier) in prepared.iter().enumerate() {
+                    if earlier.id == MedicationId::default()
+                        && medications_overlap(earlier, &medication, today)
+                    {
+                        warnings.extend(
+                            interactions_with(&known, earlier)
+                                .into_iter()
+                                .map(|warning| (Some(index), warning)),
+                        );
+                    }
+                }
+                ensure_not_contraindicated(
+                    &medication.name,
+                    warnings.iter().map(|(_, warning)| warning),
+                )?;
+                interaction_warnings.extend(warnings);
+                prepared.push(Medication {
+                    allergy_override,
+                    ..medication
+                });
+            }
+        }
+    }
+    Ok(PreparedMedications {
+        medications: prepared,
+        interaction_warnings,
+    })
+}
+
+// Function to check a medication against the patient's active allergies. A match is refused
+// unless an override reason is given, which is then stored with the medication.
+fn check_allergies(
+    patient_id: PatientId,
+    medication_name: &str,
+    override_reason: Option<String>,
+) -> Result<Option<AllergyOverride>, Message> {
+    let matching: Vec<Allergy> = load_allergies(patient_id)
+        .into_iter()
+        .filter(|allergy| {
+            allergy.status == AllergyStatus::Active
+                && allergy_matches(&allergy.substance, medication_name)
+        })
+        .collect();
+    if matching.is_empty() {
+        return Ok(None);
+    }
+
+    match override_reason.filter(|reason| !reason.trim().is_empty()) {
+        Some(reason) => {
+            validate_length("allergy_override_reason", &reason, MAX_DESCRIPTION_LENGTH)?;
+            Ok(Some(AllergyOverride {
+                allergy_ids: matching.iter().map(|allergy| allergy.id).collect(),
+                reason,
+            }))
+        }
+        None => {
+            let substances: Vec<String> = matching
+                .iter()
+                .map(|allergy| allergy.substance.clone())
+                .collect();
+            Err(Message::InvalidPayload(format!(
+                "'{}' matches the patient's active allergies to {}; provide 'allergy_override_reason' to prescribe it anyway.",
+                medication_name,
+                substances.join(", ")
+            )))
+        }
+    }
+}
+
+// A substance matches when either name contains the other as whole words, ignoring case
+fn allergy_matches(substance: &str, medication_name: &str) -> bool {
+    let (substance, medication_name) =
+        (normalize_words(substance), normalize_words(medication_name));
+    contains_words(&medication_name, &substance) || contains_words(&substance, &medication_name)
+}
+
+// Function to find the interactions of a medication with the patient's other medications
+// that are taken on a day it is, most severe first
+fn find_interactions(patient_id: PatientId, candidate: &Medication) -> Vec<InteractionWarning> {
+    let known = known_interactions(&candidate.name);
+    if known.is_empty() {
+        return Vec::new();
+    }
+
+    let today = current_date();
+    let mut warnings: Vec<InteractionWarning> = medication_ids_for_patient(patient_id)
+        .into_iter()
+        .filter(|id| *id != candidate.id)
+        .filter_map(|id| MEDICATIONS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .filter(|medication| medications_overlap(medication, candidate, today))
+        .flat_map(|medication| interactions_with(&known, &medication))
+        .collect();
+    warnings.sort_by_key(|warning| std::cmp::Reverse(warning.severity));
+    warnings
+}
+
+// Function to look up the interactions of a medication name in the knowledge base, each
+// oriented so drug_b is the other drug
+fn known_interactions(candidate: &str) -> Vec<DrugInteraction> {
+    // Any run of consecutive words in the name may be a drug in the knowledge base
+    let candidate = normalize_words(candidate);
+    let words: Vec<&str> = candidate.split(' ').collect();
+    let mut known: Vec<DrugInteraction> = Vec::new();
+    for start in 0..words.len() {
+        for end in start + 1..=words.len() {
+            let drug = words[start..end].join(" ");
+            if drug.is_empty() || drug.len() > MAX_DRUG_NAME_LENGTH {
+                continue;
+            }
+            let key = drug_key(&drug);
+            DRUG_INTERACTIONS.with(|map| {
+                known.extend(
+                    map.borrow()
+                        .range((key, DrugKey::default())..)
+                        .take_while(|((first, _), _)| *first == key)
+                        .map(|(_, interaction)| {
+                            // Orient each pair so drug_b is the other medication
+                            if interaction.drug_a == drug {
+                                interaction
+                            } else {
+                                DrugInteraction {
+                                    drug_a: interaction.drug_b,
+                                    drug_b: interaction.drug_a,
+                                    ..interaction
+                                }
+                            }
+                        }),
+                )
+            });
+        }
+    }
+    known
+}
+
+// Function to turn the known interactions that involve another medication into warnings
+fn interactions_with(
+    known: &[DrugInteraction],
+    medication: &Medication,
+) -> Vec<InteractionWarning> {
+    let name = normalize_words(&medication.name);
+    known
+        .iter()
+        .filter(|interaction| contains_words(&name, &interaction.drug_b))
+        .map(|interaction| InteractionWarning {
+            medication_id: medication.id,
+            medication_name: medication.name.clone(),
+            severity: interaction.severity,
+            description: interaction.description.clone(),
+        })
+        .collect()
+}
+
+// Function to refuse contraindicated combinations and return the other interactions as warnings
+fn check_prescribing_interactions(
+    patient_id: PatientId,
+    candidate: &Medication,
+) -> Result<Vec<InteractionWarning>, Message> {
+    let warnings = find_interactions(patient_id, candidate);
+    ensure_not_contraindicated(&candidate.name, &warnings)?;
+    Ok(warnings)
+}
+
+fn ensure_not_contraindicated<'a>(
+    medication_name: &str,
+    warnings: impl IntoIterator<Item = &'a InteractionWarning>,
+) -> Result<(), Message> {
+    let blocking: Vec<String> = warnings
+        .into_iter()
+        .filter(|warning| warning.severity == InteractionSeverity::Contraindicated)
+        .map(|warning| format!("{} ({})", warning.medication_name, warning.description))
+        .collect();
+    if blocking.is_empty() {
+        Ok(())
+    } else {
+        Err(Message::InvalidPayload(format!(
+            "'{}' is contraindicated with the patient's medications: {}",
+            medication_name,
+            blocking.join("; ")
+        )))
+    }
+}
+
+// Two medications overlap when they are taken on a common day from today on; what was taken
+// together in the past no longer needs checking
+fn medications_overlap(a: &Medication, b: &Medication, today: NaiveDate) -> bool {
+    let start = |medication: &Medication| {
+        medication
+            .start_date
+            .as_deref()
+            .and_then(parse_date)
+            .map_or(today, |start_date| start_date.max(today))
+    };
+    let end = |medication: &Medication| {
+        medication
+            .end_date
+            .as_deref()
+            .and_then(parse_date)
+            .unwrap_or(NaiveDate::MAX)
+    };
+    start(a).max(start(b)) <= end(a).min(end(b))
+}
+
+// A medication is active from its start date (if any) through its end date (if any)
+fn is_active_medication(medication: &Medication, today: NaiveDate) -> bool {
+    let start_date = medication.start_date.as_deref().and_then(parse_date);
+    let end_date = medication.end_date.as_deref().and_then(parse_date);
+    !matches!(start_date, Some(start_date) if start_date > today)
+        && !matches!(end_date, Some(end_date) if end_date < today)
+}
+
+fn drug_key(drug: &str) -> DrugKey {
+    DrugKey::try_from(drug.as_bytes()).expect("Drug name exceeds MAX_DRUG_NAME_LENGTH")
+}
+
+fn speciality_key(speciality: &str) -> SpecialityKey {
+    SpecialityKey::try_from(normalize_words(speciality).as_bytes())
+        .expect("Speciality exceeds MAX_LABEL_LENGTH")
+}
+
+// Expects a validated language tag, whose primary subtag is at most 8 letters
+fn language_key(language: &str) -> LanguageKey {
+    let primary = language.split('-').next().unwrap_or_default();
+    LanguageKey::try_from(primary.to_ascii_lowercase().as_bytes())
+        .expect("Language subtag exceeds MAX_LANGUAGE_SUBTAG_LENGTH")
+}
+
+fn find_speciality(name: &str) -> Option<Speciality> {
+    let key = normalize_words(name);
+    if key.is_empty() || key.len() > MAX_LABEL_LENGTH {
+        return None;
+    }
+    SPECIALITIES.with(|catalogue| catalogue.borrow().get(&speciality_key(name)))
+}
+
+// Function to replace the given speciality names with their catalogue names, dropping repeats
+fn catalogue_specialities(names: &[String]) -> Result<Vec<String>, Message> {
+    let mut specialities: Vec<String> = Vec::with_capacity(names.len());
+    for name in names {
+        let speciality = find_speciality(name).ok_or_else(|| {
+            Message::InvalidPayload(format!("Speciality '{}' is not in the catalogue.", name))
+        })?;
+        if !specialities.contains(&speciality.name) {
+            specialities.push(speciality.name);
+        }
+    }
+    Ok(specialities)
+}
+
+fn vaccine_key(vaccine: &str) -> VaccineKey {
+    VaccineKey::try_from(vaccine.as_bytes()).expect("Vaccine name exceeds MAX_VACCINE_NAME_LENGTH")
+}
+
+// Lower-case alphanumeric words separated by single spaces
+fn normalize_words(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Whether normalized text contains normalized words as a whole-word run
+fn contains_words(text: &str, words: &str) -> bool {
+    !words.is_empty() && format!(" {} ", text).contains(&format!(" {} ", words))
+}
+
+// A note can only be linked to an appointment between its patient and its author
+fn ensure_note_appointment(
+    appointment_id: AppointmentId,
+    patient_id: PatientId,
+    doctor_id: DoctorId,
+) -> Result<(), Message> {
+    ensure_appointment_of_patient(appointment_id, patient_id)?;
+    let appointment = get_appointment_id(appointment_id)?;
+    if appointment.doctor_id != doctor_id {
+        return Err(Message::InvalidPayload(format!(
+            "Appointment {} is with a different doctor.",
+            appointment_id.0
+        )));
+    }
+    Ok(())
+}
+
+// Documents can be read by their uploader and by canister controllers
+fn ensure_document_access(document: &Document) -> Result<(), Message> {
+    let reader = caller();
+    if reader == document.uploaded_by || ic_cdk::api::is_controller(&reader) {
+        Ok(())
+    } else {
+        Err(Message::Unauthorized(
+            "Only the uploader or a controller can read this document.".to_string(),
+        ))
+    }
+}
+
+// Function to check a download token for an available document
+fn authorize_download(document_id: DocumentId, token: &str) -> Result<Document, Message> {
+    let document = load_document(document_id)?;
+    let grant = hex_decode(token)
+        .filter(|bytes| bytes.len() == DOWNLOAD_TOKEN_LENGTH)
+        .and_then(|bytes| {
+            DOWNLOAD_GRANTS.with(|grants| {
+                grants
+                    .borrow()
+                    .get(&DownloadToken::try_from(bytes.as_slice()).unwrap())
+            })
+        });
+    match grant {
+        Some(grant)
+            if grant.document_id == document_id
+                && grant.expires_at > current_time()
+                && document.status == DocumentStatus::Available =>
+        {
+            Ok(document)
+        }
+        _ => Err(Message::Unauthorized(
+            "The download link is invalid or has expired.".to_string(),
+        )),
+    }
+}
+
+fn next_streaming_token(document: &Document, index: u32, token: &str) -> Option<StreamingToken> {
+    if index + 1 < chunk_count(document.size) {
+        Some(StreamingToken {
+            document_id: document.id,
+            index: index + 1,
+            token: token.to_string(),
+        })
+    } else {
+        None
+    }
+}
+
+fn http_error(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: message.as_bytes().to_vec(),
+        streaming_strategy: None,
+    }
+}
+
+fn chunk_count(size: u64) -> u32 {
+    match size {
+        0 => 0,
+        _ => ((size - 1) / DOCUMENT_CHUNK_SIZE as u64 + 1) as u32,
+    }
+}
+
+// Every chunk is full except the last
+fn chunk_length(size: u64, index: u32) -> usize {
+    let start = index as u64 * DOCUMENT_CHUNK_SIZE as u64;
+    size.saturating_sub(start).min(DOCUMENT_CHUNK_SIZE as u64) as usize
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Function to set an empty field from another value, recording the field's name
+fn fill_field<T>(field: &mut Option<T>, value: Option<T>, name: &str, filled: &mut Vec<String>) {
+    if field.is_none() && value.is_some() {
+        *field = value;
+        filled.push(name.to_string());
+    }
+}
+
+// Keys of the groups a patient is compared within when looking for duplicates
+// An estimated date of birth is only a year, and migrated patients share January 1, so it
+// does not make a block
+fn duplicate_blocking_keys(patient: &Patient) -> Vec<DuplicateBlockKey> {
+    let mut keys = vec![format!("name:{}", sorted_name_words(&patient.name))];
+    if !patient.date_of_birth_estimated {
+        keys.push(format!("date_of_birth:{}", patient.date_of_birth));
+    }
+    if let Some(phone) = phone_digits(&patient.phone) {
+        keys.push(format!("phone:{}", phone));
+    }
+    if let Some(email) = normalized_email(&patient.email) {
+        keys.push(format!("email:{}", email));
+    }
+    keys.iter()
+        .map(|key| {
+            DuplicateBlockKey::try_from(Sha256::digest(key.as_bytes()).as_slice())
+                .expect("A SHA-256 digest is 32 bytes")
+        })
+        .collect()
+}
+
+fn next_duplicate_block(after: Bound<(DuplicateBlockKey, PatientId)>) -> Option<DuplicateBlockKey> {
+    DUPLICATE_BLOCKS.with(|index| {
+        index
+            .borrow()
+            .range((after, Bound::Unbounded))
+            .next()
+            .map(|((block, _), _)| block)
+    })
+}
+
+// Members of a block, stopping one past MAX_DUPLICATE_BLOCK_SIZE
+fn duplicate_block_members(block: DuplicateBlockKey) -> Vec<PatientId> {
+    DUPLICATE_BLOCKS.with(|index| {
+        index
+            .borrow()
+            .range((block, PatientId(0))..=(block, PatientId(u64::MAX)))
+            .take(MAX_DUPLICATE_BLOCK_SIZE + 1)
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+// The first block, in index order, that two patients share and that is small enough to compare
+fn first_shared_block(a: &Patient, b: &Patient) -> Option<DuplicateBlockKey> {
+    let keys_b = duplicate_blocking_keys(b);
+    let mut shared: Vec<DuplicateBlockKey> = duplicate_blocking_keys(a)
+        .into_iter()
+        .filter(|key| keys_b.contains(key))
+        .collect();
+    shared.sort();
+    shared
+        .into_iter()
+        .find(|block| duplicate_block_members(*block).len() <= MAX_DUPLICATE_BLOCK_SIZE)
+}
+
+// Function to score how likely two patients are the same person, out of 100
+fn duplicate_score(a: &Patient, b: &Patient) -> (u32, Vec<String>) {
+    let mut score = 0;
+    let mut matched_fields = Vec::new();
+
+    let (name_a, name_b) = (normalize_words(&a.name), normalize_words(&b.name));
+    let same_surname_and_initial = matches!(
+        (name_a.rsplit(' ').next(), name_b.rsplit(' ').next()),
+        (Some(last_a), Some(last_b)) if last_a == last_b
+    ) && name_a.chars().next() == name_b.chars().next();
+    let name_score = if name_a == name_b {
+        40
+    } else if sorted_name_words(&a.name) == sorted_name_words(&b.name) {
+        35
+    } else if same_surname_and_initial {
+        20
+    } else {
+        0
+    };
+    if name_score > 0 {
+        score += name_score;
+        matched_fields.push("name".to_string());
+    }
+
+    // An estimated date of birth only tells the year
+    let estimated = a.date_of_birth_estimated || b.date_of_birth_estimated;
+    if a.date_of_birth == b.date_of_birth {
+        score += 30;
+        matched_fields.push("date_of_birth".to_string());
+    } else if estimated && a.date_of_birth.get(..4) == b.date_of_birth.get(..4) {
+        score += 10;
+        matched_fields.push("date_of_birth".to_string());
+    }
+
+    if matches!((phone_digits(&a.phone), phone_digits(&b.phone)), (Some(x), Some(y)) if x == y) {
+        score += 15;
+        matched_fields.push("phone".to_string());
+    }
+    if matches!(
+        (normalized_email(&a.email), normalized_email(&b.email)),
+        (Some(x), Some(y)) if x == y
+    ) {
+        score += 15;
+        matched_fields.push("email".to_string());
+    }
+    (score, matched_fields)
+}
+
+fn sorted_name_words(name: &str) -> String {
+    let normalized = normalize_words(name);
+    let mut words: Vec<&str> = normalized.split(' ').collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+fn phone_digits(phone: &Option<String>) -> Option<String> {
+    let digits: String = phone
+        .as_deref()?
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect();
+    (!digits.is_empty()).then_some(digits)
+}
+
+fn normalized_email(email: &Option<String>) -> Option<String> {
+    let email = email.as_deref()?.trim().to_lowercase();
+    (!email.is_empty()).then_some(email)
+}
+
+fn ensure_record_of_patient(
+    record_id: PatientRecordId,
+    patient_id: PatientId,
+) -> Result<(), Message> {
+    ensure_id_kind(record_id.0, EntityKind::PatientRecord)?;
+    let patient_record = PATIENT_RECORDS_STORAGE
+        .with(|storage| storage.borrow().get(&record_id))
+        .ok_or(Message::NotFound(format!(
+            "Patient record {} not found",
+            record_id.0
+        )))?;
+    if patient_record.patient_id != patient_id {
+        return Err(Message::InvalidPayload(format!(
+            "Patient record {} belongs to a different patient.",
+            record_id.0
+        )));
+    }
+    Ok(())
+}
+
+fn ensure_appointment_of_patient(
+    appointment_id: AppointmentId,
+    patient_id: PatientId,
+) -> Result<(), Message> {
+    ensure_id_kind(appointment_id.0, EntityKind::Appointment)?;
+    let appointment = APPOINTMENTS_STORAGE
+        .with(|storage| storage.borrow().get(&appointment_id))
+        .ok_or(Message::NotFound(format!(
+            "Appointment {} not found",
+            appointment_id.0
+        )))?;
+    if appointment.patient_id != patient_id {
+        return Err(Message::InvalidPayload(format!(
+            "Appointment {} belongs to a different patient.",
+            appointment_id.0
+        )));
+    }
+    Ok(())
+}
+
+// Function to check a measurement and convert it to the type's standard unit
+fn standardize_observation(
+    observation_type: ObservationType,
+    value: ObservationValue,
+    unit: ObservationUnit,
+) -> Result<ObservationValue, Message> {
+    let convert = |value: f64| -> Result<f64, Message> {
+        let standard = match (unit, observation_type.standard_unit()) {
+            (unit, standard) if unit == standard => value,
+            (ObservationUnit::Fahrenheit, ObservationUnit::Celsius) => (value - 32.0) * 5.0 / 9.0,
+            (ObservationUnit::Pounds, ObservationUnit::Kilograms) => value * 0.453_592_37,
+            (ObservationUnit::Inches, ObservationUnit::Centimeters) => value * 2.54,
+            _ => {
+                return Err(Message::InvalidPayload(
+                    "'unit' does not apply to this observation type.".to_string(),
+                ))
+            }
+        };
+        let (min, max) = observation_type.valid_range();
+        if !standard.is_finite() || standard < min || standard > max {
+            return Err(Message::InvalidPayload(format!(
+                "'value' must be between {} and {} in the standard unit.",
+                min, max
+            )));
+        }
+        Ok(standard)
+    };
+
+    match (observation_type, value) {
+        (
+            ObservationType::BloodPressure,
+            ObservationValue::BloodPressure {
+                systolic,
+                diastolic,
+            },
+        ) => {
+            let (systolic, diastolic) = (convert(systolic)?, convert(diastolic)?);
+            if systolic <= diastolic {
+                return Err(Message::InvalidPayload(
+                    "'systolic' must be greater than 'diastolic'.".to_string(),
+                ));
+            }
+            Ok(ObservationValue::BloodPressure {
+                systolic,
+                diastolic,
+            })
+        }
+        (ObservationType::BloodPressure, ObservationValue::Quantity(_)) => {
+            Err(Message::InvalidPayload(
+                "Blood pressure needs a systolic and diastolic value.".to_string(),
+            ))
+        }
+        (_, ObservationValue::Quantity(value)) => Ok(ObservationValue::Quantity(convert(value)?)),
+        (_, ObservationValue::BloodPressure { .. }) => Err(Message::InvalidPayload(
+            "Only blood pressure has systolic and diastolic values.".to_string(),
+        )),
+    }
+}
+
+// Function to check a goal's target and convert its bounds to the type's standard unit
+fn standardize_target(target: TargetMeasure) -> Result<TargetMeasure, Message> {
+    let standardize = |bound: Option<ObservationValue>| {
+        bound
+            .map(|value| standardize_observation(target.observation_type, value, target.unit))
+            .transpose()
+    };
+    let minimum = standardize(target.minimum.clone())?;
+    let maximum = standardize(target.maximum.clone())?;
+    match (&minimum, &maximum) {
+        (None, None) => {
+            return Err(Message::InvalidPayload(
+                "A goal's target needs a 'minimum' or a 'maximum'.".to_string(),
+            ))
+        }
+        (Some(minimum), Some(maximum))
+            if observation_components(minimum)
+                .into_iter()
+                .zip(observation_components(maximum))
+                .any(|(minimum, maximum)| minimum > maximum) =>
+        {
+            return Err(Message::InvalidPayload(
+                "A goal's target 'minimum' must not exceed its 'maximum'.".to_string(),
+            ))
+        }
+        _ => {}
+    }
+    Ok(TargetMeasure {
+        observation_type: target.observation_type,
+        unit: target.observation_type.standard_unit(),
+        minimum,
+        maximum,
+    })
+}
+
+fn observation_components(value: &ObservationValue) -> Vec<f64> {
+    match value {
+        ObservationValue::Quantity(value) => vec![*value],
+        ObservationValue::BloodPressure {
+            systolic,
+            diastolic,
+        } => vec![*systolic, *diastolic],
+    }
+}
+
+// How far a value lies outside a target's bounds, summed over its components; 0 when the
+// value meets the target
+fn distance_from_target(target: &TargetMeasure, value: &ObservationValue) -> f64 {
+    let minimum = target.minimum.as_ref().map(observation_components);
+    let maximum = target.maximum.as_ref().map(observation_components);
+    observation_components(value)
+        .into_iter()
+        .enumerate()
+        .map(|(n, value)| {
+            let below = minimum.as_ref().map(|bound| bound[n] - value);
+            let above = maximum.as_ref().map(|bound| value - bound[n]);
+            below.unwrap_or(0.0).max(above.unwrap_or(0.0)).max(0.0)
+        })
+        .sum()
+}
+
+// Function to compare a goal against the patient's observations
+fn goal_progress(
+    goal: &CareGoal,
+    patient_id: PatientId,
+    plan_start: u64,
+    today: NaiveDate,
+) -> GoalProgress {
+    let past_target_date =
+        matches!(goal.target_date.as_deref().and_then(parse_date), Some(date) if date < today);
+    let target = match &goal.target {
+        Some(target) => target,
+        None => {
+            return GoalProgress {
+                goal: goal.clone(),
+                status: GoalStatus::NotMeasured,
+                baseline: None,
+                latest: None,
+                improving: None,
+                overdue: false,
+            }
+        }
+    };
+
+    let baseline = first_observation_since(patient_id, target.observation_type, plan_start);
+    let latest = latest_observation(patient_id, target.observation_type);
+    let status = match &latest {
+        Some(latest) if distance_from_target(target, &latest.value) == 0.0 => GoalStatus::Met,
+        Some(_) => GoalStatus::NotMet,
+        None => GoalStatus::NoData,
+    };
+    let improving = match (&baseline, &latest) {
+        (Some(baseline), Some(latest)) if baseline.id != latest.id => Some(
+            distance_from_target(target, &latest.value)
+                < distance_from_target(target, &baseline.value),
+        ),
+        _ => None,
+    };
+    GoalProgress {
+        goal: goal.clone(),
+        status,
+        baseline,
+        latest,
+        improving,
+        overdue: past_target_date && status != GoalStatus::Met,
+    }
+}
+
+// Function to flag a value against a reference range, critical bounds first
+fn abnormal_flag(value: f64, range: &ReferenceRange) -> AbnormalFlag {
+    match range {
+        ReferenceRange {
+            critical_low: Some(bound),
+            ..
+        } if value < *bound => AbnormalFlag::CriticalLow,
+        ReferenceRange {
+            critical_high: Some(bound),
+            ..
+        } if value > *bound => AbnormalFlag::CriticalHigh,
+        ReferenceRange {
+            low: Some(bound), ..
+        } if value < *bound => AbnormalFlag::Low,
+        ReferenceRange {
+            high: Some(bound), ..
+        } if value > *bound => AbnormalFlag::High,
+        _ => AbnormalFlag::Normal,
+    }
+}
+
+fn series_point(start: u64, width: u64, to: u64, values: &[(f64, Option<f64>)]) -> SeriesPoint {
+    let stats = |values: Vec<f64>| SeriesStats {
+        min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+        max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        mean: values.iter().sum::<f64>() / values.len() as f64,
+    };
+    let secondary: Vec<f64> = values
+        .iter()
+        .filter_map(|(_, secondary)| *secondary)
+        .collect();
+    SeriesPoint {
+        start,
+        end: start.saturating_add(width).min(to),
+        count: values.len() as u32,
+        primary: stats(values.iter().map(|(primary, _)| *primary).collect()),
+        secondary: if secondary.is_empty() {
+            None
+        } else {
+            Some(stats(secondary))
+        },
+    }
+}
+
+// Function to save the new medications prepared for a record and return all of their ids,
+// along with the interaction warnings now that every medication has an id
+fn save_record_medications(
+    prepared: PreparedMedications,
+) -> (Vec<MedicationId>, Vec<InteractionWarning>) {
+    let ids: Vec<MedicationId> = prepared
+        .medications
+        .into_iter()
+        .map(|mut medication| {
+            if medication.id == MedicationId::default() {
+                medication.id = MedicationId(next_id(EntityKind::Medication));
+                save_medication(&medication);
+            }
+            medication.id
+        })
+        .collect();
+    let mut interaction_warnings: Vec<InteractionWarning> = prepared
+        .interaction_warnings
+        .into_iter()
+        .map(|(index, mut warning)| {
+            if let Some(index) = index {
+                warning.medication_id = ids[index];
+            }
+            warning
+        })
+        .collect();
+    interaction_warnings.sort_by_key(|warning| std::cmp::Reverse(warning.severity));
+    (ids, interaction_warnings)
+}
+
+// Function to refuse removing a medication that a patient record still lists
+fn ensure_medication_unreferenced(
+    id: MedicationId,
+    removed_patient_records: &[PatientRecordId],
+) -> Result<(), Message> {
+    let referencing: Vec<PatientRecordId> = patient_record_ids_for_medication(id)
+        .into_iter()
+        .filter(|record_id| !removed_patient_records.contains(record_id))
+        .collect();
+    if referencing.is_empty() {
+        Ok(())
+    } else {
+        Err(Message::InvalidPayload(format!(
+            "Medication {} is listed by {} patient record(s); remove it from them first.",
+            id.0,
+            referencing.len()
+        )))
+    }
+}
+
+// Function to refuse callers that are not controllers of the canister
+fn ensure_controller() -> Result<(), Message> {
+    if ic_cdk::api::is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Message::Unauthorized(
+            "Only canister controllers can do this.".to_string(),
+        ))
+    }
+}
+
+// Function to check that an entity fits its bounded map, so saving it cannot trap
+fn ensure_fits_bounded<T: Storable>(entity: &T, name: &str) -> Result<(), Message> {
+    if entity.to_bytes().len() > BOUNDED_ENTITY_SIZE as usize {
+        return Err(Message::InvalidPayload(format!(
+            "The {} is too large to store; shorten its fields.",
+            name
+        )));
+    }
+    Ok(())
+}
+
+// Function to shorten text to at most max_length bytes, cut on a character boundary
+fn truncate_text(text: &str, max_length: usize) -> String {
+    if text.len() <= max_length {
+        return text.to_string();
+    }
+    let end = (0..=max_length)
+        .rev()
+        .find(|end| text.is_char_boundary(*end))
+        .unwrap_or(0);
+    format!("{}...", text[..end].trim_end())
+}
+
+fn search_limit(limit: u32) -> usize {
+    (limit as usize).clamp(1, MAX_SEARCH_RESULTS)
+}
+
+// Function to bring a code to its stored form: upper case with a dot after the category
+fn normalize_icd10_code(code: &str) -> Result<String, Message> {
+    let compact: String = code.trim().to_uppercase().replace('.', "");
+    let bytes = compact.as_bytes();
+    let valid = (3..MAX_ICD10_CODE_LENGTH).contains(&bytes.len())
+        && bytes[0].is_ascii_uppercase()
+        && bytes[1].is_ascii_digit()
+        && bytes[2..].iter().all(|b| b.is_ascii_alphanumeric());
+    if !valid {
+        return Err(Message::InvalidPayload(format!(
+            "'{}' is not a valid ICD-10 code.",
+            code
+        )));
+    }
+    if compact.len() == 3 {
+        Ok(compact)
+    } else {
+        Ok(format!("{}.{}", &compact[..3], &compact[3..]))
+    }
+}
+
+// Like normalize_icd10_code, but also accepts the start of a code such as "J" or "J45.9"
+fn normalize_icd10_prefix(prefix: &str) -> Result<String, Message> {
+    let compact: String = prefix.trim().to_uppercase().replace('.', "");
+    if compact.len() >= 3 {
+        return normalize_icd10_code(&compact);
+    }
+    let valid = !compact.is_empty()
+        && compact.bytes().enumerate().all(|(i, b)| {
+            if i == 0 {
+                b.is_ascii_uppercase()
+            } else {
+                b.is_ascii_digit()
+            }
+        });
+    if valid {
+        Ok(compact)
+    } else {
+        Err(Message::InvalidPayload(format!(
+            "'{}' is not a valid ICD-10 code prefix.",
+            prefix
+        )))
+    }
+}
+
+// Function to check that every coded diagnosis is in the ICD-10 table
+fn normalize_coded_diagnoses(codes: Vec<String>) -> Result<Vec<String>, Message> {
+    if codes.len() > MAX_CODED_DIAGNOSES {
+        return Err(Message::InvalidPayload(format!(
+            "'coded_diagnoses' must have at most {} entries.",
+            MAX_CODED_DIAGNOSES
+        )));
+    }
+    let mut normalized: Vec<String> = Vec::with_capacity(codes.len());
+    for code in codes {
+        let code = known_icd10_code(&code)?;
+        if normalized.contains(&code) {
+            return Err(Message::InvalidPayload(format!(
+                "ICD-10 code {} is listed more than once.",
+                code
+            )));
+        }
+        normalized.push(code);
+    }
+    Ok(normalized)
+}
+
+// Function to normalize an ICD-10 code and check that it is in the code table
+fn known_icd10_code(code: &str) -> Result<String, Message> {
+    let code = normalize_icd10_code(code)?;
+    if !ICD10_CODES.with(|codes| codes.borrow().contains_key(&icd10_key(&code))) {
+        return Err(Message::InvalidPayload(format!(
+            "ICD-10 code {} is not in the code table.",
+            code
+        )));
+    }
+    Ok(code)
+}
+
+fn icd10_key(code: &str) -> Icd10CodeKey {
+    Icd10CodeKey::try_from(code.as_bytes()).expect("ICD-10 code exceeds MAX_ICD10_CODE_LENGTH")
+}
+
+// Lower-case words of at least 3 characters, as indexed for keyword search
+fn description_words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 3)
+        .map(|word| word.to_string())
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+fn keyword_key(word: &str) -> Icd10Keyword {
+    let mut end = word.len().min(MAX_KEYWORD_LENGTH);
+    while !word.is_char_boundary(end) {
+        end -= 1;
+    }
+    Icd10Keyword::try_from(&word.as_bytes()[..end]).expect("Keyword exceeds MAX_KEYWORD_LENGTH")
+}
+
+// Referential integrity checks shared by the create and update endpoints
+fn ensure_patient_exists(patient_id: PatientId) -> Result<(), Message> {
+    ensure_id_kind(patient_id.0, EntityKind::Patient)?;
+    if PATIENTS_STORAGE.with(|storage| storage.borrow().contains_key(&patient_id)) {
+        Ok(())
+    } else if let Some(survivor) = merged_into(patient_id) {
+        Err(Message::NotFound(format!(
+            "Patient {} was merged into patient {}",
+            patient_id.0, survivor.0
+        )))
+    } else {
+        Err(Message::NotFound("Patient not found".to_string()))
+    }
+}
+
+// Function for per-patient reads: follows a merge redirect to the surviving patient and
+// checks that it exists
+fn resolve_patient(patient_id: PatientId) -> Result<PatientId, Message> {
+    ensure_id_kind(patient_id.0, EntityKind::Patient)?;
+    let patient_id = merged_into(patient_id).unwrap_or(patient_id);
+    ensure_patient_exists(patient_id)?;
+    Ok(patient_id)
+}
+
+fn merged_into(patient_id: PatientId) -> Option<PatientId> {
+    PATIENT_REDIRECTS.with(|redirects| {
+        redirects
+            .borrow()
+            .get(&patient_id)
+            .map(|redirect| redirect.merged_into)
+    })
+}
+
+fn ensure_facility_exists(facility_id: FacilityId) -> Result<(), Message> {
+    ensure_id_kind(facility_id.0, EntityKind::Facility)?;
+    if FACILITIES_STORAGE.with(|storage| storage.borrow().contains_key(&facility_id)) {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Facility not found".to_string()))
+    }
+}
+
+fn ensure_department_exists(department_id: DepartmentId) -> Result<(), Message> {
+    ensure_id_kind(department_id.0, EntityKind::Department)?;
+    if DEPARTMENTS_STORAGE.with(|storage| storage.borrow().contains_key(&department_id)) {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Department not found".to_string()))
+    }
+}
+
+fn ensure_room_exists(room_id: RoomId) -> Result<(), Message> {
+    ensure_id_kind(room_id.0, EntityKind::Room)?;
+    if ROOMS_STORAGE.with(|storage| storage.borrow().contains_key(&room_id)) {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Room not found".to_string()))
+    }
+}
+
+fn appointment_end(date_time: u64, duration: u32) -> u64 {
+    date_time.saturating_add(duration as u64 * NANOS_PER_MINUTE)
+}
+
+// Function to get the appointments in a room that overlap [from, to), ordered by start time
+fn room_appointments_overlapping(room_id: RoomId, from: u64, to: u64) -> Vec<Appointment> {
+    // No appointment is longer than MAX_APPOINTMENT_DURATION, so none starting earlier reaches from
+    let earliest = from.saturating_sub(MAX_APPOINTMENT_DURATION as u64 * NANOS_PER_MINUTE);
+    load_appointments(appointment_ids_for_room(room_id, earliest, to))
+        .into_iter()
+        .filter(|appointment| appointment_end(appointment.date_time, appointment.duration) > from)
+        .collect()
+}
+
+// Function to refuse booking a room that another appointment holds at the same time
+fn ensure_room_available(
+    room_id: RoomId,
+    date_time: u64,
+    duration: u32,
+    except: Option<AppointmentId>,
+) -> Result<(), Message> {
+    let end = appointment_end(date_time, duration);
+    match room_appointments_overlapping(room_id, date_time, end)
+        .into_iter()
+        .find(|appointment| Some(appointment.id) != except)
+    {
+        Some(appointment) => Err(Message::Error(format!(
+            "The room is already booked by appointment {} at that time.",
+            appointment.id.0
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn timestamp_from_date(date: NaiveDate) -> Option<u64> {
+    let seconds = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+    u64::try_from(seconds).ok()?.checked_mul(1_000_000_000)
+}
+
+fn ensure_doctor_exists(doctor_id: DoctorId) -> Result<(), Message> {
+    ensure_id_kind(doctor_id.0, EntityKind::Doctor)?;
+    if DOCTORS_STORAGE.with(|storage| storage.borrow().contains_key(&doctor_id)) {
+        Ok(())
+    } else {
+        Err(Message::NotFound("Doctor not found".to_string()))
+    }
+}
+
+// Function to apply an optional update to a field, recording its name if the value changed
+fn apply_field<T: PartialEq>(
+    field: &mut T,
+    value: Option<T>,
+    name: &str,
+    changed_fields: &mut Vec<String>,
+) {
+    if let Some(value) = value {
+        if *field != value {
+            *field = value;
+            changed_fields.push(name.to_string());
+        }
+    }
+}
+
+// Validation shared by the create and update endpoints
+fn validate_doctor(doctor: &Doctor) -> Result<(), Message> {
+    if doctor.name.is_empty() || doctor.specialities.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' and 'specialities' are provided.".to_string(),
+        ));
+    }
+    validate_length("name", &doctor.name, MAX_NAME_LENGTH)?;
+    if doctor.specialities.len() > MAX_DOCTOR_SPECIALITIES {
+        return Err(Message::InvalidPayload(format!(
+            "'specialities' must have at most {} entries.",
+            MAX_DOCTOR_SPECIALITIES
+        )));
+    }
+    validate_optional_length(
+        "license_number",
+        &doctor.license_number,
+        MAX_IDENTIFIER_LENGTH,
+    )?;
+    if doctor.qualifications.len() > MAX_QUALIFICATIONS {
+        return Err(Message::InvalidPayload(format!(
+            "'qualifications' must have at most {} entries.",
+            MAX_QUALIFICATIONS
+        )));
+    }
+    for qualification in &doctor.qualifications {
+        if qualification.trim().is_empty() {
+            return Err(Message::InvalidPayload(
+                "Qualifications cannot be empty.".to_string(),
+            ));
+        }
+        validate_length("qualifications", qualification, MAX_LABEL_LENGTH)?;
+    }
+    if doctor.languages.len() > MAX_DOCTOR_LANGUAGES {
+        return Err(Message::InvalidPayload(format!(
+            "'languages' must have at most {} entries.",
+            MAX_DOCTOR_LANGUAGES
+        )));
+    }
+    for language in &doctor.languages {
+        validate_language_tag("languages", language)?;
+    }
+    if let Some(fee) = &doctor.consultation_fee {
+        if fee.currency.len() != 3 || !fee.currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(Message::InvalidPayload(
+                "'consultation_fee' must have a currency code such as 'USD'.".to_string(),
+            ));
+        }
+    }
+    validate_optional_length("bio", &doctor.bio, MAX_BIO_LENGTH)
+}
+
+fn validate_patient(patient: &Patient) -> Result<(), Message> {
+    if patient.name.is_empty() || patient.date_of_birth.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' and 'date_of_birth' are provided.".to_string(),
+        ));
+    }
+    validate_length("name", &patient.name, MAX_NAME_LENGTH)?;
+    validate_optional_length("preferred_name", &patient.preferred_name, MAX_NAME_LENGTH)?;
+    validate_date_of_birth(&patient.date_of_birth)?;
+    if let Some(GenderIdentity::Other(description)) = &patient.gender_identity {
+        validate_length("gender_identity", description, MAX_LABEL_LENGTH)?;
+    }
+    if let Some(phone) = &patient.phone {
+        validate_phone("phone", phone)?;
+    }
+    if let Some(email) = &patient.email {
+        validate_email("email", email)?;
+    }
+    if let Some(address) = &patient.address {
+        validate_address(address)?;
+    }
+    if let Some(language) = &patient.preferred_language {
+        validate_language_tag("preferred_language", language)?;
+    }
+    validate_optional_length(
+        "medical_record_number",
+        &patient.medical_record_number,
+        MAX_IDENTIFIER_LENGTH,
+    )?;
+    validate_optional_length("national_id", &patient.national_id, MAX_IDENTIFIER_LENGTH)
+}
+
+fn validate_date_of_birth(date_of_birth: &str) -> Result<(), Message> {
+    let date = NaiveDate::parse_from_str(date_of_birth, "%Y-%m-%d").map_err(|_| {
+        Message::InvalidPayload(
+            "'date_of_birth' must be a date formatted as YYYY-MM-DD.".to_string(),
+        )
+    })?;
+    if date > current_date() || date.year() < MIN_BIRTH_YEAR {
+        return Err(Message::InvalidPayload(
+            "'date_of_birth' must be between 1900-01-01 and today.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Accepts an optional leading '+', digits and common separators, with 7 to 15 digits
+fn validate_phone(field: &str, phone: &str) -> Result<(), Message> {
+    validate_length(field, phone, MAX_PHONE_LENGTH)?;
+    let digits = phone.chars().filter(|c| c.is_ascii_digit()).count();
+    let allowed = phone
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c.is_ascii_digit() || " -().".contains(c) || (c == '+' && i == 0));
+    if !allowed || !(7..=15).contains(&digits) {
+        return Err(Message::InvalidPayload(format!(
+            "'{}' must be a phone number with 7 to 15 digits.",
+            field
+        )));
+    }
+    Ok(())
+}
+
+fn validate_email(field: &str, email: &str) -> Result<(), Message> {
+    validate_length(field, email, MAX_EMAIL_LENGTH)?;
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(Message::InvalidPayload(format!(
+            "'{}' must be an email address.",
+            field
+        )));
+    }
+    Ok(())
+}
+
+fn validate_address(address: &Address) -> Result<(), Message> {
+    if address.line1.is_empty() || address.city.is_empty() || address.country.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure the address has 'line1', 'city' and 'country'.".to_string(),
+        ));
+    }
+    validate_length("address.line1", &address.line1, MAX_NAME_LENGTH)?;
+    validate_optional_length("address.line2", &address.line2, MAX_NAME_LENGTH)?;
+    validate_length("address.city", &address.city, MAX_LABEL_LENGTH)?;
+    validate_optional_length("address.region", &address.region, MAX_LABEL_LENGTH)?;
+    validate_optional_length(
+        "address.postal_code",
+        &address.postal_code,
+        MAX_IDENTIFIER_LENGTH,
+    )?;
+    validate_length("address.country", &address.country, MAX_LABEL_LENGTH)
+}
+
+// Checks the shape of a BCP 47 tag: alphanumeric subtags of 1 to 8 characters separated by '-'
+fn validate_language_tag(field: &str, language: &str) -> Result<(), Message> {
+    let valid = language.len() <= MAX_LANGUAGE_TAG_LENGTH
+        && language.split('-').enumerate().all(|(i, subtag)| {
+            (1..=8).contains(&subtag.len())
+                && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+                && (i > 0 || subtag.chars().all(|c| c.is_ascii_alphabetic()))
+        });
+    if !valid {
+        return Err(Message::InvalidPayload(format!(
+            "'{}' must be a language tag such as 'en' or 'pt-BR'.",
+            field
+        )));
+    }
+    Ok(())
+}
+
+fn validate_appointment(description: &str) -> Result<(), Message> {
+    if description.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure all fields are provided.".to_string(),
+        ));
+    }
+    validate_length("description", description, MAX_DESCRIPTION_LENGTH)
+}
+
+fn validate_appointment_duration(duration: u32) -> Result<(), Message> {
+    if !(1..=MAX_APPOINTMENT_DURATION).contains(&duration) {
+        return Err(Message::InvalidPayload(format!(
+            "'duration' must be between 1 and {} minutes.",
+            MAX_APPOINTMENT_DURATION
+        )));
+    }
+    Ok(())
+}
+
+fn validate_facility(facility: &Facility) -> Result<(), Message> {
+    if facility.name.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' is provided.".to_string(),
+        ));
+    }
+    validate_length("name", &facility.name, MAX_NAME_LENGTH)?;
+    if let Some(address) = &facility.address {
+        validate_address(address)?;
+    }
+    if let Some(phone) = &facility.phone {
+        validate_phone("phone", phone)?;
+    }
+    Ok(())
+}
+
+// Validation shared by departments and rooms, which only have a name to check
+fn validate_location_name(name: &str) -> Result<(), Message> {
+    if name.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' is provided.".to_string(),
+        ));
+    }
+    validate_length("name", name, MAX_LABEL_LENGTH)
+}
+
+fn validate_patient_record(diagnosis: &str, treatment: &str) -> Result<(), Message> {
+    if diagnosis.is_empty() || treatment.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'diagnosis' and 'treatment' are provided.".to_string(),
+        ));
+    }
+    validate_length("diagnosis", diagnosis, MAX_DIAGNOSIS_LENGTH)?;
+    validate_length("treatment", treatment, MAX_TREATMENT_LENGTH)
+}
+
+fn validate_medication(medication: &Medication) -> Result<(), Message> {
+    if medication.name.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' is provided.".to_string(),
+        ));
+    }
+    validate_length("name", &medication.name, MAX_NAME_LENGTH)?;
+    validate_optional_length("indication", &medication.indication, MAX_LABEL_LENGTH)?;
+    if let Some(dose) = &medication.dose {
+        if !dose.quantity.is_finite() || dose.quantity <= 0.0 {
+            return Err(Message::InvalidPayload(
+                "'dose.quantity' must be a positive number.".to_string(),
+            ));
+        }
+    }
+
+    let start_date = match &medication.start_date {
+        Some(start_date) => Some(parse_date(start_date).ok_or(Message::InvalidPayload(
+            "'start_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?),
+        None => None,
     };
-    PATIENTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, patient.clone()));
-    Ok(patient)
+    if let Some(end_date) = &medication.end_date {
+        let end_date = parse_date(end_date).ok_or(Message::InvalidPayload(
+            "'end_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?;
+        if matches!(start_date, Some(start_date) if end_date < start_date) {
+            return Err(Message::InvalidPayload(
+                "'end_date' must not be before 'start_date'.".to_string(),
+            ));
+        }
+    }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn get_patients() -> Result<Vec<Patient>, Message> {
-    PATIENTS_STORAGE.with(|storage| {
-        let patients: Vec<Patient> = storage
-            .borrow()
-            .iter()
-            .map(|(_, patient)| patient.clone())
-            .collect();
+// New medications must state how they are given
+fn validate_route(route: Route) -> Result<(), Message> {
+    if route == Route::Unknown {
+        return Err(Message::InvalidPayload(
+            "'route' must be provided.".to_string(),
+        ));
+    }
+    Ok(())
+}
 
-        if patients.is_empty() {
-            Err(Message::NotFound("No patients found".to_string()))
-        } else {
-            Ok(patients)
+fn validate_problem(problem: &Problem) -> Result<(), Message> {
+    if normalize_words(&problem.condition).is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'condition' is provided.".to_string(),
+        ));
+    }
+    validate_length("condition", &problem.condition, MAX_NAME_LENGTH)?;
+
+    let today = current_date();
+    let born_on = PATIENTS_STORAGE
+        .with(|storage| storage.borrow().get(&problem.patient_id))
+        .and_then(|patient| parse_date(&patient.date_of_birth));
+    let onset = match &problem.onset_date {
+        Some(onset_date) => Some(parse_date(onset_date).ok_or(Message::InvalidPayload(
+            "'onset_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?),
+        None => None,
+    };
+    if matches!(onset, Some(onset) if onset > today || matches!(born_on, Some(born_on) if onset < born_on))
+    {
+        return Err(Message::InvalidPayload(
+            "'onset_date' must be between the date of birth and today.".to_string(),
+        ));
+    }
+
+    match (&problem.resolved_date, problem.status) {
+        (Some(resolved_date), ProblemStatus::Resolved) => {
+            let resolved = parse_date(resolved_date).ok_or(Message::InvalidPayload(
+                "'resolved_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+            ))?;
+            if resolved > today || matches!(onset, Some(onset) if resolved < onset) {
+                return Err(Message::InvalidPayload(
+                    "'resolved_date' must be between the onset date and today.".to_string(),
+                ));
+            }
+            Ok(())
         }
-    })
+        (None, ProblemStatus::Resolved) => Err(Message::InvalidPayload(
+            "Ensure 'resolved_date' is provided for a resolved problem.".to_string(),
+        )),
+        (Some(_), _) => Err(Message::InvalidPayload(
+            "Only resolved problems have a 'resolved_date'.".to_string(),
+        )),
+        (None, _) => Ok(()),
+    }
 }
 
-#[ic_cdk::query]
-fn get_patient_by_id(id: u64) -> Result<Patient, Message> {
-    PATIENTS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, patient)| patient.id == id)
-            .map(|(_, patient)| patient.clone())
-            .ok_or(Message::NotFound("Patient not found".to_string()))
-    })
+fn validate_family_history(entry: &FamilyHistory) -> Result<(), Message> {
+    if normalize_words(&entry.condition).is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'condition' is provided.".to_string(),
+        ));
+    }
+    validate_length("condition", &entry.condition, MAX_NAME_LENGTH)?;
+    if let Relative::Other(relative) = &entry.relative {
+        if relative.trim().is_empty() {
+            return Err(Message::InvalidPayload(
+                "Ensure 'relative' describes the relative.".to_string(),
+            ));
+        }
+        validate_length("relative", relative, MAX_LABEL_LENGTH)?;
+    }
+    if matches!(entry.age_at_onset, Some(age) if age > MAX_AGE_AT_ONSET) {
+        return Err(Message::InvalidPayload(format!(
+            "'age_at_onset' must be at most {}.",
+            MAX_AGE_AT_ONSET
+        )));
+    }
+    validate_optional_length("notes", &entry.notes, MAX_DESCRIPTION_LENGTH)
 }
 
-#[ic_cdk::update]
-fn update_patient(id: u64, name: String, age: u32, gender: String) -> Result<Patient, Message> {
-    PATIENTS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        let id_entry = storage.iter().find(|(_, patient)| patient.id == id);
-        match id_entry {
-            Some((key, _)) => {
-                let updated_patient = Patient {
-                    id,
-                    name,
-                    age,
-                    gender,
-                    created_at: current_time(),
-                };
-                storage.insert(key, updated_patient.clone());
-                Ok(updated_patient)
+fn validate_social_history(social_history: &SocialHistory) -> Result<(), Message> {
+    if social_history.pack_years.is_some()
+        && !matches!(
+            social_history.smoking,
+            SmokingStatus::Former | SmokingStatus::Current
+        )
+    {
+        return Err(Message::InvalidPayload(
+            "Only current and former smokers have 'pack_years'.".to_string(),
+        ));
+    }
+    if social_history.drinks_per_week.is_some()
+        && matches!(
+            social_history.alcohol,
+            AlcoholUse::NonDrinker | AlcoholUse::Unknown
+        )
+    {
+        return Err(Message::InvalidPayload(
+            "Only drinkers have 'drinks_per_week'.".to_string(),
+        ));
+    }
+    validate_optional_length("occupation", &social_history.occupation, MAX_LABEL_LENGTH)
+}
+
+fn standardize_goals(goals: Vec<CareGoal>) -> Result<Vec<CareGoal>, Message> {
+    goals
+        .into_iter()
+        .map(|goal| {
+            Ok(CareGoal {
+                target: goal.target.map(standardize_target).transpose()?,
+                ..goal
+            })
+        })
+        .collect()
+}
+
+fn validate_care_plan(plan: &CarePlan) -> Result<(), Message> {
+    if plan.title.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'title' is provided.".to_string(),
+        ));
+    }
+    validate_length("title", &plan.title, MAX_NAME_LENGTH)?;
+    validate_optional_length("description", &plan.description, MAX_NOTE_SECTION_LENGTH)?;
+
+    let start = parse_date(&plan.start_date).ok_or(Message::InvalidPayload(
+        "'start_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+    ))?;
+    if let Some(end_date) = &plan.end_date {
+        let end = parse_date(end_date).ok_or(Message::InvalidPayload(
+            "'end_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?;
+        if end < start {
+            return Err(Message::InvalidPayload(
+                "'end_date' must not be before 'start_date'.".to_string(),
+            ));
+        }
+    }
+
+    if plan.goals.len() > MAX_CARE_GOALS {
+        return Err(Message::InvalidPayload(format!(
+            "'goals' must have at most {} entries.",
+            MAX_CARE_GOALS
+        )));
+    }
+    for goal in &plan.goals {
+        if goal.description.trim().is_empty() {
+            return Err(Message::InvalidPayload(
+                "Ensure each goal has a 'description'.".to_string(),
+            ));
+        }
+        validate_length("goals", &goal.description, MAX_DESCRIPTION_LENGTH)?;
+        if let Some(target_date) = &goal.target_date {
+            let target_date = parse_date(target_date).ok_or(Message::InvalidPayload(
+                "'target_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+            ))?;
+            if target_date < start {
+                return Err(Message::InvalidPayload(
+                    "A goal's 'target_date' must not be before 'start_date'.".to_string(),
+                ));
             }
-            None => Err(Message::NotFound("Patient not found".to_string())),
         }
-    })
+    }
+
+    if plan.interventions.len() > MAX_CARE_INTERVENTIONS {
+        return Err(Message::InvalidPayload(format!(
+            "'interventions' must have at most {} entries.",
+            MAX_CARE_INTERVENTIONS
+        )));
+    }
+    for intervention in &plan.interventions {
+        if intervention.trim().is_empty() {
+            return Err(Message::InvalidPayload(
+                "Interventions must not be empty.".to_string(),
+            ));
+        }
+        validate_length("interventions", intervention, MAX_DESCRIPTION_LENGTH)?;
+    }
+    Ok(())
+}
+
+fn validate_care_task(task: &CareTask) -> Result<(), Message> {
+    if task.description.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'description' is provided.".to_string(),
+        ));
+    }
+    validate_length("description", &task.description, MAX_DESCRIPTION_LENGTH)?;
+    if parse_date(&task.due_date).is_none() {
+        return Err(Message::InvalidPayload(
+            "'due_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_allergy(substance: &str, reaction: &Option<String>) -> Result<(), Message> {
+    if substance.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'substance' is provided.".to_string(),
+        ));
+    }
+    validate_length("substance", substance, MAX_LABEL_LENGTH)?;
+    validate_optional_length("reaction", reaction, MAX_DESCRIPTION_LENGTH)
+}
+
+fn validate_lab_tests(tests: &[String]) -> Result<(), Message> {
+    if tests.is_empty() || tests.len() > MAX_LAB_TESTS {
+        return Err(Message::InvalidPayload(format!(
+            "Ensure between 1 and {} tests are ordered.",
+            MAX_LAB_TESTS
+        )));
+    }
+    for test in tests {
+        if test.trim().is_empty() {
+            return Err(Message::InvalidPayload(
+                "Test names must not be empty.".to_string(),
+            ));
+        }
+        validate_length("tests", test, MAX_TEST_NAME_LENGTH)?;
+    }
+    Ok(())
+}
+
+fn validate_lab_result(
+    order: &LabOrder,
+    analyte: &str,
+    value: f64,
+    unit: &str,
+    reference_range: &Option<ReferenceRange>,
+    performed_at: u64,
+    comment: &Option<String>,
+) -> Result<(), Message> {
+    if analyte.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'analyte' is provided.".to_string(),
+        ));
+    }
+    validate_length("analyte", analyte, MAX_TEST_NAME_LENGTH)?;
+    validate_length("unit", unit, MAX_LABEL_LENGTH)?;
+    validate_optional_length("comment", comment, MAX_LABEL_LENGTH)?;
+    if !value.is_finite() {
+        return Err(Message::InvalidPayload(
+            "'value' must be a finite number.".to_string(),
+        ));
+    }
+    if let Some(range) = reference_range {
+        validate_reference_range(range)?;
+    }
+    if matches!(order.collected_at, Some(collected_at) if performed_at < collected_at)
+        || performed_at > current_time()
+    {
+        return Err(Message::InvalidPayload(
+            "'performed_at' must be between collection and now.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Bounds must be finite and ordered critical low <= low <= high <= critical high
+fn validate_reference_range(range: &ReferenceRange) -> Result<(), Message> {
+    let bounds: Vec<f64> = [
+        range.critical_low,
+        range.low,
+        range.high,
+        range.critical_high,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if bounds.iter().any(|bound| !bound.is_finite()) || bounds.windows(2).any(|w| w[0] > w[1]) {
+        return Err(Message::InvalidPayload(
+            "'reference_range' bounds must be finite and in increasing order.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_immunization(immunization: &Immunization) -> Result<(), Message> {
+    if normalize_words(&immunization.vaccine).is_empty() || immunization.dose_number == 0 {
+        return Err(Message::InvalidPayload(
+            "Ensure 'vaccine' and a 'dose_number' from 1 are provided.".to_string(),
+        ));
+    }
+    validate_length("vaccine", &immunization.vaccine, MAX_VACCINE_NAME_LENGTH)?;
+    validate_optional_length(
+        "lot_number",
+        &immunization.lot_number,
+        MAX_IDENTIFIER_LENGTH,
+    )?;
+    if let Some(AdministrationSite::Other(site)) = &immunization.site {
+        validate_length("site", site, MAX_LABEL_LENGTH)?;
+    }
+
+    let administered_on =
+        parse_date(&immunization.administered_on).ok_or(Message::InvalidPayload(
+            "'administered_on' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?;
+    let born_on = PATIENTS_STORAGE
+        .with(|storage| storage.borrow().get(&immunization.patient_id))
+        .and_then(|patient| parse_date(&patient.date_of_birth));
+    if administered_on > current_date()
+        || matches!(born_on, Some(born_on) if administered_on < born_on)
+    {
+        return Err(Message::InvalidPayload(
+            "'administered_on' must be between the date of birth and today.".to_string(),
+        ));
+    }
+
+    // A patient receives each dose of a vaccine once
+    let vaccine = normalize_words(&immunization.vaccine);
+    let duplicate = load_immunizations(immunization.patient_id)
+        .into_iter()
+        .any(|other| {
+            other.id != immunization.id
+                && other.dose_number == immunization.dose_number
+                && normalize_words(&other.vaccine) == vaccine
+        });
+    if duplicate {
+        return Err(Message::InvalidPayload(format!(
+            "Dose {} of '{}' is already recorded for this patient.",
+            immunization.dose_number, immunization.vaccine
+        )));
+    }
+    Ok(())
+}
+
+fn validate_clinical_note(note: &ClinicalNote) -> Result<(), Message> {
+    validate_note_sections(
+        &note.subjective,
+        &note.objective,
+        &note.assessment,
+        &note.plan,
+    )
+}
+
+fn validate_note_sections(
+    subjective: &str,
+    objective: &str,
+    assessment: &str,
+    plan: &str,
+) -> Result<(), Message> {
+    validate_length("subjective", subjective, MAX_NOTE_SECTION_LENGTH)?;
+    validate_length("objective", objective, MAX_NOTE_SECTION_LENGTH)?;
+    validate_length("assessment", assessment, MAX_NOTE_SECTION_LENGTH)?;
+    validate_length("plan", plan, MAX_NOTE_SECTION_LENGTH)
+}
+
+fn validate_document(payload: &DocumentPayload) -> Result<(), Message> {
+    if payload.title.trim().is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'title' is provided.".to_string(),
+        ));
+    }
+    validate_length("title", &payload.title, MAX_NAME_LENGTH)?;
+    validate_length("mime_type", &payload.mime_type, MAX_MIME_TYPE_LENGTH)?;
+    if !ALLOWED_DOCUMENT_TYPES.contains(&payload.mime_type.to_lowercase().as_str()) {
+        return Err(Message::InvalidPayload(format!(
+            "'mime_type' must be one of: {}.",
+            ALLOWED_DOCUMENT_TYPES.join(", ")
+        )));
+    }
+    if payload.size == 0 || payload.size > MAX_DOCUMENT_SIZE {
+        return Err(Message::InvalidPayload(format!(
+            "'size' must be between 1 and {} bytes.",
+            MAX_DOCUMENT_SIZE
+        )));
+    }
+    if let Some(sha256) = &payload.sha256 {
+        if sha256.len() != 64 || hex_decode(sha256).is_none() {
+            return Err(Message::InvalidPayload(
+                "'sha256' must be 64 hexadecimal characters.".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_observation(observed_at: &u64, note: &Option<String>) -> Result<(), Message> {
+    if *observed_at > current_time() {
+        return Err(Message::InvalidPayload(
+            "'observed_at' must not be in the future.".to_string(),
+        ));
+    }
+    validate_optional_length("note", note, MAX_DESCRIPTION_LENGTH)
+}
+
+fn validate_emergency_contact(
+    name: &str,
+    relationship: &Relationship,
+    phones: &[String],
+) -> Result<(), Message> {
+    if name.is_empty() || phones.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'name' and at least one phone number are provided.".to_string(),
+        ));
+    }
+    validate_length("name", name, MAX_NAME_LENGTH)?;
+    if let Relationship::Other(description) = relationship {
+        validate_length("relationship", description, MAX_LABEL_LENGTH)?;
+    }
+    if phones.len() > MAX_CONTACT_PHONES {
+        return Err(Message::InvalidPayload(format!(
+            "'phones' must have at most {} entries.",
+            MAX_CONTACT_PHONES
+        )));
+    }
+    for phone in phones {
+        validate_phone("phones", phone)?;
+    }
+    Ok(())
+}
+
+fn validate_insurance_coverage(
+    payer: &str,
+    plan: &str,
+    member_id: &str,
+    group_number: &Option<String>,
+    effective_date: &str,
+    expiry_date: &Option<String>,
+) -> Result<(), Message> {
+    if payer.is_empty() || plan.is_empty() || member_id.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Ensure 'payer', 'plan' and 'member_id' are provided.".to_string(),
+        ));
+    }
+    validate_length("payer", payer, MAX_NAME_LENGTH)?;
+    validate_length("plan", plan, MAX_NAME_LENGTH)?;
+    validate_length("member_id", member_id, MAX_IDENTIFIER_LENGTH)?;
+    validate_optional_length("group_number", group_number, MAX_IDENTIFIER_LENGTH)?;
+
+    let effective = parse_date(effective_date).ok_or(Message::InvalidPayload(
+        "'effective_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+    ))?;
+    if let Some(expiry_date) = expiry_date {
+        let expiry = parse_date(expiry_date).ok_or(Message::InvalidPayload(
+            "'expiry_date' must be a date formatted as YYYY-MM-DD.".to_string(),
+        ))?;
+        if expiry < effective {
+            return Err(Message::InvalidPayload(
+                "'expiry_date' must not be before 'effective_date'.".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_optional_length(
+    field: &str,
+    value: &Option<String>,
+    max_length: usize,
+) -> Result<(), Message> {
+    match value {
+        Some(value) => validate_length(field, value, max_length),
+        None => Ok(()),
+    }
+}
+
+fn validate_length(field: &str, value: &str, max_length: usize) -> Result<(), Message> {
+    if value.len() > max_length {
+        return Err(Message::InvalidPayload(format!(
+            "'{}' must be at most {} bytes, got {}.",
+            field,
+            max_length,
+            value.len()
+        )));
+    }
+    Ok(())
+}
+
+// Benchmarks of the indexed queries, built only with `--features bench` and run by
+// bench.sh against a local replica. They report the instructions each query uses once the
+// canister holds many rows.
+#[cfg(feature = "bench")]
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct BenchResult {
+    name: String,
+    // Rows the query returned
+    rows: u64,
+    instructions: u64,
 }
 
+// Doctors the seeded appointments are spread over
+#[cfg(feature = "bench")]
+const BENCH_DOCTORS: usize = 100;
+
+// Function to add `count` patients, each with a medication, a patient record listing it and
+// one appointment half an hour after the previous one. Seeding is split over several calls to stay within the instruction limit
+// of a single message.
+#[cfg(feature = "bench")]
 #[ic_cdk::update]
-fn delete_patient(id: u64) -> Result<(), Message> {
-    PATIENTS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if storage.remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Message::NotFound("Patient not found".to_string()))
+fn bench_seed(count: u32) -> Result<(), Message> {
+    ensure_controller()?;
+    let now = current_time();
+    let mut doctors: Vec<DoctorId> =
+        DOCTORS_STORAGE.with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    while doctors.len() < BENCH_DOCTORS {
+        let doctor = Doctor {
+            id: DoctorId(next_id(EntityKind::Doctor)),
+            name: format!("Bench doctor {}", doctors.len()),
+            created_at: now,
+            ..Default::default()
+        };
+        save_doctor(&doctor);
+        doctors.push(doctor.id);
+    }
+    let mut date_time = APPOINTMENTS_BY_DATE
+        .with(|index| index.borrow().last_key_value())
+        .map(|((date_time, _), _)| date_time)
+        .unwrap_or(now);
+
+    for n in 0..count as usize {
+        let patient = Patient {
+            id: PatientId(next_id(EntityKind::Patient)),
+            name: format!("Bench patient {}", n),
+            date_of_birth: "1980-01-01".to_string(),
+            created_at: now,
+            ..Default::default()
+        };
+        save_patient(&patient);
+        let doctor_id = doctors[n % doctors.len()];
+        let medication = Medication {
+            id: MedicationId(next_id(EntityKind::Medication)),
+            name: "Amoxicillin".to_string(),
+            patient_id: patient.id,
+            doctor_id: Some(doctor_id),
+            created_at: now,
+            ..Default::default()
+        };
+        save_medication(&medication);
+        save_patient_record(&PatientRecord {
+            id: PatientRecordId(next_id(EntityKind::PatientRecord)),
+            patient_id: patient.id,
+            doctor_id,
+            diagnosis: "Bench diagnosis".to_string(),
+            treatment: "Bench treatment".to_string(),
+            medications: vec![medication.id],
+            created_at: now,
+            ..Default::default()
+        });
+        date_time += 30 * NANOS_PER_MINUTE;
+        save_appointment(&Appointment {
+            id: AppointmentId(next_id(EntityKind::Appointment)),
+            patient_id: patient.id,
+            doctor_id,
+            date_time,
+            duration: 30,
+            description: "Check-up".to_string(),
+            created_at: now,
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+// Function to measure the indexed queries against the seeded data, with the linear scan
+// they replaced for comparison
+#[cfg(feature = "bench")]
+#[ic_cdk::query]
+fn bench_queries() -> Result<Vec<BenchResult>, Message> {
+    ensure_controller()?;
+    let appointment = APPOINTMENTS_STORAGE
+        .with(|storage| storage.borrow().last_key_value())
+        .map(|(_, appointment)| appointment)
+        .ok_or(Message::NotFound(
+            "Seed the benchmark data with bench_seed first".to_string(),
+        ))?;
+
+    let mut results = Vec::new();
+    let mut measure = |name: &str, query: &dyn Fn() -> usize| {
+        let start = ic_cdk::api::instruction_counter();
+        let rows = query();
+        results.push(BenchResult {
+            name: name.to_string(),
+            rows: rows as u64,
+            instructions: ic_cdk::api::instruction_counter() - start,
+        });
+    };
+    measure("get_patient_by_id", &|| {
+        get_patient_by_id(appointment.patient_id).iter().count()
+    });
+    measure("get_doctor_id", &|| {
+        get_doctor_id(appointment.doctor_id).iter().count()
+    });
+    measure("get_appointment_id", &|| {
+        get_appointment_id(appointment.id).iter().count()
+    });
+    measure("create_appointment foreign key checks", &|| {
+        (ensure_patient_exists(appointment.patient_id).is_ok()
+            && ensure_doctor_exists(appointment.doctor_id).is_ok()) as usize
+    });
+    measure("get_patient_appointments", &|| {
+        get_patient_appointments(appointment.patient_id)
+            .map(|appointments| appointments.len())
+            .unwrap_or(0)
+    });
+    measure("get_doctor_appointments", &|| {
+        get_doctor_appointments(appointment.doctor_id)
+            .map(|appointments| appointments.len())
+            .unwrap_or(0)
+    });
+    measure("get_records_for_patient", &|| {
+        get_records_for_patient(appointment.patient_id)
+            .map(|records| records.len())
+            .unwrap_or(0)
+    });
+    measure("get_medications_for_patient", &|| {
+        get_medications_for_patient(appointment.patient_id)
+            .map(|medications| medications.len())
+            .unwrap_or(0)
+    });
+    measure("get_appointments_between (one day)", &|| {
+        get_appointments_between(appointment.date_time - NANOS_PER_DAY, appointment.date_time)
+            .map(|appointments| appointments.len())
+            .unwrap_or(0)
+    });
+    measure("patient appointments by linear scan", &|| {
+        APPOINTMENTS_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, other)| other.patient_id == appointment.patient_id)
+                .count()
+        })
+    });
+    Ok(results)
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frequency_code_accepts_common_codes() {
+        assert!(parse_frequency_code("QD").ok() == Some(Frequency::OnceDaily));
+        assert!(parse_frequency_code("BID").ok() == Some(Frequency::TwiceDaily));
+        assert!(parse_frequency_code("TID").ok() == Some(Frequency::ThreeTimesDaily));
+        assert!(parse_frequency_code("QID").ok() == Some(Frequency::FourTimesDaily));
+        assert!(parse_frequency_code("PRN").ok() == Some(Frequency::AsNeeded));
+        assert!(parse_frequency_code("q8h").ok() == Some(Frequency::EveryHours(8)));
+        assert!(parse_frequency_code("q1h").ok() == Some(Frequency::EveryHours(1)));
+        assert!(parse_frequency_code("q72h").ok() == Some(Frequency::EveryHours(72)));
+    }
+
+    #[test]
+    fn parse_frequency_code_ignores_case_and_whitespace() {
+        assert!(parse_frequency_code("bid").ok() == Some(Frequency::TwiceDaily));
+        assert!(parse_frequency_code("  Prn\t").ok() == Some(Frequency::AsNeeded));
+        assert!(parse_frequency_code(" Q12H ").ok() == Some(Frequency::EveryHours(12)));
+    }
+
+    #[test]
+    fn parse_frequency_code_rejects_malformed_codes() {
+        for code in [
+            "",
+            "  ",
+            "B ID",
+            "BIDS",
+            "q",
+            "qh",
+            "q0h",
+            "q73h",
+            "q-8h",
+            "q+8h",
+            "q8",
+            "8h",
+            "q 8h",
+            "q8.5h",
+            "q99999999999h",
+            "twice a day",
+        ] {
+            assert!(
+                matches!(parse_frequency_code(code), Err(Message::InvalidPayload(_))),
+                "{code:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_legacy_frequency_reads_migrated_text() {
+        let cases = [
+            ("BID", Frequency::TwiceDaily),
+            ("q6h", Frequency::EveryHours(6)),
+            ("once daily", Frequency::OnceDaily),
+            ("Daily", Frequency::OnceDaily),
+            ("OD", Frequency::OnceDaily),
+            ("twice a day", Frequency::TwiceDaily),
+            ("Twice-Daily", Frequency::TwiceDaily),
+            ("2x daily", Frequency::TwiceDaily),
+            ("  twice   a  day ", Frequency::TwiceDaily),
+            ("b.d.", Frequency::TwiceDaily),
+            ("b.i.d.", Frequency::TwiceDaily),
+            ("t.i.d", Frequency::ThreeTimesDaily),
+            ("p.r.n.", Frequency::AsNeeded),
+            ("three times a day", Frequency::ThreeTimesDaily),
+            ("t.d.s", Frequency::ThreeTimesDaily),
+            ("4 times a day", Frequency::FourTimesDaily),
+            ("as needed", Frequency::AsNeeded),
+            ("hourly", Frequency::EveryHours(1)),
+            ("every 8 hours", Frequency::EveryHours(8)),
+            ("Every 12 hrs", Frequency::EveryHours(12)),
+        ];
+        for (text, expected) in cases {
+            assert!(
+                parse_legacy_frequency(text) == Some(expected),
+                "{text:?} should parse"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_legacy_frequency_leaves_ambiguous_text_unset() {
+        for text in [
+            "",
+            "sometimes",
+            "twice",
+            "every 0 hours",
+            "every 100 hours",
+            "every eight hours",
+            "every 8 days",
+            "twice a day with food",
+        ] {
+            assert!(
+                parse_legacy_frequency(text).is_none(),
+                "{text:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_legacy_dose_reads_migrated_text() {
+        let cases = [
+            ("500mg", 500.0, DoseUnit::Mg),
+            ("500 mg", 500.0, DoseUnit::Mg),
+            (" 500 MG ", 500.0, DoseUnit::Mg),
+            ("1g", 1.0, DoseUnit::G),
+            ("0.5 ml", 0.5, DoseUnit::Ml),
+            ("100 mcg", 100.0, DoseUnit::Mcg),
+            ("10 units", 10.0, DoseUnit::Unit),
+            ("2 tablets", 2.0, DoseUnit::Tablet),
+            ("1 Capsule", 1.0, DoseUnit::Capsule),
+            ("2 puffs", 2.0, DoseUnit::Puff),
+        ];
+        for (text, quantity, unit) in cases {
+            assert!(
+                parse_legacy_dose(text) == Some(Dose { quantity, unit }),
+                "{text:?} should parse"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_legacy_dose_leaves_ambiguous_text_unset() {
+        for text in [
+            "",
+            "mg",
+            "500",
+            "0 mg",
+            "-5 mg",
+            "1.2.3 mg",
+            "500 mg twice",
+            "two tablets",
+            "500 bottles",
+            "5-10 mg",
+        ] {
+            assert!(
+                parse_legacy_dose(text).is_none(),
+                "{text:?} should not parse"
+            );
+        }
+    }
+
+    fn medication_between(start_date: Option<&str>, end_date: Option<&str>) -> Medication {
+        Medication {
+            start_date: start_date.map(str::to_string),
+            end_date: end_date.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn medications_overlap_compares_date_windows_from_today() {
+        let today = parse_date("2024-06-15").unwrap();
+        let open = medication_between(None, None);
+        let cases = [
+            (medication_between(Some("2024-01-01"), None), true),
+            (
+                medication_between(Some("2024-01-01"), Some("2024-06-15")),
+                true,
+            ),
+            (
+                medication_between(Some("2024-01-01"), Some("2024-06-14")),
+                false,
+            ),
+            (medication_between(Some("2025-01-01"), None), true),
+        ];
+        for (medication, expected) in &cases {
+            assert_eq!(medications_overlap(medication, &open, today), *expected);
+            assert_eq!(medications_overlap(&open, medication, today), *expected);
         }
-    })
-}
 
-#[ic_cdk::update]
-fn create_appointment(payload: AppointmentPayload) -> Result<Appointment, Message> {
-    if payload.description.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Ensure all fields are provided.".to_string(),
-        ));
+        let july = medication_between(Some("2024-07-01"), Some("2024-07-31"));
+        let august = medication_between(Some("2024-08-01"), None);
+        assert!(!medications_overlap(&july, &august, today));
+        let late_july = medication_between(Some("2024-07-31"), Some("2024-08-10"));
+        assert!(medications_overlap(&july, &late_july, today));
+        // Both ended before today, even if they were taken together
+        let past = medication_between(Some("2024-01-01"), Some("2024-03-01"));
+        assert!(!medications_overlap(&past, &past, today));
     }
 
-    // Validate the patient id
-    let patient = PATIENTS_STORAGE.with(|storage| {
-        storage
-            .borrow()
+    #[test]
+    fn downsample_never_returns_more_points_than_buckets() {
+        // 10 does not divide by 3, so the buckets are 4 wide: [0, 4), [4, 8) and [8, 10]
+        let observations: Vec<(u64, ObservationValue)> = (0..=10)
+            .map(|at| (at, ObservationValue::Quantity(at as f64)))
+            .collect();
+        let points = downsample(observations, 0, 10, 3);
+        let bounds: Vec<(u64, u64, u32)> = points
             .iter()
-            .find(|(_, patient)| patient.id == payload.patient_id)
-            .map(|(_, patient)| patient.clone())
-    });
-    if patient.is_none() {
-        return Err(Message::NotFound("Patient not found".to_string()));
+            .map(|point| (point.start, point.end, point.count))
+            .collect();
+        assert_eq!(bounds, vec![(0, 4, 4), (4, 8, 4), (8, 10, 3)]);
+        assert_eq!(points[2].primary.max, 10.0);
     }
 
-    // Validate the doctor id
-    let doctor = DOCTORS_STORAGE.with(|storage| {
-        storage
-            .borrow()
+    #[test]
+    fn downsample_puts_values_at_the_end_of_the_range_in_the_last_bucket() {
+        // The range divides evenly, and a value at `to` must not open a fourth bucket
+        let observations = vec![
+            (0, ObservationValue::Quantity(1.0)),
+            (9, ObservationValue::Quantity(2.0)),
+            (12, ObservationValue::Quantity(3.0)),
+        ];
+        let points = downsample(observations, 0, 12, 3);
+        let bounds: Vec<(u64, u32)> = points
             .iter()
-            .find(|(_, doctor)| doctor.id == payload.doctor_id)
-            .map(|(_, doctor)| doctor.clone())
-    });
-    if doctor.is_none() {
-        return Err(Message::NotFound("Doctor not found".to_string()));
+            .map(|point| (point.start, point.count))
+            .collect();
+        assert_eq!(bounds, vec![(0, 1), (8, 2)]);
+        // More buckets than nanoseconds in the range
+        let points = downsample(vec![(5, ObservationValue::Quantity(1.0))], 3, 5, 10);
+        assert_eq!(points.len(), 1);
+        assert_eq!((points[0].start, points[0].end), (5, 5));
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    #[test]
+    fn abnormal_flag_uses_inclusive_bounds() {
+        let range = ReferenceRange {
+            low: Some(3.5),
+            high: Some(5.0),
+            critical_low: Some(2.5),
+            critical_high: Some(6.5),
+        };
+        let cases = [
+            (2.4, AbnormalFlag::CriticalLow),
+            (2.5, AbnormalFlag::Low),
+            (3.4, AbnormalFlag::Low),
+            (3.5, AbnormalFlag::Normal),
+            (4.2, AbnormalFlag::Normal),
+            (5.0, AbnormalFlag::Normal),
+            (5.1, AbnormalFlag::High),
+            (6.5, AbnormalFlag::High),
+            (6.6, AbnormalFlag::CriticalHigh),
+        ];
+        for (value, expected) in cases {
+            assert!(
+                abnormal_flag(value, &range) == expected,
+                "{value} is flagged wrongly"
+            );
+        }
+    }
 
-    let appointment = Appointment {
-        id,
-        patient_id: payload.patient_id,
-        doctor_id: payload.doctor_id,
-        date_time: payload.date_time,
-        duration: payload.duration,
-        description: payload.description,
-        created_at: current_time(),
-        updated_at: None,
-    };
-    APPOINTMENTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, appointment.clone()));
-    Ok(appointment)
-}
+    #[test]
+    fn abnormal_flag_skips_missing_bounds() {
+        let high_only = ReferenceRange {
+            high: Some(200.0),
+            ..Default::default()
+        };
+        assert!(abnormal_flag(-1000.0, &high_only) == AbnormalFlag::Normal);
+        assert!(abnormal_flag(200.5, &high_only) == AbnormalFlag::High);
+        // A critical bound alone still flags, without a normal range
+        let critical_only = ReferenceRange {
+            critical_low: Some(50.0),
+            ..Default::default()
+        };
+        assert!(abnormal_flag(49.9, &critical_only) == AbnormalFlag::CriticalLow);
+        assert!(abnormal_flag(50.0, &critical_only) == AbnormalFlag::Normal);
+        assert!(abnormal_flag(1.0, &ReferenceRange::default()) == AbnormalFlag::Normal);
+    }
 
-#[ic_cdk::query]
-fn get_appointments() -> Result<Vec<Appointment>, Message> {
-    APPOINTMENTS_STORAGE.with(|storage| {
-        let appointments: Vec<Appointment> = storage
-            .borrow()
-            .iter()
-            .map(|(_, appointment)| appointment.clone())
-            .collect();
+    fn patient(name: &str, date_of_birth: &str) -> Patient {
+        Patient {
+            name: name.to_string(),
+            date_of_birth: date_of_birth.to_string(),
+            ..Default::default()
+        }
+    }
 
-        if appointments.is_empty() {
-            Err(Message::NotFound("No appointments found".to_string()))
-        } else {
-            Ok(appointments)
+    #[test]
+    fn duplicate_score_grades_name_matches() {
+        let jane = patient("Jane Mary Doe", "1990-05-01");
+        let cases = [
+            ("jane  mary DOE", 40),
+            ("Doe, Jane Mary", 35),
+            ("J. Doe", 20),
+            ("Janet Doe", 20),
+            ("Mary Doe", 0),
+            ("Jane Smith", 0),
+        ];
+        for (name, expected) in cases {
+            let (score, matched_fields) = duplicate_score(&jane, &patient(name, "2000-01-01"));
+            assert_eq!(score, expected, "{name:?}");
+            assert_eq!(matched_fields.contains(&"name".to_string()), expected > 0);
         }
-    })
-}
+    }
 
-#[ic_cdk::query]
-fn get_appointment_id(id: u64) -> Result<Appointment, Message> {
-    APPOINTMENTS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, appointment)| appointment.id == id)
-            .map(|(_, appointment)| appointment.clone())
-            .ok_or(Message::NotFound("Appointment not found".to_string()))
-    })
-}
+    #[test]
+    fn duplicate_score_compares_birth_dates_and_contact_details() {
+        let a = Patient {
+            phone: Some("+1 (555) 010-2030".to_string()),
+            email: Some(" Jane@Example.com".to_string()),
+            ..patient("Jane Doe", "1990-05-01")
+        };
+        let b = Patient {
+            phone: Some("15550102030".to_string()),
+            email: Some("jane@example.com".to_string()),
+            ..patient("Jane Doe", "1990-05-01")
+        };
+        let (score, matched_fields) = duplicate_score(&a, &b);
+        assert_eq!(score, 100);
+        assert_eq!(
+            matched_fields,
+            vec!["name", "date_of_birth", "phone", "email"]
+        );
 
-#[ic_cdk::update]
-fn update_appointment(
-    id: u64,
-    patient_id: u64,
-    doctor_id: u64,
-    date_time: u64,
-    duration: u32,
-    description: String,
-) -> Result<Appointment, Message> {
-    APPOINTMENTS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        let id_entry = storage.iter().find(|(_, appointment)| appointment.id == id);
-        match id_entry {
-            Some((key, _)) => {
-                let updated_appointment = Appointment {
-                    id,
-                    patient_id,
-                    doctor_id,
-                    date_time,
-                    duration,
-                    description,
-                    created_at: current_time(),
-                    updated_at: Some(current_time()),
-                };
-                storage.insert(key, updated_appointment.clone());
-                Ok(updated_appointment)
-            }
-            None => Err(Message::NotFound("Appointment not found".to_string())),
-        }
-    })
-}
+        // An estimated birth date only matches on the year
+        let estimated = Patient {
+            date_of_birth_estimated: true,
+            ..patient("Someone Else", "1990-01-01")
+        };
+        assert_eq!(duplicate_score(&a, &estimated).0, 10);
+        assert_eq!(
+            duplicate_score(&a, &patient("Someone Else", "1990-01-01")).0,
+            0
+        );
+        assert_eq!(
+            duplicate_score(&a, &patient("Someone Else", "1991-05-01")).0,
+            0
+        );
 
-#[ic_cdk::update]
-fn delete_appointment(id: u64) -> Result<(), Message> {
-    APPOINTMENTS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if storage.remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Message::NotFound("Appointment not found".to_string()))
+        // Blank contact details never match each other
+        let blank = Patient {
+            phone: Some("n/a".to_string()),
+            email: Some("  ".to_string()),
+            ..patient("Someone Else", "2000-01-01")
+        };
+        assert_eq!(
+            duplicate_score(&blank, &blank).1,
+            vec!["name", "date_of_birth"]
+        );
+    }
+
+    #[test]
+    fn allergy_matches_whole_words_ignoring_case() {
+        let cases = [
+            ("Penicillin", "penicillin", true),
+            ("penicillin", "Penicillin V Potassium", true),
+            ("PENICILLIN V", "penicillin", true),
+            ("Sulfa drugs", "sulfa", true),
+            ("co-trimoxazole", "Co Trimoxazole 960mg", true),
+            ("Penicillin", "Amoxicillin", false),
+            ("cillin", "Penicillin", false),
+            ("Aspirin", "Aspirin-free cold tablets", true),
+            ("Latex", "Ibuprofen", false),
+            ("", "Ibuprofen", false),
+            ("Ibuprofen", "", false),
+        ];
+        for (substance, medication_name, expected) in cases {
+            assert_eq!(
+                allergy_matches(substance, medication_name),
+                expected,
+                "{substance:?} and {medication_name:?}"
+            );
         }
-    })
-}
+    }
 
-#[ic_cdk::update]
-fn create_patient_record(payload: PatientRecordPayload) -> Result<PatientRecord, Message> {
-    if payload.diagnosis.is_empty() || payload.treatment.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Ensure 'diagnosis' and 'treatment' are provided.".to_string(),
-        ));
+    fn chunked_map() -> ChunkedMap<u64, Vec<u8>> {
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        ChunkedMap::init(manager.get(MemoryId::new(0)))
     }
 
-    // Validate the patient id
-    let patient = PATIENTS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, patient)| patient.id == payload.patient_id)
-            .map(|(_, patient)| patient.clone())
-    });
-    if patient.is_none() {
-        return Err(Message::NotFound("Patient not found".to_string()));
+    fn chunk_count(map: &ChunkedMap<u64, Vec<u8>>, key: u64) -> usize {
+        map.chunks.range((key, 0)..=(key, u32::MAX)).count()
     }
 
-    // Validate the doctor id
-    let doctor = DOCTORS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, doctor)| doctor.id == payload.doctor_id)
-            .map(|(_, doctor)| doctor.clone())
-    });
-    if doctor.is_none() {
-        return Err(Message::NotFound("Doctor not found".to_string()));
+    #[test]
+    fn chunked_map_insert_splits_values_into_chunks() {
+        let mut map = chunked_map();
+        let value: Vec<u8> = (0..3 * CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        assert!(map.insert(1, value.clone()).is_none());
+        assert!(map.contains_key(&1));
+        assert!(map.get(&1) == Some(value));
+        assert_eq!(chunk_count(&map, 1), 4);
+        assert!(map.get(&2).is_none());
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    #[test]
+    fn chunked_map_overwrite_with_fewer_chunks_drops_stray_chunks() {
+        let mut map = chunked_map();
+        let long = vec![7u8; 4 * CHUNK_SIZE];
+        let short = vec![9u8; CHUNK_SIZE / 2];
+        map.insert(1, long.clone());
+        map.insert(2, long.clone());
 
-    let patient_record = PatientRecord {
-        id,
-        patient_id: payload.patient_id,
-        doctor_id: payload.doctor_id,
-        diagnosis: payload.diagnosis,
-        treatment: payload.treatment,
-        medications: payload.medications,
-        created_at: current_time(),
-    };
-    PATIENT_RECORDS_STORAGE.with(|storage| storage.borrow_mut().insert(id, patient_record.clone()));
-    Ok(patient_record)
-}
+        assert!(map.insert(1, short.clone()) == Some(long.clone()));
+        assert!(map.get(&1) == Some(short.clone()));
+        assert_eq!(chunk_count(&map, 1), 1);
+        assert!(map.get(&2) == Some(long.clone()));
+        assert_eq!(chunk_count(&map, 2), 4);
 
-#[ic_cdk::query]
-fn get_patient_records() -> Result<Vec<PatientRecord>, Message> {
-    PATIENT_RECORDS_STORAGE.with(|storage| {
-        let patient_records: Vec<PatientRecord> = storage
-            .borrow()
-            .iter()
-            .map(|(_, patient_record)| patient_record.clone())
-            .collect();
+        let entries: Vec<(u64, Vec<u8>)> = map.iter().collect();
+        assert!(entries == vec![(1, short), (2, long)]);
+    }
 
-        if patient_records.is_empty() {
-            Err(Message::NotFound("No patient records found".to_string()))
-        } else {
-            Ok(patient_records)
-        }
-    })
-}
+    #[test]
+    fn chunked_map_remove_deletes_every_chunk() {
+        let mut map = chunked_map();
+        let value = vec![3u8; 2 * CHUNK_SIZE + 1];
+        map.insert(1, value.clone());
+        map.insert(2, vec![4u8; 10]);
 
-#[ic_cdk::query]
-fn get_patient_record_by_id(id: u64) -> Result<PatientRecord, Message> {
-    PATIENT_RECORDS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, patient_record)| patient_record.id == id)
-            .map(|(_, patient_record)| patient_record.clone())
-            .ok_or(Message::NotFound("Patient record not found".to_string()))
-    })
-}
+        assert!(map.remove(&1) == Some(value));
+        assert!(!map.contains_key(&1));
+        assert!(map.get(&1).is_none());
+        assert_eq!(chunk_count(&map, 1), 0);
+        assert!(map.remove(&1).is_none());
+        assert!(map.get(&2) == Some(vec![4u8; 10]));
+        assert_eq!(map.chunks.len(), 1);
+    }
 
-#[ic_cdk::update]
-fn update_patient_record(
-    id: u64,
-    patient_id: u64,
-    doctor_id: u64,
-    diagnosis: String,
-    treatment: String,
-    medications: Vec<String>,
-) -> Result<PatientRecord, Message> {
-    PATIENT_RECORDS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        let id_entry = storage
-            .iter()
-            .find(|(_, patient_record)| patient_record.id == id);
-        match id_entry {
-            Some((key, _)) => {
-                let updated_patient_record = PatientRecord {
-                    id,
-                    patient_id,
-                    doctor_id,
-                    diagnosis,
-                    treatment,
-                    medications,
-                    created_at: current_time(),
-                };
-                storage.insert(key, updated_patient_record.clone());
-                Ok(updated_patient_record)
-            }
-            None => Err(Message::NotFound("Patient record not found".to_string())),
+    // 2024-01-01T00:00:00Z
+    pub(super) const NOW: u64 = 1_704_067_200_000_000_000;
+
+    fn expect_ok<T>(result: Result<T, Message>) -> T {
+        match result {
+            Ok(value) => value,
+            Err(
+                Message::Success(message)
+                | Message::Error(message)
+                | Message::NotFound(message)
+                | Message::InvalidPayload(message)
+                | Message::Unauthorized(message),
+            ) => panic!("unexpected error: {}", message),
         }
-    })
-}
+    }
 
-#[ic_cdk::update]
-fn delete_patient_record(id: u64) -> Result<(), Message> {
-    PATIENT_RECORDS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if storage.remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Message::NotFound("Patient record not found".to_string()))
+    fn saved_patient(name: &str) -> PatientId {
+        let id = PatientId(next_id(EntityKind::Patient));
+        let patient = Patient {
+            id,
+            ..patient(name, "1990-05-01")
+        };
+        save_patient(&patient);
+        id
+    }
+
+    fn saved_doctor(name: &str) -> DoctorId {
+        let id = DoctorId(next_id(EntityKind::Doctor));
+        save_doctor(&Doctor {
+            id,
+            name: name.to_string(),
+            ..Default::default()
+        });
+        id
+    }
+
+    fn saved_appointment(
+        patient_id: PatientId,
+        doctor_id: DoctorId,
+        room_id: Option<RoomId>,
+        date_time: u64,
+        duration: u32,
+    ) -> AppointmentId {
+        let id = AppointmentId(next_id(EntityKind::Appointment));
+        save_appointment(&Appointment {
+            id,
+            patient_id,
+            doctor_id,
+            date_time,
+            duration,
+            room_id,
+            ..Default::default()
+        });
+        id
+    }
+
+    fn appointment_ids_between(from: u64, to: u64) -> Vec<AppointmentId> {
+        get_appointments_between(from, to)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|appointment| appointment.id)
+            .collect()
+    }
+
+    fn patient_delete_options(action: DependentAction) -> PatientDeleteOptions {
+        PatientDeleteOptions {
+            appointments: action,
+            patient_records: action,
+            medications: action,
+            clinical_notes: action,
+            emergency_contacts: action,
+            insurance_coverages: action,
+            allergies: action,
+            observations: action,
+            lab_orders: action,
+            immunizations: action,
+            documents: action,
+            problems: action,
+            family_history: action,
+            care_plans: action,
         }
-    })
-}
+    }
 
-#[ic_cdk::update]
-fn create_medication(payload: MedicationPayload) -> Result<Medication, Message> {
-    if payload.name.is_empty() || payload.dosage.is_empty() || payload.frequency.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Ensure 'name', 'dosage', and 'frequency' are provided.".to_string(),
+    #[test]
+    fn save_appointment_moves_every_index_entry() {
+        let (alice, bob) = (saved_patient("Alice"), saved_patient("Bob"));
+        let (house, grey) = (saved_doctor("House"), saved_doctor("Grey"));
+        let (room_a, room_b) = (RoomId(1), RoomId(2));
+        let id = saved_appointment(alice, house, Some(room_a), NOW, 30);
+
+        let mut appointment = APPOINTMENTS_STORAGE
+            .with(|storage| storage.borrow().get(&id))
+            .unwrap();
+        appointment.patient_id = bob;
+        appointment.doctor_id = grey;
+        appointment.room_id = Some(room_b);
+        appointment.date_time = NOW + 60 * NANOS_PER_MINUTE;
+        save_appointment(&appointment);
+
+        assert!(appointment_ids_for_patient(alice).is_empty());
+        assert!(appointment_ids_for_patient(bob) == vec![id]);
+        assert!(appointment_ids_for_doctor(house).is_empty());
+        assert!(appointment_ids_for_doctor(grey) == vec![id]);
+        assert!(appointment_ids_for_room(room_a, 0, u64::MAX).is_empty());
+        assert!(appointment_ids_for_room(room_b, 0, u64::MAX) == vec![id]);
+        assert!(appointment_ids_between(NOW, NOW + 1).is_empty());
+        assert!(appointment_ids_between(NOW, u64::MAX) == vec![id]);
+
+        // Dropping the room removes its room index entry
+        appointment.room_id = None;
+        save_appointment(&appointment);
+        assert!(appointment_ids_for_room(room_b, 0, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn remove_appointment_clears_every_index_entry() {
+        let patient_id = saved_patient("Alice");
+        let doctor_id = saved_doctor("House");
+        let room_id = RoomId(1);
+        let kept = saved_appointment(patient_id, doctor_id, Some(room_id), NOW, 30);
+        let removed = saved_appointment(patient_id, doctor_id, Some(room_id), NOW + 1, 30);
+
+        assert!(remove_appointment(removed).map(|appointment| appointment.id) == Some(removed));
+        assert!(remove_appointment(removed).is_none());
+
+        assert!(appointment_ids_for_patient(patient_id) == vec![kept]);
+        assert!(appointment_ids_for_doctor(doctor_id) == vec![kept]);
+        assert!(appointment_ids_for_room(room_id, 0, u64::MAX) == vec![kept]);
+        assert!(appointment_ids_between(0, u64::MAX) == vec![kept]);
+    }
+
+    #[test]
+    fn room_appointments_overlapping_uses_half_open_ranges() {
+        let patient_id = saved_patient("Alice");
+        let doctor_id = saved_doctor("House");
+        let room_id = RoomId(1);
+        let minute = NANOS_PER_MINUTE;
+        // 09:00-10:00, 10:00-10:30 and an all-day booking from the previous day at 10:15
+        let early = saved_appointment(patient_id, doctor_id, Some(room_id), NOW, 60);
+        let late = saved_appointment(patient_id, doctor_id, Some(room_id), NOW + 60 * minute, 30);
+        let long = saved_appointment(
+            patient_id,
+            doctor_id,
+            Some(room_id),
+            NOW + 75 * minute - MAX_APPOINTMENT_DURATION as u64 * minute,
+            MAX_APPOINTMENT_DURATION,
+        );
+        // Another room is never reported
+        saved_appointment(patient_id, doctor_id, Some(RoomId(2)), NOW, 60);
+
+        let overlapping = |from: u64, to: u64| -> Vec<AppointmentId> {
+            room_appointments_overlapping(room_id, NOW + from * minute, NOW + to * minute)
+                .into_iter()
+                .map(|appointment| appointment.id)
+                .collect()
+        };
+        assert!(overlapping(0, 60) == vec![long, early]);
+        assert!(overlapping(60, 90) == vec![long, late]);
+        // Touching ends do not overlap
+        assert!(overlapping(90, 120).is_empty());
+        assert!(overlapping(75, 90) == vec![late]);
+        assert!(overlapping(59, 61) == vec![long, early, late]);
+    }
+
+    #[test]
+    fn delete_patient_restricts_then_cascades() {
+        let patient_id = saved_patient("Alice");
+        let doctor_id = saved_doctor("House");
+        let appointment_id = saved_appointment(patient_id, doctor_id, Some(RoomId(1)), NOW, 30);
+
+        assert!(delete_patient(
+            patient_id,
+            patient_delete_options(DependentAction::Restrict)
+        )
+        .is_err());
+        assert!(ensure_patient_exists(patient_id).is_ok());
+        assert!(appointment_ids_for_patient(patient_id) == vec![appointment_id]);
+
+        let impact = expect_ok(delete_patient(
+            patient_id,
+            patient_delete_options(DependentAction::Cascade),
         ));
+        assert!(impact.removed_appointments == vec![appointment_id]);
+        assert!(ensure_patient_exists(patient_id).is_err());
+        assert!(APPOINTMENTS_STORAGE.with(|storage| storage.borrow().is_empty()));
+        assert!(appointment_ids_for_doctor(doctor_id).is_empty());
+        assert!(appointment_ids_for_room(RoomId(1), 0, u64::MAX).is_empty());
     }
 
-    // Validate patient id
-    let patient = PATIENTS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, patient)| patient.id == payload.patient_id)
-            .map(|(_, patient)| patient.clone())
-    });
-    if patient.is_none() {
-        return Err(Message::NotFound("Patient not found".to_string()));
+    #[test]
+    fn delete_doctor_reassigns_future_appointments() {
+        let patient_id = saved_patient("Alice");
+        let (house, grey) = (saved_doctor("House"), saved_doctor("Grey"));
+        let past = saved_appointment(patient_id, house, None, NOW - 1, 30);
+        let future = saved_appointment(patient_id, house, None, NOW + 1, 30);
+        let options = DoctorDeleteOptions {
+            future_appointments: FutureAppointmentAction::Reassign(grey),
+            past_appointments: DependentAction::Cascade,
+            patient_records: DependentAction::Restrict,
+            medications: DependentAction::Restrict,
+            allergies: DependentAction::Restrict,
+            lab_orders: DependentAction::Restrict,
+            immunizations: DependentAction::Restrict,
+            clinical_notes: DependentAction::Restrict,
+            problems: DependentAction::Restrict,
+            care_plans: DependentAction::Restrict,
+            care_tasks: DependentAction::Restrict,
+        };
+
+        let impact = expect_ok(delete_doctor(house, options));
+        assert!(impact.removed_appointments == vec![past]);
+        assert!(impact.reassigned_appointments == vec![future]);
+        assert!(ensure_doctor_exists(house).is_err());
+        assert!(appointment_ids_for_doctor(house).is_empty());
+        assert!(appointment_ids_for_doctor(grey) == vec![future]);
+        assert!(appointment_ids_for_patient(patient_id) == vec![future]);
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    #[test]
+    fn merge_patients_repoints_dependents_and_collapses_redirects() {
+        let (first, second, third) = (
+            saved_patient("Alice"),
+            saved_patient("Alice B"),
+            saved_patient("Alice C"),
+        );
+        let doctor_id = saved_doctor("House");
+        let appointment_id = saved_appointment(first, doctor_id, None, NOW, 30);
 
-    let medication = Medication {
-        id,
-        name: payload.name,
-        dosage: payload.dosage,
-        frequency: payload.frequency,
-        patient_id: payload.patient_id,
-        created_at: current_time(),
-    };
-    MEDICATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, medication.clone()));
-    Ok(medication)
-}
+        let response = expect_ok(merge_patients(second, first));
+        assert!(response.moved_appointments == vec![appointment_id]);
+        assert!(appointment_ids_for_patient(first).is_empty());
+        assert!(appointment_ids_for_patient(second) == vec![appointment_id]);
+        assert!(resolve_patient(first).ok() == Some(second));
 
-#[ic_cdk::query]
-fn get_medications() -> Result<Vec<Medication>, Message> {
-    MEDICATIONS_STORAGE.with(|storage| {
-        let medications: Vec<Medication> = storage
-            .borrow()
-            .iter()
-            .map(|(_, medication)| medication.clone())
-            .collect();
+        // The earlier redirect is rewritten to point straight at the new survivor
+        expect_ok(merge_patients(third, second));
+        assert!(merged_into(first) == Some(third));
+        assert!(merged_into(second) == Some(third));
+        assert!(redirect_ids_to(second).is_empty());
+        assert!(redirect_ids_to(third) == vec![first, second]);
+        assert!(appointment_ids_for_patient(third) == vec![appointment_id]);
 
-        if medications.is_empty() {
-            Err(Message::NotFound("No medications found".to_string()))
-        } else {
-            Ok(medications)
-        }
-    })
-}
+        expect_ok(delete_patient(
+            third,
+            patient_delete_options(DependentAction::Cascade),
+        ));
+        assert!(merged_into(first).is_none());
+        assert!(redirect_ids_to(third).is_empty());
+        assert!(resolve_patient(first).is_err());
+    }
 
-#[ic_cdk::query]
-fn get_medication_by_id(id: u64) -> Result<Medication, Message> {
-    MEDICATIONS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, medication)| medication.id == id)
-            .map(|(_, medication)| medication.clone())
-            .ok_or(Message::NotFound("Medication not found".to_string()))
-    })
-}
+    #[test]
+    fn patient_timeline_pages_newest_first_from_the_index() {
+        let patient_id = saved_patient("Alice");
+        let doctor_id = saved_doctor("House");
+        let day = 24 * 60 * NANOS_PER_MINUTE;
+        let yesterday = saved_appointment(patient_id, doctor_id, None, NOW - day, 30);
+        let tomorrow = saved_appointment(patient_id, doctor_id, None, NOW + day, 30);
+        // Another patient's history is never listed
+        saved_appointment(saved_patient("Bob"), doctor_id, None, NOW, 30);
+        // Started last year and ends next year, so only the start is history yet
+        let medication_id = MedicationId(next_id(EntityKind::Medication));
+        save_medication(&Medication {
+            id: medication_id,
+            name: "Metformin".to_string(),
+            patient_id,
+            start_date: Some("2023-06-01".to_string()),
+            end_date: Some("2025-06-01".to_string()),
+            ..Default::default()
+        });
 
-#[ic_cdk::update]
-fn update_medication(
-    id: u64,
-    name: String,
-    dosage: String,
-    frequency: String,
-    patient_id: u64,
-) -> Result<Medication, Message> {
-    MEDICATIONS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        let id_entry = storage.iter().find(|(_, medication)| medication.id == id);
-        match id_entry {
-            Some((key, _)) => {
-                let updated_medication = Medication {
-                    id,
-                    name,
-                    dosage,
-                    frequency,
-                    patient_id,
-                    created_at: current_time(),
-                };
-                storage.insert(key, updated_medication.clone());
-                Ok(updated_medication)
+        let page = |before: Option<TimelineCursor>| {
+            let timeline = expect_ok(get_patient_timeline(patient_id, before, 2));
+            let events: Vec<(TimelineEvent, Option<u64>)> = timeline
+                .entries
+                .iter()
+                .map(|entry| (entry.event, entry.entity_id))
+                .collect();
+            (events, timeline.next_before)
+        };
+        let (first, next_before) = page(None);
+        assert!(
+            first
+                == vec![
+                    (TimelineEvent::Appointment, Some(tomorrow.0)),
+                    (TimelineEvent::Appointment, Some(yesterday.0)),
+                ]
+        );
+        assert!(next_before.is_some());
+        let (second, next_before) = page(next_before);
+        assert!(second == vec![(TimelineEvent::MedicationStarted, Some(medication_id.0))]);
+        assert!(next_before.is_none());
+
+        remove_appointment(tomorrow);
+        remove_medication(medication_id);
+        let (after_removal, _) = page(None);
+        assert!(after_removal == vec![(TimelineEvent::Appointment, Some(yesterday.0))]);
+    }
+
+    #[test]
+    fn find_duplicate_patients_pages_through_blocks_once_per_pair() {
+        let save = |name: &str, date_of_birth: &str, estimated: bool, phone: &str| {
+            let id = PatientId(next_id(EntityKind::Patient));
+            save_patient(&Patient {
+                id,
+                date_of_birth_estimated: estimated,
+                phone: Some(phone.to_string()),
+                ..patient(name, date_of_birth)
+            });
+            id
+        };
+        // Same name words, birth date and phone: three shared blocks, one candidate
+        let jane = save("Jane Doe", "1990-05-01", false, "555-0100");
+        let doe = save("Doe Jane", "1990-05-01", false, "(555) 0100");
+        // Migrated patients share an estimated January 1 birth date, which is not a block
+        save("Ann Smith", "1980-01-01", true, "555-0200");
+        save("Bob Jones", "1980-01-01", true, "555-0300");
+        // A shared front-desk number makes a block too large to compare
+        for n in 0..=MAX_DUPLICATE_BLOCK_SIZE {
+            save(&format!("Walk In {}", n), "2000-01-01", true, "555-9999");
+        }
+
+        let mut pairs = Vec::new();
+        let mut after = None;
+        loop {
+            let page = expect_ok(find_duplicate_patients(0, after, 1));
+            pairs.extend(
+                page.candidates
+                    .into_iter()
+                    .map(|candidate| (candidate.first, candidate.second, candidate.score)),
+            );
+            match page.next_after {
+                Some(next_after) => after = Some(next_after),
+                None => break,
             }
-            None => Err(Message::NotFound("Medication not found".to_string())),
         }
-    })
-}
+        assert!(pairs == vec![(jane, doe, 80)]);
+    }
 
-#[ic_cdk::update]
-fn delete_medication(id: u64) -> Result<(), Message> {
-    MEDICATIONS_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if storage.remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Message::NotFound("Medication not found".to_string()))
+    // Appointment layout in memory 3, before rooms and eligibility were added
+    #[derive(candid::CandidType)]
+    struct OriginalAppointment {
+        id: u64,
+        patient_id: u64,
+        doctor_id: u64,
+        date_time: u64,
+        duration: u32,
+        description: String,
+        created_at: u64,
+        updated_at: Option<u64>,
+    }
+
+    fn insert_legacy_row(memory_id: u8, id: u64, bytes: &[u8]) {
+        legacy_map(memory_id).insert(id, BoundedEntityBytes::try_from(bytes).unwrap());
+    }
+
+    #[test]
+    fn migration_moves_the_original_layout_in_batches_and_rejects_undecodable_rows() {
+        insert_legacy_row(
+            1,
+            1,
+            &Encode!(&LegacyDoctor {
+                id: DoctorId(1),
+                name: "Dr. Grey".to_string(),
+                speciality: " Cardiology ".to_string(),
+                created_at: 1,
+            })
+            .unwrap(),
+        );
+        // One more patient than fits in a batch, and a row that is not a patient
+        for id in 2..MIGRATION_BATCH_SIZE as u64 + 3 {
+            insert_legacy_row(
+                2,
+                id,
+                &Encode!(&LegacyPatient {
+                    id: PatientId(id),
+                    name: format!("Patient {}", id),
+                    age: 40,
+                    gender: "female".to_string(),
+                    created_at: 2,
+                })
+                .unwrap(),
+            );
         }
-    })
-}
+        insert_legacy_row(2, 9_999, b"not candid");
+        insert_legacy_row(
+            5,
+            3_000,
+            &Encode!(&LegacyMedication {
+                id: MedicationId(3_000),
+                name: "Aspirin".to_string(),
+                dosage: "500mg".to_string(),
+                frequency: "twice a day".to_string(),
+                patient_id: PatientId(2),
+                created_at: 3,
+            })
+            .unwrap(),
+        );
+        insert_legacy_row(
+            4,
+            4_000,
+            &Encode!(&LegacyPatientRecord {
+                id: PatientRecordId(4_000),
+                patient_id: PatientId(2),
+                doctor_id: DoctorId(1),
+                diagnosis: "Flu".to_string(),
+                treatment: "Rest".to_string(),
+                medications: vec![
+                    "aspirin".to_string(),
+                    "Ibuprofen".to_string(),
+                    " ".to_string()
+                ],
+                created_at: 4,
+            })
+            .unwrap(),
+        );
+        insert_legacy_row(
+            3,
+            5_000,
+            &Encode!(&OriginalAppointment {
+                id: 5_000,
+                patient_id: 2,
+                doctor_id: 1,
+                date_time: NOW,
+                duration: 30,
+                description: "Checkup".to_string(),
+                created_at: 5,
+                updated_at: None,
+            })
+            .unwrap(),
+        );
+        insert_legacy_row(3, 5_001, b"not candid either");
 
-fn current_time() -> u64 {
-    time()
-}
+        assert!(!run_migration_batch());
+        assert!(run_migration_batch());
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    UnAuthorized { msg: String },
-}
+        for memory_id in [1, 2, 4, 5] {
+            assert!(legacy_map(memory_id).is_empty());
+        }
+        let doctor = DOCTORS_STORAGE
+            .with(|storage| storage.borrow().get(&DoctorId(1)))
+            .unwrap();
+        assert!(doctor.specialities == vec!["Cardiology".to_string()]);
+        let patient = PATIENTS_STORAGE
+            .with(|storage| storage.borrow().get(&PatientId(2)))
+            .unwrap();
+        assert!(patient.date_of_birth == "1984-01-01" && patient.date_of_birth_estimated);
+        assert!(matches!(patient.sex, Sex::Female));
+        assert!(
+            PATIENTS_STORAGE.with(|storage| storage.borrow().iter().count())
+                == MIGRATION_BATCH_SIZE + 1
+        );
 
-ic_cdk::export_candid!();
+        let medication = MEDICATIONS_STORAGE
+            .with(|storage| storage.borrow().get(&MedicationId(3_000)))
+            .unwrap();
+        assert!(medication.legacy_dosage == Some("500mg".to_string()));
+        assert!(medication.frequency == Some(Frequency::TwiceDaily));
+        let record = PATIENT_RECORDS_STORAGE
+            .with(|storage| storage.borrow().get(&PatientRecordId(4_000)))
+            .unwrap();
+        assert!(record.medications.len() == 2 && record.medications[0] == MedicationId(3_000));
+        let created = MEDICATIONS_STORAGE
+            .with(|storage| storage.borrow().get(&record.medications[1]))
+            .unwrap();
+        assert!(created.name == "Ibuprofen" && created.patient_id == PatientId(2));
+
+        // The appointment stays in place and is indexed; the bad row is gone from the map
+        assert!(appointment_ids_for_patient(PatientId(2)) == vec![AppointmentId(5_000)]);
+        assert!(APPOINTMENTS_STORAGE.with(|storage| storage.borrow().len()) == 1);
+        assert!(TIMELINE_BY_PATIENT.with(|index| {
+            index
+                .borrow()
+                .iter()
+                .any(|((patient_id, (_, (_, id))), _)| patient_id == PatientId(2) && id == 5_000)
+        }));
+
+        let progress = MIGRATION_PROGRESS.with(|progress| progress.borrow().get().clone());
+        assert!(progress.step == MigrationStep::Done);
+        assert!(progress.migrated == MIGRATION_BATCH_SIZE as u64 + 5);
+        assert!(progress.rejected == 2);
+        let rejected: Vec<(u8, u64)> =
+            MIGRATION_REJECTS.with(|rejects| rejects.borrow().iter().map(|(key, _)| key).collect());
+        assert!(rejected == vec![(2, 9_999), (3, 5_001)]);
+    }
+}